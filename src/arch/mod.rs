@@ -24,7 +24,7 @@ pub use self::x86_64::mm;
 // Export our platform-specific modules.
 #[cfg(target_arch = "x86_64")]
 pub use self::x86_64::mm::paging::{
-	drop_user_space, get_kernel_root_page_table, BasePageSize, PageSize,
+	drop_user_space, get_kernel_root_page_table, is_user_memory, BasePageSize, PageSize,
 };
 
 // Implementations for x86_64.
@@ -0,0 +1,83 @@
+// NEW
+
+// The boot parameters of the kernel. The bootloader passes no command line, so they are read from
+// QEMU's firmware configuration device, where they are given when booting, e.g.
+// `qemu-system-x86_64 ... -fw_cfg name=opt/eduos/selftest,string=2048,,64` (QEMU reads ",," as a comma).
+
+use crate::synch::spinlock::SpinlockIrqSave;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use x86::io::*;
+
+const FW_CFG_PORT_SELECTOR: u16 = 0x510;
+const FW_CFG_PORT_DATA: u16 = 0x511;
+
+const FW_CFG_SIGNATURE: u16 = 0x0000;
+const FW_CFG_FILE_DIR: u16 = 0x0019;
+
+/// Length of a file name in the directory, including the terminating zero
+const FW_CFG_NAME_LEN: usize = 56;
+
+/// Larger files are not meant as parameters, they are ignored
+const MAX_PARAMETER_LEN: u32 = 4096;
+
+/// Selecting an item and reading it is not atomic
+static FW_CFG: SpinlockIrqSave<()> = SpinlockIrqSave::new(());
+
+unsafe fn read_bytes(buffer: &mut [u8]) {
+	for it in buffer.iter_mut() {
+		*it = inb(FW_CFG_PORT_DATA);
+	}
+}
+
+unsafe fn read_be_u32() -> u32 {
+	let mut buffer = [0u8; 4];
+	read_bytes(&mut buffer);
+	u32::from_be_bytes(buffer)
+}
+
+unsafe fn read_be_u16() -> u16 {
+	let mut buffer = [0u8; 2];
+	read_bytes(&mut buffer);
+	u16::from_be_bytes(buffer)
+}
+
+/// The boot parameter `name` (e.g. "opt/eduos/selftest"), None if it is not given,
+/// not text or the machine has no firmware configuration device.
+pub fn boot_parameter(name: &str) -> Option<String> {
+	let _guard = FW_CFG.lock();
+
+	unsafe {
+		outw(FW_CFG_PORT_SELECTOR, FW_CFG_SIGNATURE);
+		let mut signature = [0u8; 4];
+		read_bytes(&mut signature);
+		if &signature != b"QEMU" {
+			return None;
+		}
+
+		outw(FW_CFG_PORT_SELECTOR, FW_CFG_FILE_DIR);
+		let (mut size, mut select) = (None, 0u16);
+		for _ in 0..read_be_u32() {
+			let file_size = read_be_u32();
+			let file_select = read_be_u16();
+			let _reserved = read_be_u16();
+			let mut file_name = [0u8; FW_CFG_NAME_LEN];
+			read_bytes(&mut file_name);
+
+			let len = file_name.iter().position(|it| *it == 0).unwrap_or(FW_CFG_NAME_LEN);
+			if size.is_none() && &file_name[..len] == name.as_bytes() {
+				size = Some(file_size);
+				select = file_select;
+			}
+		}
+
+		let size = size.filter(|it| *it <= MAX_PARAMETER_LEN)?;
+		outw(FW_CFG_PORT_SELECTOR, select);
+		let mut value: Vec<u8> = vec![0; size as usize];
+		read_bytes(&mut value);
+
+		let value = String::from_utf8(value).ok()?;
+		Some(String::from(value.trim_end_matches(|it| it == '\0' || it == '\n')))
+	}
+}
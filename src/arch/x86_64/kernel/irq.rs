@@ -25,12 +25,19 @@ const KERNEL_CODE_SELECTOR: SegmentSelector = SegmentSelector::new(1, Ring::Ring
 
 /// Enable Interrupts
 pub fn irq_enable() {
-	unsafe { asm!("sti", options(preserves_flags, nomem, nostack)) };
+	// privileged, the tests on the host run without (e.g. locking a SpinlockIrqSave)
+	#[cfg(not(all(test, not(target_os = "none"))))]
+	unsafe {
+		asm!("sti", options(preserves_flags, nomem, nostack))
+	};
 }
 
 /// Disable Interrupts
 pub fn irq_disable() {
-	unsafe { asm!("cli", options(preserves_flags, nomem, nostack)) };
+	#[cfg(not(all(test, not(target_os = "none"))))]
+	unsafe {
+		asm!("cli", options(preserves_flags, nomem, nostack))
+	};
 }
 
 /// Determines, if the interrupt flags (IF) is set
@@ -341,12 +348,14 @@ extern "x86-interrupt" fn timer_handler(stack_frame: ExceptionStackFrame) {
 	if old % 10 == 0
 	{
 		// serial_print!("!"); // 10027 µs = 10.027 ms
+		crate::drivers::on_timer(old);
 		schedule();
 	}
 }
 
 // On my laptop, the HBA is set to IRQ 9, and for what ever reason I can't change that. I am probably doing something wrong.
-// QEMU is by default 11 (if I recall correctly, at least with this setup), which is what i am setting anyway.
+// QEMU is by default 11 (if I recall correctly, at least with this setup).
+// The driver no longer sets the line, each HBA keeps the one the firmware routed it to (see ahci2::AHCI_IRQS).
 // According to https://os.phil-opp.com/hardware-interrupts/ Interrupts 10 & 11 are free for everything.
 // According to https://wiki.osdev.org/Interrupts#General_IBM-PC_Compatible_Interrupt_Information Interrupt 9 is also free.
 extern "x86-interrupt" fn ahci_handler_9(_stack_frame: ExceptionStackFrame)
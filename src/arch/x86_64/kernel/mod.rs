@@ -7,6 +7,7 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
+pub mod fw_cfg;
 pub mod gdt;
 pub mod irq;
 mod pit;
@@ -22,6 +23,9 @@ pub use pit::{
 	busy_sleep,
 	get_ticks
 };
+// The AHCI emulation lets time pass, whenever the driver waits
+#[cfg(all(test, not(target_os = "none")))]
+pub use pit::inc_ticks;
 
 pub use crate::arch::x86_64::kernel::syscall::syscall_handler;
 use crate::consts::USER_SPACE_START;
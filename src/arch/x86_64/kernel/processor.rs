@@ -84,6 +84,9 @@ pub fn pause() {
 
 #[no_mangle]
 pub extern "C" fn shutdown() -> ! {
+	// write back whatever the disks still cache
+	crate::drivers::shutdown();
+
 	// shutdown, works like Qemu's shutdown command
 	let qemu_exit_handle = qemu_exit::X86::new(0xf4, 5);
 	qemu_exit_handle.exit_success();
@@ -135,6 +135,10 @@ impl PageTableEntry {
 		(self.physical_address_and_flags & PageTableEntryFlags::USER_ACCESSIBLE.bits()) != 0
 	}
 
+	fn is_writable(&self) -> bool {
+		(self.physical_address_and_flags & PageTableEntryFlags::WRITABLE.bits()) != 0
+	}
+
 	/// Mark this as a valid (present) entry and set address translation and flags.
 	///
 	/// # Arguments
@@ -757,6 +761,7 @@ pub fn get_page_table_entry<S: PageSize>(virtual_address: usize) -> Option<PageT
 	root_pagetable.get_page_table_entry(page)
 }
 
+#[cfg(not(all(test, not(target_os = "none"))))]
 pub fn get_physical_address<S: PageSize>(virtual_address: usize) -> usize {
 	debug!("Getting physical address for {:#X}", virtual_address);
 
@@ -770,6 +775,36 @@ pub fn get_physical_address<S: PageSize>(virtual_address: usize) -> usize {
 	address | offset
 }
 
+/// the tests on the host run without paging, their memory is what the emulated devices see
+#[cfg(all(test, not(target_os = "none")))]
+pub fn get_physical_address<S: PageSize>(virtual_address: usize) -> usize {
+	virtual_address
+}
+
+/// Checks memory handed in by user space, before the kernel touches it (or gives it to a device):
+/// every page of `[virtual_address, virtual_address + len)` has to be above USER_SPACE_START, present,
+/// user accessible and, if `writable` is set, writable.
+pub fn is_user_memory(virtual_address: usize, len: usize, writable: bool) -> bool {
+	let end = match virtual_address.checked_add(len) {
+		Some(end) => end,
+		None => return false,
+	};
+	if virtual_address < USER_SPACE_START {
+		return false;
+	}
+
+	// currently, the user space uses only 4KB pages
+	let mut page = align_down!(virtual_address, BasePageSize::SIZE);
+	while page < end {
+		match get_page_table_entry::<BasePageSize>(page) {
+			Some(entry) if entry.is_user() && (entry.is_writable() || !writable) => {}
+			_ => return false,
+		}
+		page += BasePageSize::SIZE;
+	}
+	true
+}
+
 /// Translate a virtual memory address to a physical one.
 /// Just like get_physical_address, but automatically uses the correct page size for the respective memory address.
 pub fn virtual_to_physical(virtual_address: usize) -> usize {
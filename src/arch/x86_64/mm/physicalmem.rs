@@ -92,6 +92,27 @@ pub fn allocate(size: usize) -> usize {
 	result.unwrap()
 }
 
+/// Allocates physical memory ending at or below `limit`, for devices unable to address all of it.
+pub fn allocate_below(size: usize, limit: usize) -> usize {
+	assert!(size > 0);
+	assert!(
+		size % BasePageSize::SIZE == 0,
+		"Size {:#X} is not a multiple of {:#X}",
+		size,
+		BasePageSize::SIZE
+	);
+
+	let _preemption = DisabledPreemption::new();
+	let result = unsafe { PHYSICAL_FREE_LIST.allocate_below(size, limit) };
+	assert!(
+		result.is_ok(),
+		"Could not allocate {:#X} bytes of physical memory below {:#X}",
+		size,
+		limit
+	);
+	result.unwrap()
+}
+
 pub fn allocate_aligned(size: usize, alignment: usize) -> usize {
 	assert!(size > 0);
 	assert!(alignment > 0);
@@ -9,6 +9,41 @@ mod ports;
 use ports::*;
 
 mod fis;
+pub use fis::RegH2D;
+
+mod error;
+pub use error::DiskError;
+
+mod identify;
+pub use identify::IdentifyData;
+
+mod scatter_gather;
+
+mod dma;
+
+mod smart;
+pub use smart::{SmartAttribute, SmartData};
+
+mod power;
+pub use power::{LinkPowerState, PowerPolicy, InterfacePowerState, LinkStatus};
+
+mod atapi;
+pub use atapi::InquiryData;
+
+mod passthrough;
+pub use passthrough::{DataDirection, TaskfileResult, PASSTHROUGH_BLOCK_SIZE, PASSTHROUGH_MAX_LEN};
+
+#[cfg(all(test, not(target_os = "none")))]
+mod emulation;
+
+mod selftest;
+pub use selftest::{SelfTest, SelfTestError, PortReport, StepResult, BOOT_PARAMETER};
+
+mod hotplug;
+pub use hotplug::{PortEvent, subscribe};
+
+mod block_device;
+pub use block_device::{AhciBlockDevice, register_block_devices};
 
 use crate::{
     drivers::pci::{
@@ -532,27 +567,36 @@ pub struct HbaMemory
 use ahci2::{
     // AHCI_DEVICES as DEVICES,
     with_ahci_devices,
-    with_ahci_devices_mut,
-    AhciDevice2
+    add_ahci_devices,
+    ahci_device_count,
+    AhciDevice2,
+    AhciPort2
 };
 
 pub fn init()
 {
-    with_ahci_devices_mut(|devices|{
+    if ahci_device_count() != 0
+    {
+        panic!("AHCI already initialized");
+    }
+
+    let mut devices = alloc::vec::Vec::new();
+    super::pci::on_each_generic_device_mut(|pci_idx, dev| {
 
-        if !devices.is_empty()
+        if let Some(it) = AhciDevice2::new(pci_idx, &dev, devices.len())
         {
-            panic!("AHCI already initialized");
+            devices.push(it);
         }
-        super::pci::on_each_generic_device_mut(|pci_idx, dev| {
-
-            if let Some(it) = AhciDevice2::new(pci_idx, &dev, devices.len())
-            {
-                devices.push(it);
-            }
-            // println!("After AhciInit");
-        });
+        // println!("After AhciInit");
     });
+
+    let found = !devices.is_empty();
+    add_ahci_devices(devices);
+    if found
+    {
+        crate::scheduler::spawn(hotplug::hotplug_worker, crate::scheduler::task::HIGH_PRIORITY)
+            .expect("Failed to spawn the AHCI hot-plug worker");
+    }
     /*let mut devices = DEVICES.lock();
     if !devices.is_empty()
     {
@@ -590,19 +634,47 @@ pub fn on_each_device<F>(mut fun: F)
     });
 }
 
-// I originally meant the mut to refert to FnMut (vs just Fn)
-pub fn on_each_device_mut<F>(mut fun: F)
-    where F: FnMut(usize, &mut AhciDevice2) -> ()
+/// Calls `fun` with (HBA index, Port index, Port) for every port and every disk behind a port multiplier.
+/// Only the port at hand is locked, the others keep working.
+pub fn on_each_port<F>(mut fun: F)
+    where F: FnMut(usize, usize, &mut AhciPort2) -> ()
 {
-    // let mut devs = DEVICES.lock();
-    with_ahci_devices_mut(|devs|{
+    with_ahci_devices(|devs| {
 
-        for (i, dev) in devs.iter_mut().enumerate()
+        for (i, dev) in devs.iter().enumerate()
         {
-            fun(i, dev);
+            for (j, slot) in dev.ports.iter().enumerate()
+            {
+                for port in slot.lock().iter_mut()
+                {
+                    fun(i, j, port);
+                }
+            }
+        }
+    });
+}
+
+/// Flushes the write cache of every disk, so nothing written is lost when the system goes down,
+/// and spins them down, so they are not powered off while spinning.
+pub fn shutdown()
+{
+    with_ahci_devices(|devs| {
+
+        for dev in devs.iter()
+        {
+            dev.spin_down_all();
         }
     });
-    
 }
 
-pub use ahci2::on_interrupt;
+pub use ahci2::{
+    on_interrupt,
+    on_timer,
+    get_ahci_device,
+    with_port,
+    interrupt_stats,
+    reset_interrupt_stats,
+    InterruptStats,
+    PortSlot,
+    QueuedRequest
+};
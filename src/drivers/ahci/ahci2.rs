@@ -4,6 +4,8 @@
 use super::{
     HbaMemory,
     PortRegister,
+    Capabilities,
+    CapabilitiesExtended,
     fis::{
         CommandListStructure,
         ReceivedFis,
@@ -14,7 +16,21 @@ use super::{
         Fis,
         PhysicalRegionDescriptorTable
     },
-    is_ahci_device
+    is_ahci_device,
+    DiskError,
+    IdentifyData,
+    SmartData,
+    PortEvent,
+    InquiryData,
+    IccState,
+    InterfaceSpeed,
+    LinkPowerState,
+    LinkStatus,
+    PowerPolicy,
+    atapi::Cdb,
+    passthrough::{self, DataDirection, TaskfileResult, PASSTHROUGH_BLOCK_SIZE, PASSTHROUGH_MAX_LEN},
+    scatter_gather::{PhysicalRuns, MAX_PRD_BYTES},
+    dma::{self, BounceBuffer}
 };
 
 use crate::{
@@ -23,7 +39,6 @@ use crate::{
     // LOGGER,
     arch::x86_64::{
         mm::{
-            physicalmem,
             virtualmem,
             paging::{
                 self,
@@ -31,50 +46,105 @@ use crate::{
                 BasePageSize
             }
         },
-        kernel::busy_sleep
+        kernel::{
+            busy_sleep,
+            get_ticks
+        }
+    },
+    synch::{
+        mutex::Mutex,
+        spinlock::{Spinlock, SpinlockIrqSave}
     },
-    synch::spinlock::Spinlock,
+    scheduler::{self, task::Task},
+    collections::irqsave,
     drivers::pci::{
         devices::{Generic as PciGeneric, CommonHeader},
         MemSpaceBarValue
     }
 };
-use alloc::vec::Vec;
-use core::convert::{
-    TryFrom,
-    TryInto
+use alloc::{
+    vec::Vec,
+    rc::Rc,
+    sync::Arc
+};
+use core::{
+    cell::RefCell,
+    convert::{
+        TryFrom,
+        TryInto
+    }
 };
 
 // TODO: Replace with crate::LOGGER
 static LOGGER: KernelLogger = KernelLogger{ log_level: LogLevel::DEBUG };
 
+/// Everything attached to one port of the HBA: the port and, if it is a port multiplier, the disks behind it.
+/// They share the registers and the command list of the port, so they share its lock as well.
+pub struct PortSlot
+{
+    pub port: Option<&'static mut AhciPort2>,
+    /// The disks behind a port multiplier. The port multiplier itself is `port`, with `pmp` set to its control port.
+    pub pm_ports: Vec<&'static mut AhciPort2>
+}
+
+impl PortSlot
+{
+    pub const fn new() -> Self
+    {
+        Self { port: None, pm_ports: Vec::new() }
+    }
+
+    /// The port itself (pmp None) or the disk behind its port multiplier
+    pub fn get_mut(&mut self, pmp: Option<u8>) -> Option<&mut AhciPort2>
+    {
+        match pmp
+        {
+            None => self.port.as_deref_mut(),
+            Some(pmp) => self.pm_ports.iter_mut().find(|it| it.pmp == Some(pmp)).map(|it| &mut **it)
+        }
+    }
+
+    /// The port and every disk behind it
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut AhciPort2>
+    {
+        self.port.iter_mut().chain(self.pm_ports.iter_mut()).map(|it| &mut **it)
+    }
+}
+
+/// Lock order: the lock of a port first, the one of the HBA (abar_ptr) second.
+/// Commands only need the lock of their port, so disks on different ports (or HBAs) work in parallel.
+/// Both are Mutexes, as commands block their task while waiting for the disk: a task spinning on a Spinlock
+/// the blocked one holds would keep it from ever running again.
 pub struct AhciDevice2
 {
     pub pci_idx: usize,
-    pub abar_ptr: &'static mut HbaMemory,
+    /// The IRQ the HBA raises its interrupts on, as routed by the firmware (PCI Interrupt Line)
+    pub irq: u8,
+    /// The HBA wide registers. The ports use their own registers through `AhciPort2::regs`, without this lock.
+    pub abar_ptr: Mutex<&'static mut HbaMemory>,
     pub abar_actual_size: usize,
-    pub ports: [Option<&'static mut AhciPort2>; 32]
+    pub ports: [Mutex<PortSlot>; 32]
 }
 
 impl AhciDevice2
 {
-    pub unsafe fn init_memory(this: *mut Self, pci_idx: usize, abar_ptr: &'static mut HbaMemory, abar_actual_size: usize) -> &'static mut Self
+    pub unsafe fn init_memory(this: *mut Self, pci_idx: usize, irq: u8, abar_ptr: &'static mut HbaMemory, abar_actual_size: usize) -> &'static mut Self
     {
         use core::ptr::addr_of_mut;
-        const PORT_TEMPLATE: Option<&'static mut AhciPort2> = None;
 
         addr_of_mut!((*this).pci_idx).write_volatile(pci_idx);
-        addr_of_mut!((*this).abar_ptr).write_volatile(abar_ptr);
+        addr_of_mut!((*this).irq).write_volatile(irq);
+        addr_of_mut!((*this).abar_ptr).write_volatile(Mutex::new(abar_ptr));
         addr_of_mut!((*this).abar_actual_size).write_volatile(abar_actual_size);
-        addr_of_mut!((*this).ports).write_volatile([PORT_TEMPLATE; 32]);
+        addr_of_mut!((*this).ports).write_volatile(core::array::from_fn(|_| Mutex::new(PortSlot::new())));
 
         &mut *this
     }
 
     pub fn new(pci_idx: usize, device: &PciGeneric, hba_idx: usize) -> Option<Self>
     {
-        let mut output = Self::pci_init(pci_idx, device);
-        if let Some(ref mut it) = output
+        let output = Self::pci_init(pci_idx, device);
+        if let Some(ref it) = output
         {
             if it.ahci_init(hba_idx)
             {
@@ -91,10 +161,9 @@ impl AhciDevice2
     /// - Enable Memory Space Access
     /// - Make Bus Master
     /// - Load the ABAR address and size
-    /// - Set Interrupt Line
+    /// - Read the Interrupt Line
     fn pci_init(pci_idx: usize, device: &PciGeneric) -> Option<Self>
     {
-        const PORT_TEMPLATE: Option<&'static mut AhciPort2> = None;
         if is_ahci_device(device)
         {
             // Load ABAR (BAR[5])
@@ -116,10 +185,16 @@ impl AhciDevice2
             debug_assert_eq!(port_size % 0x80, 0);
             let port_count = port_size / 0x80;
 
-            // Set the Interrupt Line
-            let old_irq = device.get_interrupt_line();
-            device.set_interrupt_line(11);
-            println!("AHCI IRQ: {} => 11 ({})", old_irq, device.get_interrupt_line());
+            // The Interrupt Line only tells where the firmware routed the interrupt, writing it changes nothing.
+            let irq = device.get_interrupt_line();
+            if AHCI_IRQS.contains(&irq)
+            {
+                debug!("AHCI IRQ: {}", irq);
+            }
+            else
+            {
+                warn!("AHCI IRQ {} has no handler, commands will only complete by timing out", irq);
+            }
 
             // Do the rest
             let mut cmd = device.get_command();
@@ -133,9 +208,10 @@ impl AhciDevice2
                 Some(Self {
 
                     pci_idx,
-                    abar_ptr: &mut *core::ptr::from_raw_parts_mut(vmem as *mut (), port_count as usize),
+                    irq,
+                    abar_ptr: Mutex::new(&mut *core::ptr::from_raw_parts_mut(vmem as *mut (), port_count as usize)),
                     abar_actual_size: size as usize,
-                    ports: [PORT_TEMPLATE; 32]
+                    ports: core::array::from_fn(|_| Mutex::new(PortSlot::new()))
                 })
             }
         }
@@ -145,62 +221,63 @@ impl AhciDevice2
         }
     }
 
-    fn ahci_init(&mut self, hba_idx: usize) -> bool
+    fn ahci_init(&self, hba_idx: usize) -> bool
     {
         self.bios_os_handoff();
         self.reset();
         
-        // ENABLE AHCI MODE
-        if !self.abar_ptr.ghc.cap.get_sam() && !self.abar_ptr.ghc.ghc.get_ae()
         {
-            unsafe { self.abar_ptr.ghc.ghc.set_ae(true) };
+            let mut hba = self.abar_ptr.lock();
+            // ENABLE AHCI MODE
+            if !hba.ghc.cap.get_sam() && !hba.ghc.ghc.get_ae()
+            {
+                unsafe { hba.ghc.ghc.set_ae(true) };
+            }
+            assert!(hba.ghc.ghc.get_ae(), "AHCI Mode is not enabled.");
+
+            // The interrupt handler has to know the HBA, before the first command is issued
+            register_hba(hba_idx, self.irq, &mut hba);
         }
-        assert!(self.abar_ptr.ghc.ghc.get_ae(), "AHCI Mode is not enabled.");
 
         // Enable Ports
         self.init_ports(hba_idx);
 
         // Enable Interrupts
-        self.abar_ptr.ghc.ghc.set_ie(true);
+        self.abar_ptr.lock().ghc.ghc.set_ie(true);
         debug!("Interrupt ENABLED");
 
         true
     }
 
-    // 10.6.3
-    // Needs Testing
-    fn bios_os_handoff(&mut self)
+    /// Takes the HBA over from the firmware, if it supports the BIOS/OS Handoff (CAP2.BOH):
+    /// - set BOHC.OOS (OS Owned Semaphore)
+    /// - wait for the firmware to clear BOHC.BOS (BIOS Owned Semaphore)
+    /// - after 25 ms, if the firmware set BOHC.BB (BIOS Busy), give it 2 more seconds to finish its commands
+    /// 
+    /// A firmware not keeping to the timeouts loses the HBA anyway, there is nothing better to do.
+    fn bios_os_handoff(&self)
     {
-        let mem = &mut self.abar_ptr;
-        if mem.ghc.cap2.get_boh()
+        let mem = self.abar_ptr.lock();
+        if !mem.ghc.cap2.get_boh()
         {
-            #[cfg(debug_assertions)] // Maybe always?
-            serial_println!("BIOS OS Handoff");
-            mem.ghc.bohc.set_oos(true);
-            loop
-            {
-                if !mem.ghc.bohc.get_bos()
-                {
-                    #[cfg(debug_assertions)] // Maybe always?
-                    serial_println!("Wait for BOHC.bos successful");
-
-                    busy_sleep(30); // Doc says 25, but my code is not "perfect" 1 ms. Hope this will compensate enough.
+            debug!("BIOS/OS Handoff not supported, skipping");
+            return;
+        }
 
-                    let mut tries = 0u32;
-                    while tries < 10 && mem.ghc.bohc.get_bb()
-                    {
-                        tries += 1;
-                        busy_sleep(2000); // Wait the minimum amount of time for the bios to clear this bit, at most 10 times (random choice).
-                    }
+        debug!("BIOS/OS Handoff");
+        mem.ghc.bohc.set_oos(true);
+        // The firmware should react within 25 ms, a second is plenty
+        if !AhciPort2::wait_ms(1000, || !mem.ghc.bohc.get_bos())
+        {
+            debug!("BIOS/OS Handoff: BOHC.BOS still set, taking over the HBA anyway");
+        }
 
-                    if tries >= 10 && mem.ghc.bohc.get_bb() // after 10 tries of waiting 2 seconds, the bios busy flag is still set. I choose to timeout this operation at this point.
-                    {
-                        panic!("Bios OS Handoff failed: timeout");
-                    }
-                    break;
-                }
-            }
+        busy_sleep(25);
+        if mem.ghc.bohc.get_bb() && !AhciPort2::wait_ms(2000, || !mem.ghc.bohc.get_bb())
+        {
+            debug!("BIOS/OS Handoff: BOHC.BB still set after 2 seconds, taking over the HBA anyway");
         }
+        debug!("BIOS/OS Handoff done");
     }
 
     // 10.4.3
@@ -209,21 +286,22 @@ impl AhciDevice2
     /// - GHC.IE
     /// - IS Register
     /// - all port register fields except fields intiallized by hardware (HwInit) and PxFB/PxFBU/PxCLB/PxCLBU
-    fn reset(&mut self)
+    fn reset(&self)
     {
+        let hba = self.abar_ptr.lock();
         print!("Resetting HBA");
-        if !self.abar_ptr.ghc.ghc.get_ae()
+        if !hba.ghc.ghc.get_ae()
         {
             // Safe: as per definition, if CAP.SAM is set, GHC.AE is readonly 1/true.
             // If CAP.SAM is not set, only then can it be 0/false. In this case, it is writable.
-            unsafe { self.abar_ptr.ghc.ghc.set_ae(true) };
+            unsafe { hba.ghc.ghc.set_ae(true) };
         }
-        self.abar_ptr.ghc.ghc.set_hr();
+        hba.ghc.ghc.set_hr();
         loop 
         {
             print!(".");
             // TODO: After a second consider HBA in locked/hung state
-            if !self.abar_ptr.ghc.ghc.get_hr()
+            if !hba.ghc.ghc.get_hr()
             {
                 println!("OK");
                 break;
@@ -231,11 +309,8 @@ impl AhciDevice2
         }
     }
 
-    fn init_ports(&mut self, hba_idx: usize)
+    fn init_ports(&self, hba_idx: usize)
     {
-        let command_slots_per_port = self.abar_ptr.ghc.cap.get_ncs_adjusted();
-        let is_64bit_aware = self.abar_ptr.ghc.cap.get_s64a();
-
         for i in 0..32u8
         {
             // SSTS part
@@ -245,31 +320,288 @@ impl AhciDevice2
             // {
             //     println!("{:x}", self.abar_ptr.ports[i as usize].ssts.get() & 0xf);
             // }
-            
-            if self.abar_ptr.ghc.pi.get(i) // && self.abar_ptr.ports[i as usize].ssts.get() & 0xf == 3
+
+            let implemented = self.abar_ptr.lock().ghc.pi.get(i);
+            if implemented // && self.abar_ptr.ports[i as usize].ssts.get() & 0xf == 3
             {
-                // println!("SSTS {:3x}, SIG {:x}", self.abar_ptr.ports[i as usize].ssts.get(), self.abar_ptr.ports[i as usize].sig.get());
-                self.ports[i as usize] = AhciPort2::new(
-                    self,
-                    hba_idx,
-                    i,
-                    command_slots_per_port,
-                    is_64bit_aware);
-
-                
-                // println!("SSTS {:3x}, SIG {:x}", self.abar_ptr.ports[i as usize].ssts.get(), self.abar_ptr.ports[i as usize].sig.get());
-                // Currently, if self.ports[i] is not None, the value below should be 0x101 (Supposed SATA Signature)
-                match self.abar_ptr.ports[i as usize].sig.get()
+                let mut slot = self.ports[i as usize].lock();
+                // Nobody to tell about the disks yet
+                self.attach_port(&mut slot, hba_idx, i);
+                // Even without a device, so we learn about one being plugged in
+                AhciPort2::enable_hotplug_interrupts(&mut self.abar_ptr.lock().ports[i as usize]);
+            }
+        }
+    }
+
+    /// Sets up the port and whatever is attached to it: a disk is identified, a port multiplier enumerated.
+    /// A port with an unsupported device is torn down again.
+    ///
+    /// `slot` is the locked slot of port `i`. The HBA is only locked, while the port is set up.
+    ///
+    /// Returns a Connected event for every disk identified.
+    fn attach_port(&self, slot: &mut PortSlot, hba_idx: usize, i: u8) -> Vec<PortEvent>
+    {
+        let mut events = Vec::new();
+
+        // println!("SSTS {:3x}, SIG {:x}", self.abar_ptr.ports[i as usize].ssts.get(), self.abar_ptr.ports[i as usize].sig.get());
+        slot.port = {
+            let mut hba = self.abar_ptr.lock();
+            let command_slots_per_port = hba.ghc.cap.get_ncs_adjusted();
+            let is_64bit_aware = hba.ghc.cap.get_s64a();
+            AhciPort2::new(
+                &mut hba,
+                hba_idx,
+                i,
+                command_slots_per_port,
+                is_64bit_aware)
+        };
+
+        let port = match slot.port
+        {
+            Some(ref mut it) => it,
+            None => return events
+        };
+
+        // println!("SSTS {:3x}, SIG {:x}", self.abar_ptr.ports[i as usize].ssts.get(), self.abar_ptr.ports[i as usize].sig.get());
+        let mut sig = port.regs().sig.get();
+        // With a port multiplier attached, PxSIG holds the signature of the device on its port 0 (if any).
+        // Only a software reset to its control port tells them apart.
+        if port.cap.get_spm()
+        {
+            match port.probe_port_multiplier()
+            {
+                Ok(it) => sig = it,
+                Err(err) => debug!("Port {}: Probing for a port multiplier failed: {}", i, err)
+            }
+        }
+
+        match sig
+        {
+            // Refer to osdev wiki for other values, I don't support right now
+            SIG_ATA => match port.identify()
+            {
+                Ok(()) =>
                 {
-                    // 0x101 SATA
-                    // Refer to osdev wiki for other values, I don't support right now
-                    0x101 => if let Some(ref mut port) = self.ports[i as usize]
+                    port.report_smart_health();
+                    events.push(PortEvent::Connected { hba_idx, port_idx: i as usize, pmp: None });
+                }
+                Err(err) => debug!("Port {}: Identify failed: {}", i, err)
+            },
+            SIG_ATAPI => match port.attach_atapi()
+            {
+                Ok(()) => events.push(PortEvent::Connected { hba_idx, port_idx: i as usize, pmp: None }),
+                Err(err) => debug!("Port {}: ATAPI setup failed: {}", i, err)
+            },
+            SIG_PM => Self::attach_port_multiplier(slot, hba_idx, i, &mut events),
+            it =>
+            {
+                // 0xff_ff_ff_ff: Signature not initialized, keep quiet
+                if it != 0xff_ff_ff_ff
+                {
+                    debug!("SIG {:x}", it);
+                }
+                if let Some(port) = slot.port.take()
+                {
+                    port.detach();
+                }
+            }
+        }
+        events
+    }
+
+    /// Enumerates the ports of the port multiplier on port `i` (which is its control port already):
+    /// every port is reset through its SControl, each disk found is identified and added to `pm_ports` of the slot.
+    /// FIS-based switching is enabled, if the HBA and port support it.
+    fn attach_port_multiplier(slot: &mut PortSlot, hba_idx: usize, i: u8, events: &mut Vec<PortEvent>)
+    {
+        let PortSlot { port, pm_ports } = slot;
+        let host = match port
+        {
+            Some(ref mut it) => it,
+            None => return
+        };
+        let fbs = host.cap.get_fbss() && host.regs().cmd.get_fbscp();
+
+        let count = match host.pm_read(AhciPort2::PM_CONTROL_PORT, GSCR_PORT_INFO)
+        {
+            Ok(it) => (it & 0xf) as u8,
+            Err(err) =>
+            {
+                debug!("Port {}: Reading the port multiplier info failed: {}", i, err);
+                return;
+            }
+        };
+        let fbs = fbs && host.enable_fbs();
+        debug!("Port {}: Port Multiplier with {} ports, FIS-based switching: {}", i, count, fbs);
+
+        for pmp in 0..count
+        {
+            if let Err(err) = host.pm_reset_port(pmp)
+            {
+                debug!("Port {}.{}: No device ({})", i, pmp, err);
+                continue;
+            }
+
+            let dev = AhciPort2::new_behind_multiplier(host, pmp);
+            match dev.software_reset()
+            {
+                Ok(SIG_ATA) => match dev.identify()
+                {
+                    Ok(()) =>
                     {
-                        port.identify(self.abar_ptr);
+                        dev.report_smart_health();
+                        events.push(PortEvent::Connected { hba_idx, port_idx: i as usize, pmp: Some(pmp) });
+                        pm_ports.push(dev);
+                        continue;
                     }
-                    // Signature not initialized, keep quiet
-                    0xff_ff_ff_ff => (),
-                    it => debug!("SIG {:x}", it)
+                    Err(err) => debug!("Port {}.{}: Identify failed: {}", i, pmp, err)
+                },
+                Ok(sig) => debug!("Port {}.{}: SIG {:x}", i, pmp, sig),
+                Err(err) => debug!("Port {}.{}: Software reset failed: {}", i, pmp, err)
+            }
+            dev.detach();
+        }
+    }
+
+    /// Called by the hot-plug worker, after PxIS.PCS or PxIS.PRCS were set on the port.
+    /// A port, whose device is gone, is torn down (with every disk behind it, if it is a port multiplier).
+    /// A device appearing on an empty port is set up and identified.
+    ///
+    /// A port with a device, which still communicates, is left alone:
+    /// every COMRESET (as done by recover) makes the device send a COMINIT, which sets PCS as well.
+    ///
+    /// Only the port is locked, the other ports keep working meanwhile.
+    pub(super) fn on_port_change(&self, hba_idx: usize, port_idx: u8) -> Vec<PortEvent>
+    {
+        let mut events = Vec::new();
+        let i = port_idx as usize;
+        let mut slot = self.ports[i].lock();
+
+        // Only the registers are looked up under the HBA lock, the (busy) wait goes without it.
+        // Unsafe Note: as with AhciPort2::regs, they stay mapped as long as the HBA lives and change through a shared reference.
+        let port: &PortRegister = {
+            let hba = self.abar_ptr.lock();
+            unsafe { &*(&hba.ports[i] as *const PortRegister) }
+        };
+
+        // A device being plugged in takes a moment, until its link is up
+        let present = AhciPort2::wait_ms(500, || port.ssts.get() & 0xf == 3);
+
+        if !present
+        {
+            // The disks behind a port multiplier go first, as it owns their memory
+            for dev in slot.pm_ports.drain(..)
+            {
+                events.push(PortEvent::Disconnected { hba_idx, port_idx: i, pmp: dev.pmp });
+                dev.detach();
+            }
+
+            if let Some(port) = slot.port.take()
+            {
+                debug!("Port {}: Device removed", i);
+                if port.identify.is_some()
+                {
+                    events.push(PortEvent::Disconnected { hba_idx, port_idx: i, pmp: None });
+                }
+                port.detach();
+            }
+        }
+        else if slot.port.is_none()
+        {
+            debug!("Port {}: Device connected", i);
+            events = self.attach_port(&mut slot, hba_idx, port_idx);
+        }
+
+        // The port may have been stopped and restarted, keep listening
+        AhciPort2::enable_hotplug_interrupts(port);
+        events
+    }
+
+    /// Command Completion Coalescing: the ports in `ports` (one bit per port) raise a single interrupt
+    /// for every `completions` commands completed, or `timeout_ms` after the first completion not reported yet.
+    /// Errors and connect changes are still reported right away.
+    /// 
+    /// Fails with Unsupported without CAP.CCCS, with InvalidArgument for 0 completions, a timeout of 0 or a port not implemented.
+    pub fn enable_coalescing(&self, ports: u32, completions: u8, timeout_ms: u16) -> Result<(), DiskError>
+    {
+        let mut hba = self.abar_ptr.lock();
+        if !hba.ghc.cap.get_cccs()
+        {
+            return Err(DiskError::Unsupported);
+        }
+        if completions == 0 || timeout_ms == 0 || ports & !hba.ghc.pi.get_raw() != 0
+        {
+            return Err(DiskError::InvalidArgument);
+        }
+
+        // CC, TV and CCC_PORTS may only change, while coalescing is disabled
+        hba.ghc.ccc_ctl.set_en(false);
+        hba.ghc.ccc_ports.set(ports);
+        hba.ghc.ccc_ctl.set_cc(completions);
+        hba.ghc.ccc_ctl.set_tv(timeout_ms);
+
+        // on_interrupt has to know the interrupt, before the HBA raises it
+        let int = hba.ghc.ccc_ctl.get_int();
+        set_coalescing(&mut hba, Some((int, ports)));
+        hba.ghc.ccc_ctl.set_en(true);
+        debug!("Command Completion Coalescing: {}", hba.ghc.ccc_ctl);
+        Ok(())
+    }
+
+    /// Every port interrupts on each completion again
+    pub fn disable_coalescing(&self)
+    {
+        let mut hba = self.abar_ptr.lock();
+        if !hba.ghc.cap.get_cccs()
+        {
+            return;
+        }
+        hba.ghc.ccc_ctl.set_en(false);
+        hba.ghc.ccc_ports.set(0);
+        set_coalescing(&mut hba, None);
+    }
+
+    /// Flushes the write cache of every identified port, logging the ports failing to do so.
+    /// Locks one port at a time.
+    pub fn flush_all(&self)
+    {
+        for slot in self.ports.iter()
+        {
+            for port in slot.lock().iter_mut()
+            {
+                if port.identify.is_none()
+                {
+                    continue;
+                }
+                if let Err(err) = port.flush()
+                {
+                    debug!("Port {}: Flush failed: {}", port.hba_port_idx, err);
+                }
+            }
+        }
+    }
+
+    /// Flushes and spins down every disk (STANDBY IMMEDIATE), which is what an orderly power off looks like to a drive.
+    /// Cutting the power of a spinning disk makes it retract its heads in a hurry, which it counts (and which wears it out).
+    /// Failures are logged, the remaining ports are spun down anyway.
+    pub fn spin_down_all(&self)
+    {
+        for slot in self.ports.iter()
+        {
+            for port in slot.lock().iter_mut()
+            {
+                // The port multiplier itself, it does not spin
+                if port.identify.is_none() && !port.atapi
+                {
+                    continue;
+                }
+                if let Err(err) = port.flush()
+                {
+                    debug!("Port {}: Flush failed: {}", port.hba_port_idx, err);
+                }
+                if let Err(err) = port.standby_immediate()
+                {
+                    debug!("Port {}: STANDBY IMMEDIATE failed: {}", port.hba_port_idx, err);
                 }
             }
         }
@@ -280,7 +612,13 @@ impl Drop for AhciDevice2
 {
     fn drop(&mut self)
     {
-        let ptr = self.abar_ptr as *mut _ as *mut () as usize;
+        // Still registered, as the flush and the standby wait for the interrupt.
+        // The ports are stopped afterwards, the disks should not simply lose their power while spinning.
+        self.spin_down_all();
+        let mut hba = self.abar_ptr.lock();
+        unregister_hba(&mut hba);
+
+        let ptr = &mut **hba as *mut HbaMemory as *mut () as usize;
         let size = (self.abar_actual_size + 0x0f_ffusize) & !0x0f_ffusize;
 
         paging::unmap::<BasePageSize>(ptr, size >> 12);
@@ -296,13 +634,17 @@ impl Drop for AhciDevice2
 // CLB  at offset 1024, length 1024
 
 // Based on the Layout above, this can grow up to 256 bytes
-// the current edit would make it 48 (+8) bytes
+// the current edit would make it about 160 bytes (IdentifyData being the biggest part)
 pub struct AhciPort2
 {
     pub hba_idx: usize,
     /// The Index of the Port in the HBA
     pub hba_port_idx: usize,
-    // port_mem: &'static mut PortRegister,
+    /// The registers of the port inside the ABAR, see `regs`
+    port_mem: *mut PortRegister,
+    /// CAP and CAP2 of the HBA. Both only change on a HBA reset, so the port does not need the HBA (or its lock) to know them.
+    pub cap: Capabilities,
+    pub cap2: CapabilitiesExtended,
     pub clb: &'static mut CommandListStructure,
     pub fb: &'static mut ReceivedFis,
     /// 1 to 32 (inclusive)
@@ -311,8 +653,25 @@ pub struct AhciPort2
     pub is_64bit_aware: bool,
     // TODO: What Size?
     pub size: u64,
+    /// How many NCQ commands can be in flight at once. 0, if HBA or device do not support NCQ.
+    pub queue_depth: u8,
+    /// Milliseconds (ticks) a command may take, before the port is considered hung and gets recovered
+    pub command_timeout: u64,
+    /// What the device reported about itself, None if identify failed (or was not run yet)
+    pub identify: Option<IdentifyData>,
+    /// The port multiplier port every command is sent to. None without a port multiplier,
+    /// PM_CONTROL_PORT for the port multiplier itself.
+    pub pmp: Option<u8>,
+    /// An ATAPI device (optical drive): commands are SCSI CDBs sent through PACKET, it is read only
+    pub atapi: bool,
 }
 
+const _: () = assert!(core::mem::size_of::<AhciPort2>() <= 256, "AhciPort2 would overlap the ReceivedFis on its page");
+
+// Unsafe Note: port_mem points into the ABAR, which stays mapped as long as the port exists (see Drop of AhciDevice2).
+// The port is only used through the lock of its PortSlot.
+unsafe impl Send for AhciPort2 {}
+
 impl AhciPort2
 {
     // Not a fan of so many arguments, but my lizzard brain fails to do something more ellegant.
@@ -320,7 +679,9 @@ impl AhciPort2
         this: *mut Self,
         hba_idx: usize,
         hba_port_idx: usize,
-        // port_mem: &'static mut PortRegister,
+        port_mem: *mut PortRegister,
+        cap: Capabilities,
+        cap2: CapabilitiesExtended,
         clb: &'static mut CommandListStructure,
         fb: &'static mut ReceivedFis,
         command_slot_count: u8,
@@ -331,19 +692,26 @@ impl AhciPort2
         assert!(command_slot_count > 0 && command_slot_count <= 32, "Only 1 to 32 Command Slots are allowed");
 
         addr_of_mut!((*this).hba_idx).write_volatile(hba_idx);
-        // addr_of_mut!((*this).port_mem).write_volatile(port_mem);
+        addr_of_mut!((*this).port_mem).write_volatile(port_mem);
+        addr_of_mut!((*this).cap).write_volatile(cap);
+        addr_of_mut!((*this).cap2).write_volatile(cap2);
         addr_of_mut!((*this).hba_port_idx).write_volatile(hba_port_idx);
         addr_of_mut!((*this).clb).write_volatile(clb);
         addr_of_mut!((*this).fb).write_volatile(fb);
         addr_of_mut!((*this).cmd_slot_count).write_volatile(command_slot_count);
         addr_of_mut!((*this).is_64bit_aware).write_volatile(is_64bit_aware);
+        addr_of_mut!((*this).queue_depth).write_volatile(0);
+        addr_of_mut!((*this).command_timeout).write_volatile(Self::DEFAULT_COMMAND_TIMEOUT);
+        addr_of_mut!((*this).identify).write_volatile(None);
+        addr_of_mut!((*this).pmp).write_volatile(None);
+        addr_of_mut!((*this).atapi).write_volatile(false);
 
         &mut *this
     }
 
     pub fn new(
-        // The HBA owning this port
-        ahci: &mut AhciDevice2,
+        // The registers of the HBA owning this port (locked by the caller)
+        hba: &mut HbaMemory,
         // The Index of the HBA in AHCI_DEVICES
         hba_idx: usize,
         // The index of the port inside param ahci
@@ -354,17 +722,17 @@ impl AhciPort2
         is_64bit_aware: bool) -> Option<&'static mut Self>
     {
         let hba_port_idx = port_idx as usize;
-        let (this, clb, fb) = Self::allocate();
-        if !Self::stop_impl(&mut ahci.abar_ptr.ports[hba_port_idx])
+        let (this, clb, fb) = Self::allocate(is_64bit_aware);
+        if !Self::stop_impl(&mut hba.ports[hba_port_idx])
         {
             Self::deallocate(this);
             return None;
         }
 
-        assert!(!ahci.abar_ptr.ports[hba_port_idx].cmd.get_st());
-        assert!(!ahci.abar_ptr.ports[hba_port_idx].cmd.get_cr());
-        assert!(!ahci.abar_ptr.ports[hba_port_idx].cmd.get_fre());
-        assert!(!ahci.abar_ptr.ports[hba_port_idx].cmd.get_fr());
+        assert!(!hba.ports[hba_port_idx].cmd.get_st());
+        assert!(!hba.ports[hba_port_idx].cmd.get_cr());
+        assert!(!hba.ports[hba_port_idx].cmd.get_fre());
+        assert!(!hba.ports[hba_port_idx].cmd.get_fr());
 
         // Initialize the values pointed by clb and fb
         unsafe {
@@ -376,7 +744,7 @@ impl AhciPort2
         }
 
         {
-            let port = &mut ahci.abar_ptr.ports[hba_port_idx];
+            let port = &mut hba.ports[hba_port_idx];
             
             // I didn't do it, but redox did: disable power management by
             // Setting PxSCTL.IPM Bits 8..=11 to all 1
@@ -410,7 +778,7 @@ impl AhciPort2
             assert!(port.cmd.get_pod(), "Power On Device should be true");
 
             // Spin Up Device if required
-            if ahci.abar_ptr.ghc.cap.get_sss() && !port.cmd.get_sud()
+            if hba.ghc.cap.get_sss() && !port.cmd.get_sud()
             {
                 unsafe { port.cmd.set_sud(true) };
             }
@@ -464,20 +832,19 @@ impl AhciPort2
             debug!("After Recv: PxSSTS.DET = {:x}, PxSIG: {:x}", port.ssts.get() & 0xf, port.sig.get());
 
             // Original Idea: Check if a SATA drive is at the port, and if not, free memory
-            // Left Hand Side: Is Physical Communications Established to an connected device?
-            // Right Hand Side: Is the Signature hinting at a SATA device?
-            if port.ssts.get() & 0xf != 3 || port.sig.get() != 0x01_01
-            {
-                // debug!("Cleanup Second Chance (timeout after 60 seconds).");
-                // if Self::stop_impl(port)
-                // {
-                //     debug!("ST: {}, CR: {}, FRE: {}, FR: {}", port.cmd.get_st(), port.cmd.get_cr(), port.cmd.get_fre(), port.cmd.get_fr());
-                //     port.fb.set(0);
-                //     port.fbu.set(0);
-                //     port.clb.set(0);
-                //     port.clbu.set(0);
-                //     Self::deallocate(this);
-                // }
+            // Is Physical Communications Established to an connected device?
+            // The signature is up to the caller, as a port multiplier may hide behind any of them.
+            if port.ssts.get() & 0xf != 3
+            {
+                // With hot-plug, this happens more than once per port. No longer leaking the page.
+                if Self::stop_impl(port)
+                {
+                    port.fb.set(0);
+                    port.fbu.set(0);
+                    port.clb.set(0);
+                    port.clbu.set(0);
+                    Self::deallocate(this);
+                }
                 return None;
             }
 
@@ -490,10 +857,10 @@ impl AhciPort2
         }
 
         // Clear the pending interrupt of this port in the HBA
-        ahci.abar_ptr.ghc.is.clear(port_idx);
+        hba.ghc.is.clear(port_idx);
 
         {
-            let port = &mut ahci.abar_ptr.ports[hba_port_idx];
+            let port = &mut hba.ports[hba_port_idx];
 
             // Error Interrupts, currently all of them will just panic
             port.ie.set_hbfs(true);
@@ -504,10 +871,11 @@ impl AhciPort2
             port.ie.set_ipms(true);
 
             // Receive Interrupts, a fis was received from the device
-            // port.ie.set_sdbs(true);
-            // port.ie.set_dss(true);
-            // port.ie.set_pss(true);
-            // port.ie.set_dhrs(true); // this line seems to be the only relevant one
+            // These wake up the task waiting for the command (see on_interrupt)
+            port.ie.set_sdbs(true);
+            port.ie.set_dss(true);
+            port.ie.set_pss(true); // PIO commands (identify) complete with this one
+            port.ie.set_dhrs(true); // DMA commands complete with this one
 
             // Start the Port
             port.cmd.set_st(true);
@@ -526,6 +894,9 @@ impl AhciPort2
                     this,
                     hba_idx,
                     hba_port_idx,
+                    &mut hba.ports[hba_port_idx],
+                    hba.ghc.cap.clone(),
+                    hba.ghc.cap2.clone(),
                     &mut *clb,
                     &mut *fb,
                     command_slot_count,
@@ -533,14 +904,22 @@ impl AhciPort2
         }
     }
 
+    /// The registers of the port.
+    /// 
+    /// Unsafe Note: The reference does not borrow self, so the registers can be used alongside the rest of the port.
+    /// It is a shared one, Register changes through it like a Cell, so on_interrupt clearing PxIS aliases nothing.
+    /// The registers stay mapped, as long as the HBA (and with it the port) lives.
+    fn regs(&self) -> &'static PortRegister
+    {
+        unsafe { &*self.port_mem }
+    }
+
     pub fn start(&mut self)
     {
-        let mut it = AHCI_DEVICES.lock();
-        let hba = &mut it[self.hba_idx];
-        Self::stop_impl(&mut hba.abar_ptr.ports[self.hba_port_idx]);
+        Self::stop_impl(self.regs());
     }
 
-    fn start_impl(port: &mut PortRegister)
+    fn start_impl(port: &PortRegister)
     {
         port.cmd.set_fre(true);
         port.cmd.set_st(true);
@@ -548,12 +927,10 @@ impl AhciPort2
 
     pub fn stop(&mut self) -> bool
     {
-        let mut it = AHCI_DEVICES.lock();
-        let hba = &mut it[self.hba_idx];
-        Self::stop_impl(&mut hba.abar_ptr.ports[self.hba_port_idx])
+        Self::stop_impl(self.regs())
     }
 
-    fn stop_impl(port: &mut PortRegister) -> bool
+    fn stop_impl(port: &PortRegister) -> bool
     {
         // 10.1.2
         if port.cmd.get_st()
@@ -609,9 +986,7 @@ impl AhciPort2
     /// Before each wait, checks if PxCMD.FR (for FRE) or PxCMD.ST (for CR) is set, bailing with "false" if they are set.
     pub fn is_stopped(&self) -> bool
     {
-        let mut it = AHCI_DEVICES.lock();
-        let hba = &mut it[self.hba_idx];
-        Self::is_stopped_impl(&hba.abar_ptr.ports[self.hba_port_idx])
+        Self::is_stopped_impl(self.regs())
     }
 
     fn is_stopped_impl(port: &PortRegister) -> bool
@@ -670,11 +1045,11 @@ impl AhciPort2
     /// All pointers point to uninitiallized memory
     /// 
     /// As all pointers are part of 1 page, it any could be used for a free operation, after moving the pointer to the beginning of the page.
-    fn allocate() -> (*mut AhciPort2, *mut CommandListStructure, *mut ReceivedFis)
+    /// 
+    /// Below 4 GiB, unless the HBA is 64 bit aware.
+    fn allocate(is_64bit_aware: bool) -> (*mut AhciPort2, *mut CommandListStructure, *mut ReceivedFis)
     {
-        let phys = physicalmem::allocate(4096);
-        let virt = virtualmem::allocate(4096);
-        paging::map::<BasePageSize>(virt, phys, 1, PageTableEntryFlags::WRITABLE | PageTableEntryFlags::CACHE_DISABLE | PageTableEntryFlags::WRITE_THROUGH);
+        let (virt, _) = dma::allocate(1, is_64bit_aware);
 
         (virt as *mut AhciPort2, (virt + 2048) as *mut CommandListStructure, (virt + 256) as *mut ReceivedFis)
     }
@@ -682,29 +1057,142 @@ impl AhciPort2
     /// Requires the AhciPort2 pointer from allocate.
     fn deallocate(this: *mut AhciPort2)
     {
-        let phys = paging::get_physical_address::<BasePageSize>(this as usize);
-        paging::unmap::<BasePageSize>(this as usize, 1);
+        Self::deallocate_page(this as usize);
+    }
+
+    fn deallocate_page(virt: usize)
+    {
+        dma::deallocate(virt, 1);
+    }
+
+    /// PxIS.PCS (Port Connect Change) and PxIS.PRCS (PhyRdy Change) tell us about devices coming and going.
+    /// Both are only cleared through PxSERR.DIAG.X and .N, which have to be cleared before enabling them.
+    fn enable_hotplug_interrupts(port: &PortRegister)
+    {
+        port.serr.set(SERR_DIAG_N | SERR_DIAG_X);
+        port.ie.set_pce(true);
+        port.ie.set_prce(true);
+    }
+
+    /// Tears the port down after its device is gone: stops it, detaches the memory from the HBA and frees it.
+    /// 
+    /// As commands are only issued while holding the lock of the port, none can be in flight here.
+    /// The ones, which were in flight on removal, were failed by on_interrupt.
+    /// 
+    /// A disk behind a port multiplier only owns the page of its AhciPort2, the rest belongs to the port multiplier.
+    fn detach(&'static mut self)
+    {
+        if self.is_behind_multiplier()
+        {
+            Self::deallocate(self);
+            return;
+        }
+
+        // Nobody is interested in the errors of a port, which is gone
+        take_port_error(self.hba_idx, self.hba_port_idx);
+
+        let port = self.regs();
+        if Self::stop_impl(port)
+        {
+            // FIS-based switching moved the received FIS area to a page of its own
+            if port.fbs.get() & FBS_EN != 0
+            {
+                port.fbs.set(port.fbs.get() & !FBS_EN);
+                Self::deallocate_page(self.fb as *mut ReceivedFis as usize & !0x0f_ff);
+            }
+            unsafe { port.cmd.set_pma(false) };
+            port.fb.set(0);
+            port.fbu.set(0);
+            port.clb.set(0);
+            port.clbu.set(0);
+            Self::deallocate(self);
+        }
+        else
+        {
+            // The HBA may still write into the memory, so it is better off leaked
+            debug!("Port {}: Failed to stop, leaking its memory", self.hba_port_idx);
+        }
+    }
+}
+
+/// One read or write of a batch for `AhciPort2::read_write_queued`
+pub struct QueuedRequest
+{
+    pub first_sector: u64,
+    pub buffer: *mut u8,
+    pub buffer_len: usize,
+    pub write: bool,
+    /// Set, once the request completed: the amount of bytes transferred or why it failed
+    pub result: Option<Result<usize, DiskError>>
+}
+
+impl QueuedRequest
+{
+    pub fn read(first_sector: u64, buffer: &mut [u8]) -> Self
+    {
+        Self { first_sector, buffer: buffer.as_mut_ptr(), buffer_len: buffer.len(), write: false, result: None }
+    }
 
-        virtualmem::deallocate(this as usize, 4096);
-        physicalmem::deallocate(phys, 4096);
+    /// The buffer is never written to, the pointer is just mut to share the struct with reads.
+    pub fn write(first_sector: u64, buffer: &[u8]) -> Self
+    {
+        Self { first_sector, buffer: buffer.as_ptr() as *mut u8, buffer_len: buffer.len(), write: true, result: None }
     }
 }
 
 impl AhciPort2
 {
     const ATA_CMD_IDENTIFY: u8 = 0xEC;
+    const ATA_CMD_IDENTIFY_PACKET: u8 = 0xA1;
+    const ATA_CMD_PACKET: u8 = 0xA0;
     const ATA_CMD_READ_EXT: u8 = 0x25;
     const ATA_CMD_WRITE_EXT: u8 = 0x35;
-
-    pub fn write_raw(&mut self, hba: &mut HbaMemory, first_sector: u64, buffer: *const u8, buffer_len: usize) -> Option<usize>
+    const ATA_CMD_READ_VERIFY: u8 = 0x40;
+    const ATA_CMD_READ_VERIFY_EXT: u8 = 0x42;
+    const ATA_CMD_READ_LOG_EXT: u8 = 0x2F;
+    const ATA_CMD_READ_FPDMA_QUEUED: u8 = 0x60;
+    const ATA_CMD_WRITE_FPDMA_QUEUED: u8 = 0x61;
+    const ATA_CMD_DATA_SET_MANAGEMENT: u8 = 0x06;
+    const ATA_CMD_FLUSH_CACHE: u8 = 0xE7;
+    const ATA_CMD_FLUSH_CACHE_EXT: u8 = 0xEA;
+    const ATA_CMD_SET_FEATURES: u8 = 0xEF;
+    const SET_FEATURES_ENABLE_WRITE_CACHE: u8 = 0x02;
+    const SET_FEATURES_DISABLE_WRITE_CACHE: u8 = 0x82;
+    const ATA_CMD_STANDBY_IMMEDIATE: u8 = 0xE0;
+    const ATA_CMD_IDLE_IMMEDIATE: u8 = 0xE1;
+    const ATA_CMD_STANDBY: u8 = 0xE2;
+    const ATA_CMD_SMART: u8 = 0xB0;
+    const ATA_CMD_READ_PORT_MULTIPLIER: u8 = 0xE4;
+    const ATA_CMD_WRITE_PORT_MULTIPLIER: u8 = 0xE8;
+    /// The port multiplier itself is addressed as port 15
+    pub const PM_CONTROL_PORT: u8 = 15;
+    const SMART_READ_DATA: u8 = 0xD0;
+    const SMART_READ_THRESHOLDS: u8 = 0xD1;
+    const SMART_RETURN_STATUS: u8 = 0xDA;
+    /// DATA SET MANAGEMENT feature bit 0
+    const DSM_TRIM: u8 = 0x01;
+    /// One 512 byte block of the DSM payload holds 64 LBA range entries of 8 bytes each
+    const DSM_ENTRIES_PER_BLOCK: usize = 64;
+    /// An entry has a 16 bit sector count
+    const DSM_MAX_RANGE_LEN: u32 = 0xff_ff;
+
+    /// 30 seconds, enough for a drive to spin up
+    pub const DEFAULT_COMMAND_TIMEOUT: u64 = 30_000;
+    /// How often a command is retried after an interface error or a timeout
+    const MAX_RETRIES: u32 = 2;
+    /// The most PRDT entries of a single command, as many as fit on the command table page
+    const MAX_PRDT_PER_COMMAND: usize = CommandTable2Ptr::MAX_PRDT_ENTRIES as usize - 1;
+    /// READ/WRITE (FPDMA QUEUED) EXT take a 16 bit sector count, with 0 meaning 65536
+    const MAX_SECTORS_PER_COMMAND: u64 = 65536;
+
+    pub fn write_raw(&mut self, first_sector: u64, buffer: *const u8, buffer_len: usize) -> Result<usize, DiskError>
     {
-        // assert_eq!(buffer.len() % 512, 0, "The buffer must have a size which is a multiple of 512.");
-        unsafe { self.read_write_raw(hba, first_sector, buffer as usize, buffer_len, true) }
+        unsafe { self.read_write_raw(first_sector, buffer as usize, buffer_len, true) }
     }
 
-    pub fn read_raw(&mut self, hba: &mut HbaMemory, first_sector: u64, buffer: *mut u8, buffer_len: usize) -> Option<usize>
+    pub fn read_raw(&mut self, first_sector: u64, buffer: *mut u8, buffer_len: usize) -> Result<usize, DiskError>
     {
-        unsafe { self.read_write_raw(hba, first_sector, buffer as usize, buffer_len, false) }
+        unsafe { self.read_write_raw(first_sector, buffer as usize, buffer_len, false) }
     }
 
     // OSDevWiki has the following arguments:
@@ -712,18 +1200,57 @@ impl AhciPort2
     // "starth:startl": first sector to read (fis: lba address)
     // buf: the target buffer for data
     /// Unsafe Note: buffer must point to writable memory on a read
-    unsafe fn read_write_raw(&mut self, hba: &mut HbaMemory, first_sector: u64, buffer: usize, buffer_len: usize, write: bool) -> Option<usize>
+    /// 
+    /// Requests too large for a single command (more than 65536 sectors or PRDT entries than fit the command table)
+    /// are split into several commands, run one after the other.
+    unsafe fn read_write_raw(&mut self, first_sector: u64, buffer: usize, buffer_len: usize, write: bool) -> Result<usize, DiskError>
+    {
+        if write && self.atapi
+        {
+            return Err(DiskError::Unsupported);
+        }
+        Self::check_buffer(buffer as u64, buffer_len as u64)?;
+        self.check_request(first_sector, buffer_len as u64)?;
+        let sector_size = self.logical_sector_size() as u64;
+
+        let mut transferred = 0u64;
+        while transferred < buffer_len as u64
+        {
+            let chunk_buffer = buffer as u64 + transferred;
+            let chunk_len = self.command_len(chunk_buffer, buffer_len as u64 - transferred)?;
+            let chunk_sector = first_sector + transferred / sector_size;
+
+            let it = self.read_write_command(chunk_sector, chunk_buffer, chunk_len, write)?;
+            transferred += it as u64;
+            if it as u64 != chunk_len
+            {
+                // The device transferred less than asked for, there is no point in going on
+                break;
+            }
+        }
+        Ok(transferred as usize)
+    }
+
+    // OSDevWiki has the following arguments:
+    // count: count of sectors to read (fis: countl, counth)
+    // "starth:startl": first sector to read (fis: lba address)
+    // buf: the target buffer for data
+    /// A single READ/WRITE DMA EXT. The request must fit into one command (see command_len).
+    /// 
+    /// Returns: PRDBC, the amount of bytes transferred
+    unsafe fn read_write_command(&mut self, first_sector: u64, buffer: u64, buffer_len: u64, write: bool) -> Result<u32, DiskError>
     {
-        let sector_count = buffer_len as u64 / 512;
+        let sector_count = buffer_len / self.logical_sector_size() as u64;
+        debug_assert!(sector_count <= self.max_sectors_per_command());
 
-        // Why is a sector 512? Backwards compatibility?
-        // assert_eq!(buffer.len() % 512, 0, "The buffer must have a size which is a multiple of 512.");
-        assert_eq!(buffer_len & 0x01_ff, 0, "The buffer must have a size which is a multiple of 512.");
-        assert_eq!(buffer & 1, 0, "The buffer must be 2-byte aligned");
-        assert!(sector_count < 0x01_00_00, "Sector Count must be less than 65536");
-        // Instead of &mut [u8], maybe an *mut u8? Or *mut u16?
+        if self.atapi
+        {
+            // check_request keeps the LBA below the capacity, which READ CAPACITY(10) reported in 32 bits
+            let cdb = Cdb::read_10(first_sector as u32, sector_count as u16);
+            return self.packet(&cdb, buffer, buffer_len);
+        }
 
-        let mut fis = RegH2D::default();
+        let fis = RegH2D::default();
         fis.pmport_cc.set(0x80);
         if write
         {
@@ -741,290 +1268,1740 @@ impl AhciPort2
         fis.lba4.set((first_sector >> 32) as u8);
         fis.lba5.set((first_sector >> 40) as u8);
 
+        // 65536 is truncated to 0, which is exactly how it is encoded
         fis.countl.set(sector_count as u8);
         fis.counth.set((sector_count >> 8) as u8);
 
         fis.device.set(0x40); // Quote from OSDevWiki: LBA Mode
 
-        if let Some((slot, _prdt_count)) =
-            unsafe { self.handle_fis(hba, write, buffer as *mut () as u64, buffer_len as u64, &fis) }
-        {
-            debug!(
-                "Waiting for CI to clear and Port to be not busy, CI Clear? {}, TFD Clear? {}",
-                hba.ports[self.hba_port_idx].ci.get() & (1u32 << slot) == 0,
-                hba.ports[self.hba_port_idx].tfd.get() & 0x88 == 0);
-            while hba.ports[self.hba_port_idx].ci.get() & (1u32 << slot) != 0
-            {
-                core::hint::spin_loop();
-            }
-            debug!("Waiting for CI completed");
-            while hba.ports[self.hba_port_idx].tfd.get() & 0x88 != 0
-            {
-                core::hint::spin_loop();
-            }
-            debug!("Done, CI Clear? {}, TFD Clear? {}, SACT Clear? {}",
-                hba.ports[self.hba_port_idx].ci.get() & (1u32 << slot) == 0,
-                hba.ports[self.hba_port_idx].tfd.get() & 0x88 == 0,
-                hba.ports[self.hba_port_idx].sact.get() & (1u32 << slot) == 0);
-            let it = self.clb[slot as usize].get_prdbc();
-            Some(it as usize)
-        }
-        else
-        {
-            None
-        }
+        let (slot, _prdt_count) = self.handle_fis(write, buffer, buffer_len, &fis)?;
+        Ok(self.clb[slot as usize].get_prdbc())
     }
 
-    pub fn read_u8(&mut self, hba: &mut HbaMemory, first_sector: u64, buffer: &mut [u8]) -> Option<usize>
+    pub fn read_u8(&mut self, first_sector: u64, buffer: &mut [u8]) -> Result<usize, DiskError>
     {
         let buffer_len = buffer.len();
         let buffer = buffer as *mut _ as *mut u8;
-        self.read_raw(hba, first_sector, buffer, buffer_len)
+        self.read_raw(first_sector, buffer, buffer_len)
     }
 
-    pub fn read_u16(&mut self, hba: &mut HbaMemory, first_sector: u64, buffer: &mut [u16]) -> Option<usize>
+    pub fn read_u16(&mut self, first_sector: u64, buffer: &mut [u16]) -> Result<usize, DiskError>
     {
         let buffer_len = buffer.len() * 2; // 2 = size of u16
         let buffer = buffer as *mut _ as *mut u8;
-        self.read_raw(hba, first_sector, buffer, buffer_len)
+        self.read_raw(first_sector, buffer, buffer_len)
     }
 
-    pub fn write_u8(&mut self, hba: &mut HbaMemory, first_sector: u64, buffer: &[u8]) -> Option<usize>
+    pub fn write_u8(&mut self, first_sector: u64, buffer: &[u8]) -> Result<usize, DiskError>
     {
         let buffer_len = buffer.len();
         let buffer = buffer as *const _ as *const u8;
-        self.write_raw(hba, first_sector, buffer, buffer_len)
+        self.write_raw(first_sector, buffer, buffer_len)
     }
 
-    pub fn write_u16(&mut self, hba: &mut HbaMemory, first_sector: u64, buffer: &[u16]) -> Option<usize>
+    pub fn write_u16(&mut self, first_sector: u64, buffer: &[u16]) -> Result<usize, DiskError>
     {
         let buffer_len = buffer.len() * 2; // 2 = size of u16
         let buffer = buffer as *const _ as *const u8;
-        self.write_raw(hba, first_sector, buffer, buffer_len)
+        self.write_raw(first_sector, buffer, buffer_len)
     }
 
-    /// Sets self.lba and self.size to the values the device returns on a ATA_CMD_IDENTIFY.
-    /// 
-    /// Failing that, sets the two values to 0.
-    pub fn identify(&mut self, hba: &mut HbaMemory)
+    /// A disk behind a port multiplier, sharing the memory of the port multiplier
+    pub fn is_behind_multiplier(&self) -> bool
     {
-        let mut buffer = [0u16; 256];
-        let buffer_len: usize = core::mem::size_of_val(&buffer);
-        // I want bytes, not T (= u16)
-        // Should I either hardcode 512 or use core::mem::size_of::<[u16; 256]>(), or keep what I have?
+        matches!(self.pmp, Some(it) if it != Self::PM_CONTROL_PORT)
+    }
 
-        let mut fis = RegH2D::default();
-        fis.command.set(Self::ATA_CMD_IDENTIFY);
-        fis.pmport_cc.set(0x80);
-        fis.countl.set(1);
+    /// A disk behind a port multiplier. It shares the command list with the port multiplier (`host`),
+    /// and the received FIS area as well, unless FIS-based switching gives each device its own.
+    fn new_behind_multiplier(host: &mut AhciPort2, pmp: u8) -> &'static mut Self
+    {
+        let (this, _, _) = Self::allocate(host.is_64bit_aware);
 
-        self.lba = 0;
-        self.size = 0;
+        // Unsafe Note: Aliasing the command list (and received FIS area) is fine, as only one task at a time
+        // issues commands to the port (holding its lock, which the disks behind it share), whichever device behind it they are for.
+        let clb = unsafe { &mut *(host.clb as *mut CommandListStructure) };
+        let fb = if host.regs().fbs.get() & FBS_EN != 0
+        {
+            // The host points at the area of the control port (15), each device has its own 256 bytes
+            unsafe { &mut *(host.fb as *mut ReceivedFis).sub((Self::PM_CONTROL_PORT - pmp) as usize) }
+        }
+        else
+        {
+            unsafe { &mut *(host.fb as *mut ReceivedFis) }
+        };
+
+        let it = unsafe {
+            Self::init_memory(
+                this,
+                host.hba_idx,
+                host.hba_port_idx,
+                host.port_mem,
+                host.cap.clone(),
+                host.cap2.clone(),
+                clb,
+                fb,
+                host.cmd_slot_count,
+                host.is_64bit_aware)
+        };
+        it.pmp = Some(pmp);
+        it
+    }
 
-        let is_ready = unsafe { self.handle_fis(hba, false, &mut buffer as *mut _ as u64, buffer_len as u64, &fis) };
-        if let Some((command_slot, prdt_count)) = is_ready
+    /// 10.4.1: Software Reset through Device Control.SRST (two H2D Register FISes, setting and clearing it).
+    /// Returns the signature from the D2H Register FIS the device (or port multiplier) answers with.
+    fn software_reset(&mut self) -> Result<u32, DiskError>
+    {
+        for srst in [true, false]
         {
-            // I was right, CI has to be set, after PxCMD.ST is set to 1.
-            // Why is my laptop having problems with my old code then?
-            // Do I need to allocate and initialize PRTDLs, even when the command is not issued?
-            // Self::start_impl(&mut hba.ports[self.hba_port_idx]);
-            assert_eq!(prdt_count, 1, "prdt_count is not 1");
+            let fis = RegH2D::default();
+            // C (Bit 7) cleared: the FIS updates the Device Control register instead of issuing a command
+            fis.pmport_cc.set(0);
+            fis.control.set(if srst { 0x04 } else { 0 });
+
+            let port = self.regs();
+            let slot = Self::find_empty_slot(port, self.cmd_slot_count as usize)
+                .ok_or(DiskError::NoFreeSlot)?;
+            let (_, _cmd_table) = unsafe { self.prepare_command(slot, false, 0, 0, &fis, None)? };
+            {
+                let header = &mut self.clb[slot as usize];
+                header.set_reset(srst);
+                header.set_clear_busy(srst);
+            }
 
-            debug!("Waiting for CI to clear");
-            while hba.ports[self.hba_port_idx].ci.get() & (1u32 << command_slot) != 0
+            // No interrupt to wait for on the first FIS, so both are polled
+            port.ci.set(1u32 << slot);
+            if !Self::wait_ms(self.command_timeout, || port.ci.get() & (1u32 << slot) == 0)
             {
-                core::hint::spin_loop();
+                self.recover();
+                return Err(DiskError::Timeout);
+            }
+            if srst
+            {
+                busy_sleep(1); // SRST has to be asserted for at least 5 µs
             }
-            debug!("Done");
-            assert_eq!(self.clb[command_slot as usize].get_prdbc(), 512, "[Identify] Expected to receive 512 bytes");
+        }
 
-            // for (i, value) in buffer.iter().enumerate()
-            // {
-            //     if i % 8 == 0
-            //     {
-            //         println!();
-            //     }
-            //     print!("{:04x} ", value);
-            // }
-            // println!();
-            // println!("{}", self.clb[command_slot as usize].get_prdbc());
+        let port = self.regs();
+        if !Self::wait_ms(self.command_timeout, || port.tfd.get() & 0x88 == 0)
+        {
+            return Err(DiskError::Timeout);
+        }
+        let rfis = &self.fb.rfis;
+        Ok(
+            rfis.countl.get() as u32
+            | (rfis.lba0.get() as u32) << 8
+            | (rfis.lba1.get() as u32) << 16
+            | (rfis.lba2.get() as u32) << 24)
+    }
+
+    /// Sets PxCMD.PMA and sends a software reset to the control port of a port multiplier.
+    /// Returns the signature of whoever answered: the port multiplier, or the device itself, which ignores the PM port.
+    /// 
+    /// Without a port multiplier, PxCMD.PMA is cleared again.
+    fn probe_port_multiplier(&mut self) -> Result<u32, DiskError>
+    {
+        self.set_port_multiplier_attached(true)?;
+        self.pmp = Some(Self::PM_CONTROL_PORT);
+
+        let sig = self.software_reset();
+        if sig != Ok(SIG_PM)
+        {
+            self.pmp = None;
+            self.set_port_multiplier_attached(false)?;
+        }
+        sig
+    }
+
+    /// PxCMD.PMA can only be changed with PxCMD.ST cleared
+    fn set_port_multiplier_attached(&mut self, value: bool) -> Result<(), DiskError>
+    {
+        let port = self.regs();
+        port.cmd.set_st(false);
+        if !Self::wait_ms(500, || !port.cmd.get_cr())
+        {
+            return Err(DiskError::PortNotReady);
+        }
+        unsafe { port.cmd.set_pma(value) };
+        port.cmd.set_st(true);
+        Ok(())
+    }
+
+    /// 9.3.3: Moves the received FIS area to a page of its own (256 bytes for each of the 16 port multiplier ports)
+    /// and sets PxFBS.EN. Only for the control port of a port multiplier.
+    /// 
+    /// Returns false, if the port could not be stopped and stays with command-based switching.
+    fn enable_fbs(&mut self) -> bool
+    {
+        let port = self.regs();
+        if !Self::stop_impl(port)
+        {
+            return false;
+        }
+
+        let (virt, phys) = dma::allocate(1, self.is_64bit_aware);
+        unsafe { (virt as *mut u8).write_bytes(0, 4096) };
+
+        port.fb.set(phys as u32);
+        port.fbu.set(((phys as u64) >> 32) as u32);
+        port.fbs.set(port.fbs.get() | FBS_EN);
+        self.fb = unsafe { &mut *(virt as *mut ReceivedFis).add(Self::PM_CONTROL_PORT as usize) };
+
+        port.cmd.set_fre(true);
+        port.cmd.set_st(true);
+        true
+    }
+
+    /// READ PORT MULTIPLIER: reads `register` of the port multiplier port `pm_port`
+    /// (PM_CONTROL_PORT for the General Status and Control Registers).
+    fn pm_read(&mut self, pm_port: u8, register: u16) -> Result<u32, DiskError>
+    {
+        let fis = RegH2D::default();
+        fis.pmport_cc.set(0x80);
+        fis.command.set(Self::ATA_CMD_READ_PORT_MULTIPLIER);
+        fis.featurel.set(register as u8);
+        fis.featureh.set((register >> 8) as u8);
+        fis.device.set(pm_port & 0xf);
+
+        unsafe { self.handle_fis(false, 0, 0, &fis)? };
+
+        // Bits 0..=7 in Count, the rest in LBA
+        let rfis = &self.fb.rfis;
+        Ok(
+            rfis.countl.get() as u32
+            | (rfis.lba0.get() as u32) << 8
+            | (rfis.lba1.get() as u32) << 16
+            | (rfis.lba2.get() as u32) << 24)
+    }
+
+    /// WRITE PORT MULTIPLIER, see pm_read
+    fn pm_write(&mut self, pm_port: u8, register: u16, value: u32) -> Result<(), DiskError>
+    {
+        let fis = RegH2D::default();
+        fis.pmport_cc.set(0x80);
+        fis.command.set(Self::ATA_CMD_WRITE_PORT_MULTIPLIER);
+        fis.featurel.set(register as u8);
+        fis.featureh.set((register >> 8) as u8);
+        fis.device.set(pm_port & 0xf);
+        fis.countl.set(value as u8);
+        fis.lba0.set((value >> 8) as u8);
+        fis.lba1.set((value >> 16) as u8);
+        fis.lba2.set((value >> 24) as u8);
+
+        unsafe { self.handle_fis(false, 0, 0, &fis)? };
+        Ok(())
+    }
+
+    /// Resets the link of the port multiplier port `pm_port` through its SControl (a COMRESET),
+    /// waits up to a second for a device to communicate and clears its SError.
+    fn pm_reset_port(&mut self, pm_port: u8) -> Result<(), DiskError>
+    {
+        // DET = 1: COMRESET, IPM = 3: no Partial and Slumber
+        self.pm_write(pm_port, PSCR_SCONTROL, 0x3_01)?;
+        busy_sleep(2); // Docs: at least 1 ms
+        self.pm_write(pm_port, PSCR_SCONTROL, 0x3_00)?;
+
+        let deadline = get_ticks() + 1000;
+        while self.pm_read(pm_port, PSCR_SSTATUS)? & 0xf != 3
+        {
+            if get_ticks() >= deadline
+            {
+                return Err(DiskError::PortNotReady);
+            }
+            busy_sleep(10);
+        }
+
+        self.pm_write(pm_port, PSCR_SERROR, 0xff_ff_ff_ff)
+    }
+
+    /// Sets up an ATAPI device: IDENTIFY PACKET DEVICE, INQUIRY and, if there is a medium, its capacity.
+    /// A missing medium is not an error.
+    fn attach_atapi(&mut self) -> Result<(), DiskError>
+    {
+        self.atapi = true;
+        // Only drives the activity LED, but it is what the bit is for
+        self.regs().cmd.set_atapi(true);
+
+        self.identify()?;
+        let inquiry = self.inquiry()?;
+        debug!(
+            "ATAPI: {} {} {}, Type: {:x}, Removable: {}",
+            inquiry.vendor(),
+            inquiry.product(),
+            inquiry.revision(),
+            inquiry.peripheral_device_type,
+            inquiry.removable);
+
+        if let Err(err) = self.load_medium()
+        {
+            debug!("Port {}: No medium ({})", self.hba_port_idx, err);
+        }
+        Ok(())
+    }
+
+    /// An ATAPI device can only be read from
+    pub fn is_read_only(&self) -> bool
+    {
+        self.atapi
+    }
+
+    /// PACKET: sends the SCSI command `cdb` to an ATAPI device, the data (if any) is read into the buffer by DMA.
+    /// 
+    /// Returns: PRDBC, the amount of bytes transferred
+    unsafe fn packet(&mut self, cdb: &[u8; 16], buffer: u64, buffer_len: u64) -> Result<u32, DiskError>
+    {
+        let fis = RegH2D::default();
+        fis.pmport_cc.set(0x80);
+        fis.command.set(Self::ATA_CMD_PACKET);
+        // Bit 0: DMA
+        fis.featurel.set(if buffer_len != 0 { 0x01 } else { 0x00 });
+
+        let (slot, _prdt_count) = self.handle_command(false, buffer, buffer_len, &fis, Some(cdb))?;
+        Ok(self.clb[slot as usize].get_prdbc())
+    }
+
+    /// SCSI INQUIRY
+    pub fn inquiry(&mut self) -> Result<InquiryData, DiskError>
+    {
+        if !self.atapi
+        {
+            return Err(DiskError::Unsupported);
+        }
+
+        // u16, so the buffer is 2 byte aligned, as check_buffer wants it
+        let mut buffer = [0u16; InquiryData::LEN / 2];
+        let cdb = Cdb::inquiry(InquiryData::LEN as u8);
+        unsafe { self.packet(&cdb, buffer.as_mut_ptr() as u64, InquiryData::LEN as u64)? };
+        // Unsafe Note: same size, any byte is a valid u8
+        let bytes = unsafe { &*(buffer.as_ptr() as *const [u8; InquiryData::LEN]) };
+        Ok(InquiryData::parse(bytes))
+    }
+
+    /// SCSI TEST UNIT READY: Ok, if there is a medium and the drive is ready to read it.
+    /// Otherwise a DeviceFault with the sense key in bits 4..=7 of its error (2: Not Ready, 6: Unit Attention).
+    pub fn test_unit_ready(&mut self) -> Result<(), DiskError>
+    {
+        if !self.atapi
+        {
+            return Err(DiskError::Unsupported);
+        }
+
+        let cdb = Cdb::test_unit_ready();
+        unsafe { self.packet(&cdb, 0, 0)? };
+        Ok(())
+    }
+
+    /// SCSI READ CAPACITY(10): (last LBA, block length in bytes)
+    pub fn read_capacity(&mut self) -> Result<(u32, u32), DiskError>
+    {
+        if !self.atapi
+        {
+            return Err(DiskError::Unsupported);
+        }
+
+        // u16, so the buffer is 2 byte aligned, as check_buffer wants it
+        let mut words = [0u16; 4];
+        let cdb = Cdb::read_capacity_10();
+        unsafe { self.packet(&cdb, words.as_mut_ptr() as u64, 8)? };
+        // Unsafe Note: same size, any byte is a valid u8
+        let buffer = unsafe { &*(words.as_ptr() as *const [u8; 8]) };
+        let last_lba = u32::from_be_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]);
+        let block_len = u32::from_be_bytes([buffer[4], buffer[5], buffer[6], buffer[7]]);
+        Ok((last_lba, block_len))
+    }
+
+    /// Checks for a medium in the ATAPI device and takes over its capacity and block size (usually 2048).
+    /// Call it again after the medium changed. Without a medium, the size is 0 and every read fails.
+    pub fn load_medium(&mut self) -> Result<(), DiskError>
+    {
+        self.size = 0;
+
+        // The first command after a medium change fails with Unit Attention, so give it a second try
+        if self.test_unit_ready().is_err()
+        {
+            self.test_unit_ready()?;
+        }
+        let (last_lba, block_len) = self.read_capacity()?;
+        if block_len == 0 || block_len & 1 != 0
+        {
+            return Err(DiskError::MisalignedBuffer);
+        }
+
+        if let Some(ref mut it) = self.identify
+        {
+            it.sectors = last_lba as u64 + 1;
+            it.logical_sector_size = block_len;
+            it.physical_sector_size = block_len;
+            self.size = it.capacity();
+        }
+        debug!("ATAPI Medium: {} Blocks of {} Bytes", last_lba as u64 + 1, block_len);
+        Ok(())
+    }
+
+    /// What PxSSTS says about the link: is a device there, how fast is it and in which power state.
+    pub fn link_status(&self) -> LinkStatus
+    {
+        LinkStatus::from_ssts(self.regs().ssts.get())
+    }
+
+    /// Sets how the link of the port saves power, see PowerPolicy. The port starts out with PowerPolicy::ACTIVE.
+    /// 
+    /// Fails with Unsupported, if the HBA does not support the state or aggressive link power management,
+    /// or for DevSleep, if the device does not support it either.
+    pub fn set_power_policy(&mut self, policy: PowerPolicy) -> Result<(), DiskError>
+    {
+        let cap = &self.cap;
+        let cap2 = &self.cap2;
+        let devsleep = self.identify.as_ref().map_or(false, |it| it.devsleep);
+        let supported = match policy.deepest
+        {
+            LinkPowerState::Active => true,
+            LinkPowerState::Partial => cap.get_psc(),
+            LinkPowerState::Slumber => cap.get_ssc(),
+            LinkPowerState::DevSleep => cap2.get_sds() && devsleep
+        };
+        let aggressive = policy.aggressive && policy.deepest != LinkPowerState::Active;
+        if !supported || (aggressive && !cap.get_salp())
+        {
+            return Err(DiskError::Unsupported);
+        }
+        let (salp, apst, sds, sadm) = (cap.get_salp(), cap2.get_apst(), cap2.get_sds(), cap2.get_sadm());
+
+        // PxSCTL.IPM: Bit 0 forbids Partial, Bit 1 Slumber, Bit 2 DevSleep
+        let ipm = match policy.deepest
+        {
+            LinkPowerState::Active => 0x7,
+            LinkPowerState::Partial => 0x6,
+            LinkPowerState::Slumber => 0x4,
+            LinkPowerState::DevSleep => 0x0
+        };
+
+        let port = self.regs();
+        // PxDEVSLP may only be changed with PxCMD.ST cleared
+        port.cmd.set_st(false);
+        if !Self::wait_ms(500, || !port.cmd.get_cr())
+        {
+            port.cmd.set_st(true);
+            return Err(DiskError::PortNotReady);
+        }
+
+        port.sctl.set(port.sctl.get() & !0x0f_00 | ipm << 8);
+        // Unsafe Note: each bit is only written, if the HBA supports it
+        if salp
+        {
+            unsafe {
+                port.cmd.set_alpe(aggressive);
+                // ASP: aggressively enter Slumber instead of Partial
+                port.cmd.set_asp(policy.deepest != LinkPowerState::Partial);
+            }
+        }
+        if apst
+        {
+            // Let the HBA move on from Partial to Slumber on its own
+            let deep = policy.deepest == LinkPowerState::Slumber || policy.deepest == LinkPowerState::DevSleep;
+            unsafe { port.cmd.set_apste(deep) };
+        }
+        if sds
+        {
+            // PxDEVSLP.ADSE: Aggressive Device Sleep Enable
+            let adse = aggressive && sadm && policy.deepest == LinkPowerState::DevSleep;
+            port.devslp.set(port.devslp.get() & !1 | if adse { 1 } else { 0 });
+        }
+
+        port.cmd.set_st(true);
+
+        // Wake the link up, in case it sleeps right now
+        if policy.deepest == LinkPowerState::Active
+        {
+            self.enter_link_state(LinkPowerState::Active)?;
+        }
+        Ok(())
+    }
+
+    /// Asks the HBA to move the link into `state` right now (PxCMD.ICC), as far as the power policy allows it.
+    /// The next command wakes the link up again.
+    pub fn enter_link_state(&mut self, state: LinkPowerState) -> Result<(), DiskError>
+    {
+        let icc = match state
+        {
+            LinkPowerState::Active => IccState::ACTIVE,
+            LinkPowerState::Partial => IccState::PARTIAL,
+            LinkPowerState::Slumber => IccState::SLUMBER,
+            LinkPowerState::DevSleep => IccState::DEVSLEEP
+        };
+
+        let port = self.regs();
+        if !port.cmd.get_st()
+        {
+            return Err(DiskError::PortNotReady);
+        }
+        // ICC may only be written, once the previous request was taken (ICC back to Idle)
+        if !Self::wait_ms(10, || port.cmd.get_icc() == IccState::IDLE)
+        {
+            return Err(DiskError::Timeout);
+        }
+        port.cmd.set_icc(icc);
+        Ok(())
+    }
+
+    /// Limits the speed the link negotiates to `speed` (None: up to what the HBA supports) through PxSCTL.SPD.
+    /// The link renegotiates with a COMRESET, so nothing may be in flight.
+    pub fn set_max_link_speed(&mut self, speed: Option<InterfaceSpeed>) -> Result<(), DiskError>
+    {
+        let max = self.cap.get_iss().get_raw();
+        let spd = match speed
+        {
+            None => 0,
+            Some(it) if it.get_raw() >= 1 && it.get_raw() <= max => it.get_raw(),
+            Some(_) => return Err(DiskError::Unsupported)
+        };
+
+        let port = self.regs();
+        let old_sctl = port.sctl.get();
+        port.sctl.set(old_sctl & !0xf0 | (spd as u32) << 4);
+
+        // COMRESET requires a stopped command list
+        port.cmd.set_st(false);
+        let up = Self::wait_ms(500, || !port.cmd.get_cr()) && Self::comreset_impl(port);
+        port.serr.set(0x07_ff_0f_03);
+        port.is.clear_all();
+        // Neither the errors nor the interrupts caused by the reset matter
+        take_port_error(self.hba_idx, self.hba_port_idx);
+        if !up
+        {
+            // Back to the old limit, and the port has to run again, with or without the device
+            port.sctl.set(old_sctl);
+            self.recover();
+            return Err(DiskError::PortNotReady);
+        }
+        port.cmd.set_st(true);
+
+        debug!("Port {}: Link {}", self.hba_port_idx, self.link_status());
+        Ok(())
+    }
+
+    /// Whether the device supports trim, according to IDENTIFY.
+    pub fn supports_trim(&self) -> bool
+    {
+        self.identify.as_ref().map_or(false, |it| it.trim)
+    }
+
+    /// Tells the device, the sectors in `ranges` (first LBA, sector count) no longer hold any data,
+    /// through DATA SET MANAGEMENT with the TRIM bit.
+    /// 
+    /// Every range is checked, before anything is trimmed. Ranges longer than 65535 sectors are split up,
+    /// the entries are sent in as few commands as the device allows.
+    pub fn trim(&mut self, ranges: &[(u64, u32)]) -> Result<(), DiskError>
+    {
+        if !self.supports_trim()
+        {
+            return Err(DiskError::Unsupported);
+        }
+        let sector_size = self.logical_sector_size() as u64;
+        for &(lba, count) in ranges
+        {
+            self.check_request(lba, count as u64 * sector_size)?;
+        }
+
+        // Limited to a page worth of payload, even if the device takes more
+        let max_blocks = self.identify.as_ref().map_or(1, |it| it.dsm_max_blocks).clamp(1, 8) as usize;
+        let max_entries = max_blocks * Self::DSM_ENTRIES_PER_BLOCK;
+
+        // Entry: LBA in bits 0..=47, sector count in bits 48..=63
+        let mut entries = ranges.iter()
+            .flat_map(|&(lba, count)| {
+                (0..count).step_by(Self::DSM_MAX_RANGE_LEN as usize).map(move |offset| {
+                    let len = (count - offset).min(Self::DSM_MAX_RANGE_LEN);
+                    (lba + offset as u64) | ((len as u64) << 48)
+                })
+            })
+            .peekable();
+
+        let mut payload: Vec<u64> = Vec::with_capacity(max_entries);
+        while entries.peek().is_some()
+        {
+            payload.clear();
+            payload.extend(entries.by_ref().take(max_entries));
+            // The rest of the last block must be zeroed, a sector count of 0 marks an unused entry
+            let blocks = (payload.len() + Self::DSM_ENTRIES_PER_BLOCK - 1) / Self::DSM_ENTRIES_PER_BLOCK;
+            payload.resize(blocks * Self::DSM_ENTRIES_PER_BLOCK, 0);
+
+            let fis = RegH2D::default();
+            fis.pmport_cc.set(0x80);
+            fis.command.set(Self::ATA_CMD_DATA_SET_MANAGEMENT);
+            fis.featurel.set(Self::DSM_TRIM);
+            fis.countl.set(blocks as u8);
+            fis.counth.set((blocks >> 8) as u8);
+            fis.device.set(0x40);
+
+            // The payload is sent to the device, like a write
+            unsafe { self.handle_fis(true, payload.as_ptr() as u64, (payload.len() * 8) as u64, &fis)? };
+        }
+        Ok(())
+    }
+
+    /// READ VERIFY SECTOR(S) (EXT): the device reads the sectors, without transferring anything.
+    /// A sector it cannot read fails with a MediaError, so this checks the medium without touching memory (or the data).
+    /// 
+    /// `sector_count` is in logical sectors, split into as many commands as needed.
+    pub fn verify(&mut self, first_sector: u64, sector_count: u64) -> Result<(), DiskError>
+    {
+        if self.atapi
+        {
+            return Err(DiskError::Unsupported);
+        }
+        let sector_size = self.logical_sector_size() as u64;
+        let sector_count = match sector_count.checked_mul(sector_size)
+        {
+            Some(it) => self.check_request(first_sector, it)?,
+            None => return Err(DiskError::OutOfRange { lba: first_sector })
+        };
+
+        // Without 48 bit addressing: 28 bit LBA and 8 bit count, 0 meaning 256
+        let (command, max) = if self.lba == 48
+        {
+            (Self::ATA_CMD_READ_VERIFY_EXT, Self::MAX_SECTORS_PER_COMMAND)
+        }
+        else
+        {
+            (Self::ATA_CMD_READ_VERIFY, 256)
+        };
+
+        let mut lba = first_sector;
+        let end = first_sector + sector_count;
+        while lba < end
+        {
+            let count = (end - lba).min(max);
+
+            let fis = RegH2D::default();
+            fis.pmport_cc.set(0x80);
+            fis.command.set(command);
+            fis.lba0.set(lba as u8);
+            fis.lba1.set((lba >> 8) as u8);
+            fis.lba2.set((lba >> 16) as u8);
+            if self.lba == 48
+            {
+                fis.lba3.set((lba >> 24) as u8);
+                fis.lba4.set((lba >> 32) as u8);
+                fis.lba5.set((lba >> 40) as u8);
+                fis.device.set(0x40);
+            }
+            else
+            {
+                // LBA 27:24 go into the device register
+                fis.device.set(0x40 | ((lba >> 24) as u8 & 0x0f));
+            }
+            // The maximum is truncated to 0, which is exactly how it is encoded
+            fis.countl.set(count as u8);
+            if self.lba == 48
+            {
+                fis.counth.set((count >> 8) as u8);
+            }
+
+            unsafe { self.handle_fis(false, 0, 0, &fis)? };
+            lba += count;
+        }
+        Ok(())
+    }
+
+    /// Writes everything in the volatile write cache of the device to the medium (FLUSH CACHE (EXT)).
+    /// 
+    /// A write only survives a power loss, once a flush after it succeeded.
+    pub fn flush(&mut self) -> Result<(), DiskError>
+    {
+        // Nothing written, nothing to flush
+        if self.atapi
+        {
+            return Ok(());
+        }
+
+        let fis = RegH2D::default();
+        fis.pmport_cc.set(0x80);
+        fis.command.set(if self.lba == 48 { Self::ATA_CMD_FLUSH_CACHE_EXT } else { Self::ATA_CMD_FLUSH_CACHE });
+        fis.device.set(0x40);
+
+        unsafe { self.handle_fis(false, 0, 0, &fis)? };
+        Ok(())
+    }
+
+    /// A power management command without any data: STANDBY IMMEDIATE, IDLE IMMEDIATE or STANDBY
+    fn power_command(&mut self, command: u8, count: u8) -> Result<(), DiskError>
+    {
+        let fis = RegH2D::default();
+        fis.pmport_cc.set(0x80);
+        fis.command.set(command);
+        fis.countl.set(count);
+        fis.device.set(0x40);
+
+        unsafe { self.handle_fis(false, 0, 0, &fis)? };
+        Ok(())
+    }
+
+    /// STANDBY IMMEDIATE: the device spins down (and parks its heads) right away.
+    /// 
+    /// The next command accessing the medium spins it up again, which takes a few seconds.
+    /// Flush before, STANDBY IMMEDIATE does not promise to write back the cache.
+    pub fn standby_immediate(&mut self) -> Result<(), DiskError>
+    {
+        self.power_command(Self::ATA_CMD_STANDBY_IMMEDIATE, 0)
+    }
+
+    /// IDLE IMMEDIATE: the device enters the Idle mode right away, it keeps spinning but may power down some of its electronics.
+    pub fn idle_immediate(&mut self) -> Result<(), DiskError>
+    {
+        self.power_command(Self::ATA_CMD_IDLE_IMMEDIATE, 0)
+    }
+
+    /// STANDBY with a timer: the device spins down now and, once it was woken up, again after `seconds` without a command.
+    /// 0 disables the timer.
+    /// 
+    /// The device only knows a few steps (see `standby_timer_value`), so the timer is rounded up to the next one.
+    /// More than 5.5 hours are an InvalidArgument.
+    pub fn set_standby_timer(&mut self, seconds: u32) -> Result<(), DiskError>
+    {
+        let value = Self::standby_timer_value(seconds).ok_or(DiskError::InvalidArgument)?;
+        self.power_command(Self::ATA_CMD_STANDBY, value)
+    }
+
+    /// The Count of STANDBY (and IDLE) for a timer of at least `seconds`:
+    /// 0 is off, 1 to 240 are multiples of 5 seconds (up to 20 minutes), 241 to 251 multiples of 30 minutes (up to 5.5 hours).
+    /// None for anything longer.
+    pub fn standby_timer_value(seconds: u32) -> Option<u8>
+    {
+        match seconds
+        {
+            0 => Some(0),
+            1..=1200 => Some(((seconds + 4) / 5) as u8),
+            1201..=19800 => Some((240 + (seconds + 1799) / 1800) as u8),
+            _ => None
+        }
+    }
+
+    /// Enables or disables the volatile write cache of the device through SET FEATURES.
+    /// 
+    /// Disabling it flushes the cache as well.
+    pub fn set_write_cache(&mut self, enabled: bool) -> Result<(), DiskError>
+    {
+        if !self.identify.as_ref().map_or(false, |it| it.write_cache_supported)
+        {
+            return Err(DiskError::Unsupported);
+        }
+
+        let fis = RegH2D::default();
+        fis.pmport_cc.set(0x80);
+        fis.command.set(Self::ATA_CMD_SET_FEATURES);
+        fis.featurel.set(if enabled { Self::SET_FEATURES_ENABLE_WRITE_CACHE } else { Self::SET_FEATURES_DISABLE_WRITE_CACHE });
+        fis.device.set(0x40);
+
+        unsafe { self.handle_fis(false, 0, 0, &fis)? };
+        if let Some(ref mut it) = self.identify
+        {
+            it.write_cache_enabled = enabled;
+        }
+        Ok(())
+    }
+
+    /// Every SMART command carries its subcommand in the feature register and 4Fh/C2h in LBA mid/high
+    fn smart_fis(feature: u8) -> RegH2D
+    {
+        let fis = RegH2D::default();
+        fis.pmport_cc.set(0x80);
+        fis.command.set(Self::ATA_CMD_SMART);
+        fis.featurel.set(feature);
+        fis.lba1.set(0x4f);
+        fis.lba2.set(0xc2);
+        fis.device.set(0x40);
+        fis
+    }
+
+    /// SMART commands are only accepted, if the feature set is supported and enabled.
+    pub fn supports_smart(&self) -> bool
+    {
+        self.identify.as_ref().map_or(false, |it| it.smart_supported && it.smart_enabled)
+    }
+
+    /// SMART RETURN STATUS: true, if the device says, one of its thresholds is exceeded (it predicts its failure).
+    pub fn smart_threshold_exceeded(&mut self) -> Result<bool, DiskError>
+    {
+        if !self.supports_smart()
+        {
+            return Err(DiskError::Unsupported);
+        }
+
+        let fis = Self::smart_fis(Self::SMART_RETURN_STATUS);
+        unsafe { self.handle_fis(false, 0, 0, &fis)? };
+
+        // The answer is in LBA mid/high of the D2H Register FIS
+        let rfis = &self.fb.rfis;
+        match (rfis.lba1.get(), rfis.lba2.get())
+        {
+            (0x4f, 0xc2) => Ok(false),
+            (0xf4, 0x2c) => Ok(true),
+            _ => Err(DiskError::DeviceFault { status: rfis.status.get(), error: rfis.error.get() })
+        }
+    }
+
+    /// SMART READ DATA and SMART READ THRESHOLDS, parsed into the attribute table.
+    pub fn smart_read_data(&mut self) -> Result<SmartData, DiskError>
+    {
+        if !self.supports_smart()
+        {
+            return Err(DiskError::Unsupported);
+        }
+
+        let mut data = [0u16; 256];
+        let mut thresholds = [0u16; 256];
+        let fis = Self::smart_fis(Self::SMART_READ_DATA);
+        unsafe { self.handle_fis(false, data.as_mut_ptr() as u64, 512, &fis)? };
+        let fis = Self::smart_fis(Self::SMART_READ_THRESHOLDS);
+        unsafe { self.handle_fis(false, thresholds.as_mut_ptr() as u64, 512, &fis)? };
+
+        Ok(SmartData::parse(&data, &thresholds))
+    }
+
+    /// Reads one of the SMART logs (e.g. 03h Extended Comprehensive SMART Error log, 07h Extended SMART Self-test log)
+    /// through READ LOG EXT. See read_log_ext for `page` and `buffer`.
+    pub fn read_smart_log(&mut self, log_address: u8, page: u16, buffer: &mut [u16]) -> Result<(), DiskError>
+    {
+        if !self.supports_smart()
+        {
+            return Err(DiskError::Unsupported);
+        }
+        self.read_log_ext(log_address, page, buffer)
+    }
+
+    /// Logs a warning, if the device predicts its own failure. Quiet, if all is well or SMART is not supported.
+    pub fn report_smart_health(&mut self)
+    {
+        if !self.supports_smart()
+        {
+            return;
+        }
+
+        match self.smart_threshold_exceeded()
+        {
+            Ok(false) => (),
+            Ok(true) =>
+            {
+                let model = self.identify.as_ref().map_or("", |it| it.model());
+                warn!("Port {}: SMART reports the disk {} as failing, back up its data!", self.hba_port_idx, model);
+            }
+            Err(err) => debug!("Port {}: SMART RETURN STATUS failed: {}", self.hba_port_idx, err)
+        }
+
+        // The overall status only covers the pre-failure attributes, so look at each of them
+        match self.smart_read_data()
+        {
+            Ok(data) => for it in data.exceeded()
+            {
+                warn!(
+                    "Port {}: SMART attribute {} exceeded its threshold (current {}, worst {}, threshold {}, raw {})",
+                    self.hba_port_idx,
+                    it.id,
+                    it.current,
+                    it.worst,
+                    it.threshold,
+                    it.raw);
+            },
+            Err(err) => debug!("Port {}: SMART READ DATA failed: {}", self.hba_port_idx, err)
+        }
+    }
+
+    /// ATA pass-through: sends the taskfile `fis` as is (apart from the port multiplier port, which is ours to set),
+    /// transferring `buffer_len` bytes from or to `buffer` as `direction` says.
+    /// 
+    /// Refused with InvalidArgument: a control FIS, queued commands, PACKET, DEVICE RESET, a buffer not matching the direction
+    /// or not made of 512 byte blocks, more than PASSTHROUGH_MAX_LEN bytes, a Count not matching the buffer
+    /// (for the commands passthrough::transfer_blocks knows). ATAPI devices are Unsupported.
+    /// Commands, which may write the media or change the device (see passthrough::is_privileged_command), fail with PermissionDenied
+    /// unless `privileged` is set.
+    /// 
+    /// The device failing the command is reported as the DiskError of every other command, DeviceFault carries Status and Error.
+    /// Interface errors are retried like for every command, so the command may run more than once.
+    /// 
+    /// Unsafe Note: As with read_raw, the buffer must be valid for buffer_len bytes and writable for FromDevice.
+    pub unsafe fn ata_passthrough(
+        &mut self,
+        fis: &RegH2D,
+        direction: DataDirection,
+        buffer: *mut u8,
+        buffer_len: usize,
+        privileged: bool)
+        -> Result<TaskfileResult, DiskError>
+    {
+        if self.atapi
+        {
+            return Err(DiskError::Unsupported);
+        }
+        // Bit 7 (C): Command, the rest is up to prepare_command
+        if fis.pmport_cc.get() & 0x80 == 0 || passthrough::is_unsupported_command(fis.command.get())
+        {
+            return Err(DiskError::InvalidArgument);
+        }
+        let has_data = direction != DataDirection::None;
+        if has_data != (buffer_len != 0)
+            || (has_data && buffer.is_null())
+            || buffer_len % PASSTHROUGH_BLOCK_SIZE != 0
+            || buffer_len > PASSTHROUGH_MAX_LEN
+            || passthrough::transfer_blocks(fis).map_or(false, |it| it * PASSTHROUGH_BLOCK_SIZE != buffer_len)
+        {
+            return Err(DiskError::InvalidArgument);
+        }
+        if !privileged && passthrough::is_privileged_command(fis)
+        {
+            debug!("Port {}: Pass-through command {:02x} refused without the privileged flag", self.hba_port_idx, fis.command.get());
+            return Err(DiskError::PermissionDenied);
+        }
+
+        self.handle_fis(direction == DataDirection::ToDevice, buffer as u64, buffer_len as u64, fis)?;
+
+        let tfd = self.regs().tfd.get();
+        let rfis = &self.fb.rfis;
+        Ok(TaskfileResult {
+            status: tfd as u8,
+            error: (tfd >> 8) as u8,
+            device: rfis.device.get(),
+            count: rfis.countl.get() as u16 | (rfis.counth.get() as u16) << 8,
+            lba:
+                rfis.lba0.get() as u64
+                | (rfis.lba1.get() as u64) << 8
+                | (rfis.lba2.get() as u64) << 16
+                | (rfis.lba3.get() as u64) << 24
+                | (rfis.lba4.get() as u64) << 32
+                | (rfis.lba5.get() as u64) << 40
+        })
+    }
+
+    /// Runs all requests as READ/WRITE FPDMA QUEUED (NCQ), keeping up to `self.queue_depth` of them in flight.
+    /// The device may complete them in any order, each request gets its own result.
+    /// 
+    /// Without NCQ support, the requests are run one after the other.
+    /// After an error, every request aborted with it is retried as a non queued command.
+    /// 
+    /// Unsafe Note: As with read_raw, each buffer must be valid for buffer_len bytes and writable, if it is a read.
+    pub unsafe fn read_write_queued(&mut self, requests: &mut [QueuedRequest])
+    {
+        if self.queue_depth == 0
+        {
+            for it in requests.iter_mut()
+            {
+                it.result = Some(self.read_write_raw(it.first_sector, it.buffer as usize, it.buffer_len, it.write));
+            }
+            return;
+        }
+
+        // Request index and command table of each tag in flight. A tag is the command slot.
+        const NOT_IN_FLIGHT: Option<(usize, CommandTable2Ptr)> = None;
+        let mut in_flight = [NOT_IN_FLIGHT; 32];
+        let mut outstanding = 0u32;
+        let mut next = 0usize;
+
+        loop
+        {
+            let port = self.regs();
+
+            // Fill the queue
+            while next < requests.len()
+            {
+                let running = port.ci.get() | port.sact.get() | outstanding;
+                let tag = match (0..self.queue_depth).find(|it| running & (1u32 << it) == 0)
+                {
+                    Some(it) => it,
+                    None => break
+                };
+
+                let request = &mut requests[next];
+                let checked = Self::check_buffer(request.buffer as u64, request.buffer_len as u64)
+                    .and_then(|_| self.check_request(request.first_sector, request.buffer_len as u64));
+                let sector_count = match checked
+                {
+                    Ok(it) => it,
+                    Err(err) =>
+                    {
+                        request.result = Some(Err(err));
+                        next += 1;
+                        continue;
+                    }
+                };
+
+                // Too large for a single command: it is split up after the queue drained.
+                // The same for a buffer the HBA cannot reach, the bounce buffer is up to handle_command.
+                let addressable = dma::is_addressable(request.buffer as usize, request.buffer_len, self.is_64bit_aware);
+                match self.command_len(request.buffer as u64, request.buffer_len as u64)
+                {
+                    Ok(len) if len == request.buffer_len as u64 && addressable => {},
+                    _ =>
+                    {
+                        next += 1;
+                        continue;
+                    }
+                }
+
+                let fis = Self::fpdma_queued_fis(tag, request.first_sector, sector_count as u32, request.write);
+                let cmd_table = match self.prepare_command(tag, request.write, request.buffer as u64, request.buffer_len as u64, &fis, None)
+                {
+                    Ok((_, it)) => it,
+                    Err(err) =>
+                    {
+                        request.result = Some(Err(err));
+                        next += 1;
+                        continue;
+                    }
+                };
+                in_flight[tag as usize] = Some((next, cmd_table));
+                next += 1;
+
+                // PxSACT before PxCI, as required for queued commands
+                let mask = 1u32 << tag;
+                outstanding |= mask;
+                port.sact.set(mask);
+                port.ci.set(mask);
+            }
+
+            if outstanding == 0
+            {
+                break;
+            }
+
+            let completion = self.wait_for_any(port, outstanding);
+
+            let done = outstanding & !(port.ci.get() | port.sact.get());
+            for tag in 0..32usize
+            {
+                if done & (1u32 << tag) != 0
+                {
+                    // Dropping the command table, the command does not need it anymore
+                    if let Some((idx, _cmd_table)) = in_flight[tag].take()
+                    {
+                        // The HBA does not have to update PRDBC for queued commands, so report the requested length.
+                        requests[idx].result = Some(Ok(requests[idx].buffer_len));
+                    }
+                }
+            }
+            outstanding &= !done;
+
+            if let Completion::Done = completion
+            {
+                continue;
+            }
+
+            // An NCQ error aborts every command in flight. So does the port recovery.
+            debug!("Port {}: Queued command failed ({:?})", self.hba_port_idx, completion);
+            let recovered = self.recover();
+            // Reading the NCQ Command Error log tells us the failed command and is required to get the device out of its error state.
+            let failed = match completion
+            {
+                Completion::Error(_) if recovered => self.read_ncq_error_log().unwrap_or(None),
+                _ => None
+            };
+
+            for tag in 0..32usize
+            {
+                if let Some((idx, _cmd_table)) = in_flight[tag].take()
+                {
+                    let QueuedRequest { first_sector, buffer, buffer_len, write, .. } = requests[idx];
+                    requests[idx].result = Some(match failed
+                    {
+                        _ if !recovered => Err(DiskError::PortNotReady),
+                        Some((failed_tag, err)) if failed_tag as usize == tag => Err(err),
+                        _ => self.read_write_raw(first_sector, buffer as usize, buffer_len, write)
+                    });
+                }
+            }
+            outstanding = 0;
+
+            if !recovered
+            {
+                for it in requests.iter_mut().filter(|it| it.result.is_none())
+                {
+                    it.result = Some(Err(DiskError::PortNotReady));
+                }
+                break;
+            }
+        }
+
+        // The requests left out for being too large
+        for it in requests.iter_mut().filter(|it| it.result.is_none())
+        {
+            it.result = Some(self.read_write_raw(it.first_sector, it.buffer as usize, it.buffer_len, it.write));
+        }
+    }
+
+    /// READ/WRITE FPDMA QUEUED: the sector count moves into the feature register and the tag into count (bits 3..=7)
+    /// As with READ/WRITE DMA EXT, a sector_count of 65536 is encoded as 0.
+    fn fpdma_queued_fis(tag: u8, first_sector: u64, sector_count: u32, write: bool) -> RegH2D
+    {
+        let fis = RegH2D::default();
+        fis.pmport_cc.set(0x80);
+        if write
+        {
+            fis.command.set(Self::ATA_CMD_WRITE_FPDMA_QUEUED);
+        }
+        else
+        {
+            fis.command.set(Self::ATA_CMD_READ_FPDMA_QUEUED);
+        }
+
+        fis.lba0.set(first_sector as u8);
+        fis.lba1.set((first_sector >> 8) as u8);
+        fis.lba2.set((first_sector >> 16) as u8);
+        fis.lba3.set((first_sector >> 24) as u8);
+        fis.lba4.set((first_sector >> 32) as u8);
+        fis.lba5.set((first_sector >> 40) as u8);
+
+        fis.featurel.set(sector_count as u8);
+        fis.featureh.set((sector_count >> 8) as u8);
+        fis.countl.set(tag << 3);
+
+        fis.device.set(0x40); // LBA Mode, FUA not set
+
+        fis
+    }
+
+    /// Stores what the device returns on a ATA_CMD_IDENTIFY (ATA_CMD_IDENTIFY_PACKET for ATAPI) in self.identify,
+    /// and sets self.lba, self.size and self.queue_depth accordingly.
+    /// The size of an ATAPI device is up to load_medium.
+    /// 
+    /// Failing that, sets self.identify to None and the other values to 0.
+    pub fn identify(&mut self) -> Result<(), DiskError>
+    {
+        let mut buffer = [0u16; 256];
+        let buffer_len: usize = core::mem::size_of_val(&buffer);
+        // I want bytes, not T (= u16)
+        // Should I either hardcode 512 or use core::mem::size_of::<[u16; 256]>(), or keep what I have?
+
+        let fis = RegH2D::default();
+        fis.command.set(if self.atapi { Self::ATA_CMD_IDENTIFY_PACKET } else { Self::ATA_CMD_IDENTIFY });
+        fis.pmport_cc.set(0x80);
+        fis.countl.set(1);
+
+        self.identify = None;
+        self.lba = 0;
+        self.size = 0;
+        self.queue_depth = 0;
+
+        let (command_slot, _prdt_count) = unsafe { self.handle_fis(false, &mut buffer as *mut _ as u64, buffer_len as u64, &fis)? };
+
+        // I was right, CI has to be set, after PxCMD.ST is set to 1.
+        // Why is my laptop having problems with my old code then?
+        // Do I need to allocate and initialize PRTDLs, even when the command is not issued?
+        // Self::start_impl(self.regs());
+        if self.clb[command_slot as usize].get_prdbc() != 512
+        {
+            // [Identify] Expected to receive 512 bytes
+            let tfd = self.regs().tfd.get();
+            return Err(DiskError::DeviceFault { status: tfd as u8, error: (tfd >> 8) as u8 });
+        }
+
+        let mut data = IdentifyData::parse(&buffer);
+        if self.atapi
+        {
+            // Words 60..=61 and 100..=103 are reserved for packet devices
+            data.sectors = 0;
+        }
+        self.lba = if data.lba48 { 48 } else { 28 };
+        self.size = data.capacity();
+
+        // Each tag is a command slot, so the command slots limit the depth as well.
+        if self.cap.get_sncq() && !self.atapi
+        {
+            self.queue_depth = data.queue_depth.min(self.cmd_slot_count);
+        }
+
+        debug!("Model: {}, Serial: {}, Firmware: {}", data.model(), data.serial(), data.firmware());
+        debug!("LBA Bits: {}, Size: {} Bytes ({} GiB)", self.lba, self.size, self.size / 1073741824u64);
+        debug!(
+            "Sector Size: {}/{} (logical/physical), NCQ Queue Depth: {}, TRIM: {}, Write Cache: {}, SMART: {}",
+            data.logical_sector_size,
+            data.physical_sector_size,
+            self.queue_depth,
+            data.trim,
+            data.write_cache_supported,
+            data.smart_supported);
+
+        self.identify = Some(data);
+        Ok(())
+    }
+
+    /// Reads `buffer.len() / 256` pages of the log at `log_address` through READ LOG EXT, starting at `page`.
+    pub fn read_log_ext(&mut self, log_address: u8, page: u16, buffer: &mut [u16]) -> Result<(), DiskError>
+    {
+        let pages = buffer.len() / 256;
+        if pages == 0 || pages > 0xff_ff || buffer.len() % 256 != 0
+        {
+            return Err(DiskError::MisalignedBuffer);
+        }
+
+        let fis = RegH2D::default();
+        fis.command.set(Self::ATA_CMD_READ_LOG_EXT);
+        fis.pmport_cc.set(0x80);
+        fis.lba0.set(log_address);
+        fis.lba1.set(page as u8);
+        fis.lba4.set((page >> 8) as u8);
+        fis.countl.set(pages as u8);
+        fis.counth.set((pages >> 8) as u8);
+
+        unsafe { self.handle_fis(false, buffer.as_mut_ptr() as u64, (buffer.len() * 2) as u64, &fis)? };
+        Ok(())
+    }
+
+    /// Reads the NCQ Command Error log (10h). Returns the tag of the failed queued command and its error.
+    /// 
+    /// After an NCQ error, the device refuses every command, until this log was read.
+    fn read_ncq_error_log(&mut self) -> Result<Option<(u8, DiskError)>, DiskError>
+    {
+        const NCQ_COMMAND_ERROR_LOG: u8 = 0x10;
+        let mut buffer = [0u16; 256];
+        self.read_log_ext(NCQ_COMMAND_ERROR_LOG, 0, &mut buffer)?;
+
+        // Byte 0: NQ (Bit 7), Tag (Bits 0..=4), Byte 2: Status, Byte 3: Error, Bytes 4..=6 & 8..=10: LBA
+        let bytes = |i: usize| (buffer[i / 2] >> ((i % 2) * 8)) as u8;
+        if bytes(0) & 0x80 != 0
+        {
+            // Not caused by a queued command
+            return Ok(None);
+        }
+        let lba =
+            bytes(4) as u64
+            | (bytes(5) as u64) << 8
+            | (bytes(6) as u64) << 16
+            | (bytes(8) as u64) << 24
+            | (bytes(9) as u64) << 32
+            | (bytes(10) as u64) << 40;
+        Ok(Some((bytes(0) & 0x1f, Self::device_error(bytes(2), bytes(3), lba))))
+    }
+
+    /// Unsafe Note: buffer must be writable, if data from the device is read.
+    /// The buffer_len must be the size of the buffer.
+    /// The buffer size must be divisible by 2, the buffer aligned by 2.
+    /// A buffer_len of 0 issues a command without data (no PRDT entries), the buffer is ignored then.
+    /// 
+    /// Runs the command to completion. On an error or timeout the port is recovered,
+    /// interface errors and timeouts are retried up to MAX_RETRIES times, errors reported by the device are not.
+    /// 
+    /// Returns: Slot, PRDT Entry Count
+    unsafe fn handle_fis(
+        &mut self,
+        write: bool,
+        buffer: u64,
+        buffer_len: u64,
+        fis: &RegH2D)
+        -> Result<(u8, u32), DiskError>
+    {
+        self.handle_command(write, buffer, buffer_len, fis, None)
+    }
+
+    /// handle_fis, with the SCSI command `acmd` for a PACKET command (ATAPI)
+    unsafe fn handle_command(
+        &mut self,
+        write: bool,
+        buffer: u64,
+        buffer_len: u64,
+        fis: &RegH2D,
+        acmd: Option<&[u8; 16]>)
+        -> Result<(u8, u32), DiskError>
+    {
+        // The physical address has the same offset into the page, so it is aligned as well
+        if buffer_len != 0
+        {
+            Self::check_buffer(buffer, buffer_len)?;
+        }
+
+        // The HBA gets a copy below 4 GiB of a buffer it cannot reach.
+        // Copied in for reads as well, so a short read leaves the rest of the buffer as it was.
+        let bounce = if buffer_len != 0 && !dma::is_addressable(buffer as usize, buffer_len as usize, self.is_64bit_aware)
+        {
+            Some(BounceBuffer::new(buffer as *const u8, buffer_len as usize))
+        }
+        else
+        {
+            None
+        };
+        let data = bounce.as_ref().map_or(buffer, |it| it.as_u64());
+
+        let mut tries = 0u32;
+        loop
+        {
+            let port = self.regs();
+            // 3: Present & Communicating
+            if port.ssts.get() & 0xf != 3
+            {
+                return Err(DiskError::PortNotReady);
+            }
+            let slot_num = Self::find_empty_slot(port, self.cmd_slot_count as usize)
+                .ok_or(DiskError::NoFreeSlot)?;
+            let (total_prdt_count, _cmd_table) = self.prepare_command(slot_num, write, data, buffer_len, fis, acmd)?;
+
+            // A device still busy with something else is hung, as we are the only one issuing commands.
+            let timeout = self.command_timeout;
+            let completion = if Self::wait_ms(timeout, || port.tfd.get() & 0x88 == 0)
+            {
+                // Errors of earlier commands are handled (or irrelevant) by now
+                take_port_error(self.hba_idx, self.hba_port_idx);
+                port.is.clear_pss();
+                port.is.clear_dhrs();
+                port.ci.set(1u32 << slot_num);
+                self.wait_for_any(port, 1u32 << slot_num)
+            }
+            else
+            {
+                Completion::Timeout
+            };
+
+            let (err, retry) = match completion
+            {
+                Completion::Done =>
+                {
+                    if let (Some(bounce), false) = (&bounce, write)
+                    {
+                        bounce.copy_to(buffer as *mut u8);
+                    }
+                    return Ok((slot_num, total_prdt_count));
+                }
+                Completion::Error(status) => self.classify_error(port, status),
+                Completion::Timeout => (DiskError::Timeout, true)
+            };
+            debug!("Port {}: Command {:02x} failed: {} ({:?})", self.hba_port_idx, fis.command.get(), err, completion);
+
+            if !self.recover()
+            {
+                return Err(DiskError::PortNotReady);
+            }
+            if !retry || tries >= Self::MAX_RETRIES
+            {
+                return Err(err);
+            }
+            tries += 1;
+        }
+    }
+
+    /// Everything about a buffer, the HBA would otherwise choke on.
+    /// Whether it fits the sector size is up to check_request.
+    fn check_buffer(buffer: u64, buffer_len: u64) -> Result<(), DiskError>
+    {
+        if buffer == 0
+            || buffer_len == 0
+            || buffer & 1 != 0
+            || buffer_len & 1 != 0
+        {
+            Err(DiskError::MisalignedBuffer)
+        }
+        else
+        {
+            Ok(())
+        }
+    }
+
+    /// Is the buffer made of whole logical sectors, and does the device have all the sectors it covers?
+    /// 
+    /// Returns: Sector Count
+    fn check_request(&self, first_sector: u64, buffer_len: u64) -> Result<u64, DiskError>
+    {
+        // Identify failed (or was never run)
+        if self.size == 0
+        {
+            return Err(DiskError::PortNotReady);
+        }
+
+        let sector_size = self.logical_sector_size() as u64;
+        if buffer_len % sector_size != 0
+        {
+            return Err(DiskError::MisalignedBuffer);
+        }
+        let sector_count = buffer_len / sector_size;
+
+        match first_sector.checked_add(sector_count)
+        {
+            Some(end) if end <= self.size / sector_size => Ok(sector_count),
+            _ => Err(DiskError::OutOfRange { lba: first_sector })
+        }
+    }
+
+    /// Size of a logical sector in bytes. Every LBA and sector count is in this unit, as are the buffers for reads and writes.
+    /// 
+    /// 512 until identify succeeded.
+    pub fn logical_sector_size(&self) -> u32
+    {
+        self.identify.as_ref().map_or(512, |it| it.logical_sector_size)
+    }
 
-            // From here to the end of the containing if let Some... Taken from redoxOS-rs
-            let sectors =
-                buffer[100] as u64
-                | ((buffer[101] as u64) << 16)
-                | ((buffer[102] as u64) << 32)
-                | ((buffer[103] as u64) << 48);
+    /// Size of a physical sector in bytes. Writes aligned to it spare the device a read-modify-write (on 512e drives).
+    pub fn physical_sector_size(&self) -> u32
+    {
+        self.identify.as_ref().map_or(512, |it| it.physical_sector_size)
+    }
 
-            if sectors == 0
-            {
-                // Could this be tested for with HBA.CAP.S64A?
-                // Instead of checking if sectors above is 0?
-                let sectors = buffer[60] as u64 | ((buffer[61] as u64) << 16);
-                self.lba = 28;
-                self.size = sectors * 512;
-            }
-            else
-            {
-                self.lba = 48;
-                self.size = sectors * 512;
-            }
-            debug!("LBA Bits: {}, Size: {} Bytes ({} GiB)", self.lba, self.size, self.size / 1073741824u64);
-            // TODO: Remove (for laptop reading)
-            busy_sleep(5000);
+    /// READ(10) only has a 16 bit transfer length, without 0 meaning 65536
+    fn max_sectors_per_command(&self) -> u64
+    {
+        if self.atapi { 0xff_ff } else { Self::MAX_SECTORS_PER_COMMAND }
+    }
+
+    /// How many bytes from the start of the buffer fit into a single command:
+    /// at most 65536 sectors and as much as the PRDT entries of one command table cover, in whole logical sectors.
+    /// A buffer the HBA cannot reach goes through a bounce buffer, those are kept to a single PRDT entry (4 MiB).
+    fn command_len(&self, buffer: u64, buffer_len: u64) -> Result<u64, DiskError>
+    {
+        let sector_size = self.logical_sector_size() as u64;
+        let covered: u64 = PhysicalRuns::new(buffer as usize, buffer_len as usize)
+            .take(Self::MAX_PRDT_PER_COMMAND)
+            .map(|(_, len)| len as u64)
+            .sum();
+        let mut len = covered.min(self.max_sectors_per_command() * sector_size);
+        if !dma::is_addressable(buffer as usize, len as usize, self.is_64bit_aware)
+        {
+            len = len.min(MAX_PRD_BYTES as u64);
+        }
+        match len - len % sector_size
+        {
+            // Not even a single sector fits
+            0 => Err(DiskError::MisalignedBuffer),
+            it => Ok(it)
         }
     }
 
-    /// Unsafe Note: buffer must be writable, if data from the device is read.
-    /// The buffer_len must be the size of the buffer.
-    /// The buffer size must be divisible by 512, the buffer aligned by 2.
+    /// Fills the command header of `slot_num` and a new command table (FIS and PRDT) for the buffer.
+    /// Each PRDT entry covers one physically contiguous run of the buffer (up to 4 MiB).
     /// 
-    /// Returns: Slot, PRDT Entry Count
-    unsafe fn handle_fis(
+    /// The returned command table must not be dropped before the command completed.
+    /// 
+    /// Returns: PRDT Entry Count, Command Table
+    unsafe fn prepare_command(
         &mut self,
-        hba: &mut HbaMemory,
+        slot_num: u8,
         write: bool,
         buffer: u64,
         buffer_len: u64,
-        fis: &RegH2D)
-        -> Option<(u8, u32)>
+        fis: &RegH2D,
+        acmd: Option<&[u8; 16]>)
+        -> Result<(u32, CommandTable2Ptr), DiskError>
     {
-        // Thought about assert, as it is probably not intended
-        debug_assert_ne!(buffer, 0);
-        debug_assert_ne!(buffer_len, 0);
-        if buffer == 0 || buffer_len == 0
+        // Translating everything up front fails, before the command slot is touched.
+        // Any address not mapped (buffer smaller than argument reports) panics in here as well.
+        let (runs, total_prdt_count) = Self::collect_runs(PhysicalRuns::new(buffer as usize, buffer_len as usize))?;
+
+        // A command without data still needs its table for the FIS, CommandTable2Ptr just does not allow 0 entries
+        let mut cmd_table = CommandTable2Ptr::new((total_prdt_count as u32).max(1), self.is_64bit_aware);
+        let address = paging::get_physical_address::<BasePageSize>(cmd_table.as_usize()) as u64;
+        let pmp = self.pmp.unwrap_or(0);
+        Self::fill_command(
+            &mut self.clb[slot_num as usize],
+            cmd_table.as_mut(),
+            address,
+            self.is_64bit_aware,
+            pmp,
+            write,
+            fis,
+            acmd,
+            &runs[..total_prdt_count]);
+
+        // 9.3.5: With FIS-based switching, the HBA sends the command to the device in PxFBS.DEV,
+        // which has to be set before PxCI. Only EN, DEC and DEV are writable, DEC is left alone.
+        let port = self.regs();
+        if port.fbs.get() & FBS_EN != 0
         {
-            return None;
+            port.fbs.set(FBS_EN | ((pmp as u32 & 0xf) << FBS_DEV_SHIFT));
         }
 
-        assert_eq!(buffer & 1, 0, "buffer must be 2 byte aligned.");
-        // assert_eq!(buffer_len & 1, 0, "buffer_len must be a multiple of 2");
-        assert_eq!(buffer_len & 0x1_ff, 0, "The buffer length must be a multiple of 512");
-        assert_eq!(buffer_len & 0x00_3f_ff_ff, buffer_len, "buffer_len is restricted to the first 22 bits");
-
-        let buffer_physical = paging::get_physical_address::<BasePageSize>(buffer as usize);
-        assert_eq!(buffer_physical & 1, 0, "buffer_physical must be 2 byte aligned.");
+        Ok((total_prdt_count as u32, cmd_table))
+    }
 
-        let port = &mut hba.ports[self.hba_port_idx];
-        let slot_num = match Self::find_empty_slot(port, self.cmd_slot_count as usize - 1)
+    /// The physically contiguous runs of a buffer, one per PRDT entry.
+    /// Fails with MisalignedBuffer, if there are more than a command table holds.
+    /// 
+    /// Returns: the runs (only the first ones are used), how many there are
+    pub(super) fn collect_runs<I>(iter: I) -> Result<([(u64, u32); Self::MAX_PRDT_PER_COMMAND], usize), DiskError>
+        where I: Iterator<Item = (u64, u32)>
+    {
+        let mut runs = [(0u64, 0u32); Self::MAX_PRDT_PER_COMMAND];
+        let mut count = 0usize;
+        for run in iter
         {
-            None => return None,
-            Some(it) => it,
-        };
-        // Count of PRDT using 8KiB. OsDevWiki does not explain, why they use 8KiB, while the AHCI Docs say up to 4 MiB. Maybe standard version?
-        let full_prdt_count = buffer_len >> 13;
-        let partial_prdt_size = buffer_len & 0x1f_ff;
-        let total_prdt_count = full_prdt_count + if partial_prdt_size != 0 { 1 } else { 0 };
-        assert!(total_prdt_count < CommandTable2Ptr::MAX_PRDT_ENTRIES as u64);
-
-        // Purpose
-        // a) don't have this long paging::get_physical_... line inside the loop, where I initilize the prdt entries
-        // b) Fail fast: if any of these address is not mapped (buffer smaller than argument reports), the function will panic
-        let addresses = {
-            
-            let mut addresses = [0u64; CommandTable2Ptr::MAX_PRDT_ENTRIES as usize];
-            for i in 0..total_prdt_count
+            if count == runs.len()
             {
-                addresses[i as usize] =
-                    // The function should keep the offset (relative to page boundary)
-                    // What about integer overflow?
-                    paging::get_physical_address::<BasePageSize>((buffer + (i * 8192)) as usize) as u64;
+                return Err(DiskError::MisalignedBuffer);
             }
-            addresses
-        };
+            runs[count] = run;
+            count += 1;
+        }
+        Ok((runs, count))
+    }
 
-        debug!("Using slot {} and {} PRDT Entries", slot_num, total_prdt_count);
-        let cmd_header = &mut self.clb[slot_num as usize];
+    /// The part of prepare_command, which does not touch the page tables:
+    /// fills `cmd_header` and the command table `cmd_tbl` (at physical address `ctba`) with the FIS, ACMD and one PRDT entry per run.
+    pub(super) fn fill_command(
+        cmd_header: &mut CommandHeader,
+        cmd_tbl: &mut CommandTable2,
+        ctba: u64,
+        is_64bit_aware: bool,
+        pmp: u8,
+        write: bool,
+        fis: &RegH2D,
+        acmd: Option<&[u8; 16]>,
+        runs: &[(u64, u32)])
+    {
         cmd_header.reset();
+        cmd_header.set_pmp(pmp);
         cmd_header.set_write(write);
-        cmd_header.set_prdtl(total_prdt_count as u16); // Safe thanks to CommanTable2Ptr::MAX_PRDT_ENTRIES
+        cmd_header.set_atapi(acmd.is_some());
+        cmd_header.set_prdtl(runs.len() as u16); // Safe thanks to MAX_PRDT_PER_COMMAND
         cmd_header.set_cfl(
             (core::mem::size_of::<RegH2D>() / core::mem::size_of::<u32>()) as u8);
 
-        let mut cmd_table = CommandTable2Ptr::new(total_prdt_count as u32);
+        fis.copy_into(&mut cmd_tbl.cfis);
+        // Every FIS is built for PM Port 0, the port multiplier port goes into bits 0..=3 of byte 1
+        cmd_tbl.cfis[1].set(cmd_tbl.cfis[1].get() & 0xf0 | pmp);
+        if let Some(acmd) = acmd
         {
-            let cmd_tbl = cmd_table.as_mut();
-            fis.copy_into(&mut cmd_tbl.cfis);
-            for i in 0..full_prdt_count
+            for (dst, src) in cmd_tbl.acmd.iter_mut().zip(acmd.iter())
             {
-                cmd_tbl.prdt[usize::try_from(i).unwrap()].set(
-                    PhysicalRegionDescriptorTable::new(
-                        addresses[i as usize],
-                        false,
-                        8191)); // Yes, it has to be 8192 - 1 (0x20_00 - 1)
-            }
-            if partial_prdt_size != 0
-            {
-                cmd_tbl.prdt[full_prdt_count as usize].set(
-                    PhysicalRegionDescriptorTable::new(
-                        addresses[full_prdt_count as usize],
-                        false,
-                        (partial_prdt_size - 1) as u32
-                    ));
+                dst.set(*src);
             }
         }
+        for (i, (address, len)) in runs.iter().enumerate()
+        {
+            cmd_tbl.prdt[i].set(
+                PhysicalRegionDescriptorTable::new(
+                    *address,
+                    false,
+                    len - 1)); // Yes, it has to be the length - 1
+        }
 
-        let address = paging::get_physical_address::<BasePageSize>(cmd_table.as_usize()) as u64;
-        let addr_lo = address as u32;
-        let addr_hi = (address >> 32) as u32;
+        let addr_lo = ctba as u32;
+        let addr_hi = (ctba >> 32) as u32;
         cmd_header.set_ctba(addr_lo);
-        if hba.ghc.cap.get_s64a()
+        if is_64bit_aware
         {
-            cmd_header.set_ctbau(addr_hi);
+            unsafe { cmd_header.set_ctbau(addr_hi) };
         }
         else
         {
             assert_eq!(addr_hi, 0, "Hardware does not 64 bit, while we have a 64 bit address");
         }
+    }
 
-        debug!("Pre-Submit wait for not busy (no timeout)");
-        while port.tfd.get() & 0x88 != 0
+    /// Returns, once at least one command in `slots` completed (cleared in both PxCI and PxSACT),
+    /// the port reported an error or the command timeout expired.
+    /// 
+    /// The calling task is blocked and woken up by `on_interrupt` (or `on_timer`).
+    /// The idle task (which runs the kernel init, including identify) must not block, it spins instead.
+    fn wait_for_any(&self, port: &PortRegister, slots: u32) -> Completion
+    {
+        let deadline = get_ticks() + self.command_timeout;
+        let check = || {
+
+            if let Some(status) = take_port_error(self.hba_idx, self.hba_port_idx)
+            {
+                Some(Completion::Error(status))
+            }
+            else if (port.ci.get() | port.sact.get()) & slots != slots
+            {
+                Some(Completion::Done)
+            }
+            else if get_ticks() >= deadline
+            {
+                Some(Completion::Timeout)
+            }
+            else
+            {
+                None
+            }
+        };
+
+        // The tests on the host have no scheduler, they spin and the emulated HBA steps (see emulation::on_wait)
+        if cfg!(all(test, not(target_os = "none"))) || scheduler::is_idle_task()
         {
-            core::hint::spin_loop();
+            loop
+            {
+                if let Some(it) = check()
+                {
+                    return it;
+                }
+                #[cfg(all(test, not(target_os = "none")))]
+                super::emulation::on_wait();
+
+                core::hint::spin_loop();
+            }
         }
 
-        println!("Status before CI:");
-        println!("  CI: {:08x}, IS: {:08x}\n  SACT: {:08x}, SERR: {:08x}, TFD: {:08x}",
-            port.ci.get(),
-            port.is.get_raw(),
-            port.sact.get(),
-            port.serr.get(),
-            port.tfd.get());
-        // TODO: Remove
-        busy_sleep(5000);
+        loop
+        {
+            // Without interrupts disabled, the command could complete between checking and blocking,
+            // and nobody would ever wake us up.
+            let it = irqsave(|| {
 
-        port.is.clear_pss();
-        port.is.clear_dhrs();
-        port.ci.set(1u32 << slot_num);
+                let it = check();
+                if it.is_none()
+                {
+                    let task = scheduler::block_current_task();
+                    IRQ_STATE.lock().waiters.push(Waiter {
+                        hba_idx: self.hba_idx,
+                        port_idx: self.hba_port_idx,
+                        slots,
+                        deadline,
+                        task
+                    });
+                    scheduler::reschedule();
+                }
+                it
+            });
+            if let Some(it) = it
+            {
+                return it;
+            }
+        }
+    }
 
-        // ATA_DEV_BUSY (0x80) | ATA_DEV_DRQ (0x08)
-        debug!("Post-Submit wait for done (no timeout)");
-        let mut start = crate::arch::x86_64::kernel::get_ticks();
-        while port.tfd.get() & 0x88 != 0
+    /// Turns the PxIS bits of an error interrupt into a DiskError, and whether retrying the command could help.
+    fn classify_error(&self, port: &PortRegister, status: u32) -> (DiskError, bool)
+    {
+        // Task File Error: The device itself reported the error. Retrying will not change its mind.
+        if status & PORT_IS_TFES != 0
         {
-            core::hint::spin_loop();
-            let cur = crate::arch::x86_64::kernel::get_ticks();
-            let diff = cur - start;
-            if diff > 10_000
+            let tfd = port.tfd.get();
+            // ATAPI: the error register holds the SCSI sense key (Bits 4..=7), not the ATA error bits
+            if self.atapi
+            {
+                return (DiskError::DeviceFault { status: tfd as u8, error: (tfd >> 8) as u8 }, false);
+            }
+            let rfis = &self.fb.rfis;
+            let lba =
+                rfis.lba0.get() as u64
+                | (rfis.lba1.get() as u64) << 8
+                | (rfis.lba2.get() as u64) << 16
+                | (rfis.lba3.get() as u64) << 24
+                | (rfis.lba4.get() as u64) << 32
+                | (rfis.lba5.get() as u64) << 40;
+            return (Self::device_error(tfd as u8, (tfd >> 8) as u8, lba), false);
+        }
+
+        // Everything else is an interface or host bus error, which may be gone on the next try
+        let tfd = port.tfd.get();
+        (DiskError::DeviceFault { status: tfd as u8, error: (tfd >> 8) as u8 }, true)
+    }
+
+    /// Status and Error as reported by the device
+    fn device_error(status: u8, error: u8, lba: u64) -> DiskError
+    {
+        // ERROR.UNC (Uncorrectable Data) or ERROR.IDNF (ID Not Found)
+        if status & 0x20 == 0 && error & 0x50 != 0
+        {
+            DiskError::MediaError { lba }
+        }
+        else
+        {
+            DiskError::DeviceFault { status, error }
+        }
+    }
+
+    /// 6.2.2: Gets the port back into a usable state after an error or a timeout.
+    /// Every command in flight is gone afterwards, stopping the port clears PxCI and PxSACT.
+    /// 
+    /// Returns false, if the port could not be restarted.
+    fn recover(&mut self) -> bool
+    {
+        let supports_clo = self.cap.get_sclo();
+        let port = self.regs();
+
+        // 9.3.6: With FIS-based switching, the error of a single device is cleared without stopping the port,
+        // which would take the other devices behind the port multiplier down with it.
+        // PxFBS.DWE tells which device failed. If it is another one, this command failed for a different reason.
+        let fbs = port.fbs.get();
+        let failed_device = ((fbs >> FBS_DWE_SHIFT) & 0xf) as u8;
+        if self.is_behind_multiplier() && fbs & FBS_EN != 0 && fbs & FBS_SDE != 0 && self.pmp == Some(failed_device)
+        {
+            port.serr.set(0x07_ff_0f_03);
+            port.is.clear_all();
+            port.fbs.set(FBS_EN | FBS_DEC | ((failed_device as u32) << FBS_DEV_SHIFT));
+            let recovered = Self::wait_ms(500, || port.fbs.get() & FBS_DEC == 0);
+            take_port_error(self.hba_idx, self.hba_port_idx);
+            if recovered
+            {
+                return true;
+            }
+        }
+
+        let recovered = Self::recover_impl(port, supports_clo);
+        if !recovered
+        {
+            debug!("Port {}: Recovery failed", self.hba_port_idx);
+        }
+        // The error interrupts caused by the recovery itself are of no interest
+        take_port_error(self.hba_idx, self.hba_port_idx);
+        recovered
+    }
+
+    /// Stops the port, clears its errors and gets the device out of BSY or DRQ (CLO, else COMRESET), then starts the port again.
+    /// Returns false, if the device did not come back.
+    pub(super) fn recover_impl(port: &PortRegister, supports_clo: bool) -> bool
+    {
+        port.cmd.set_st(false);
+        let stopped = Self::wait_ms(500, || !port.cmd.get_cr());
+
+        port.serr.set(0x07_ff_0f_03);
+        port.is.clear_all();
+
+        // Is the device still busy (BSY or DRQ)? First try the cheap Command List Override,
+        // which can only be used with a stopped command list.
+        if stopped && supports_clo && port.tfd.get() & 0x88 != 0
+        {
+            port.cmd.set_clo();
+            Self::wait_ms(500, || !port.cmd.get_clo());
+        }
+
+        if !stopped || port.tfd.get() & 0x88 != 0
+        {
+            debug!("COMRESET");
+            if !Self::comreset_impl(port)
             {
-                start = cur;
-                println!(
-                    "TFD: {:02x}, CI: {:08x}, IS: {:08x}, SERR: {:08x}, SACT: {:08x}",
-                    port.tfd.get() & 0x88,
-                    port.ci.get() & (1u32 << slot_num),
-                    port.is.get_raw(),
-                    port.serr.get(),
-                    port.sact.get());
+                return false;
             }
+            port.serr.set(0x07_ff_0f_03);
+            port.is.clear_all();
         }
 
-        Some((slot_num, total_prdt_count as u32))
+        port.cmd.set_st(true);
+        true
+    }
+
+    /// 10.4.2: COMRESET through PxSCTL.DET. Returns true, if a device is communicating and ready afterwards.
+    /// 
+    /// PxCMD.ST must be 0.
+    pub(super) fn comreset_impl(port: &PortRegister) -> bool
+    {
+        port.sctl.set(port.sctl.get() & !0xfu32 | 1u32);
+        Self::wait_ms(5, || false); // Docs: wait at least 1 ms
+        port.sctl.set(port.sctl.get() & !0xfu32);
+
+        // The device has to spin up again, this can take a while
+        Self::wait_ms(1000, || port.ssts.get() & 0xf == 3)
+            && Self::wait_ms(Self::DEFAULT_COMMAND_TIMEOUT, || port.tfd.get() & 0x88 == 0)
+    }
+
+    /// Spins until `done` returns true, for at most `ms` milliseconds. Returns the final result of `done`.
+    /// Under `cargo test` every spin is a step of the emulated HBA (see emulation::on_wait).
+    fn wait_ms<F>(ms: u64, mut done: F) -> bool
+        where F: FnMut() -> bool
+    {
+        let start = get_ticks();
+        while !done()
+        {
+            #[cfg(all(test, not(target_os = "none")))]
+            super::emulation::on_wait();
+
+            if get_ticks() - start >= ms
+            {
+                return done();
+            }
+            core::hint::spin_loop();
+        }
+        true
     }
 
     /// Unsafe Note: buffer must be writable, if data from the device is read.
     /// The buffer_len must be the size of the buffer.
     /// The buffer size must be divisible by 2, the buffer aligned by 2.
-    /*unsafe fn handle_fis_ident_only(&mut self, hba: &mut HbaMemory, buffer: u64, buffer_len: u64, fis: &RegH2D) -> Option<u8>
+    /*unsafe fn handle_fis_ident_only(&mut self, buffer: u64, buffer_len: u64, fis: &RegH2D) -> Option<u8>
     {
         assert_eq!(buffer & 1, 0, "buffer must be 2 byte aligned.");
         // assert_eq!(buffer_len & 1, 0, "buffer_len must be a multiple of 2");
@@ -1033,14 +3010,13 @@ impl AhciPort2
 
         let buffer_physical = paging::get_physical_address::<BasePageSize>(buffer as usize);
 
-        let port = &mut hba.ports[self.hba_port_idx];
-        let slot_num = match Self::find_empty_slot(port, self.cmd_slot_count as usize - 1)
+        let port = self.regs();
+        let slot_num = match Self::find_empty_slot(port, self.cmd_slot_count as usize)
         {
             None => return None,
             Some(it) => it,
         };
 
-        debug!("Using slot {}", slot_num);
         let cmd_header = &mut self.clb[slot_num as usize];
         cmd_header.reset();
         cmd_header.set_prdtl(1);
@@ -1062,7 +3038,7 @@ impl AhciPort2
         let addr_lo = address as u32;
         let addr_hi = (address >> 32) as u32;
         cmd_header.set_ctba(addr_lo);
-        if hba.ghc.cap.get_s64a()
+        if self.cap.get_s64a()
         {
             cmd_header.set_ctbau(addr_hi);
         }
@@ -1083,11 +3059,11 @@ impl AhciPort2
         Some(slot_num)
     }*/
 
-    fn find_empty_slot(this: &PortRegister, cmd_slot_count: usize) -> Option<u8>
+    /// The first slot neither issued (PxCI) nor queued (PxSACT), out of the `cmd_slot_count` slots of the HBA
+    pub(super) fn find_empty_slot(this: &PortRegister, cmd_slot_count: usize) -> Option<u8>
     {
         // Remember: the hba has a value in 0..=31, I use a value in 1..=32
-        debug_assert!(cmd_slot_count < 32);
-        // let slots = self.cmd_slot_count;
+        debug_assert!(cmd_slot_count <= 32);
         let options = this.ci.get() | this.sact.get();
         for i in 0..cmd_slot_count
         {
@@ -1100,110 +3076,387 @@ impl AhciPort2
     }
 }
 
-#[doc(hidden)]
-pub fn on_interrupt(_num: u8)
+/// How waiting for a command ended
+#[derive(Debug)]
+enum Completion
+{
+    Done,
+    /// PxIS at the time of the error
+    Error(u32),
+    Timeout
+}
+
+/// A task waiting for the HBA to complete any of its command slots
+struct Waiter
+{
+    hba_idx: usize,
+    port_idx: usize,
+    /// One bit per command slot
+    slots: u32,
+    /// In ticks, on_timer wakes up the task after this point
+    deadline: u64,
+    task: Rc<RefCell<Task>>
+}
+
+/// Everything the interrupt handler needs.
+/// 
+/// A task keeps its port locked, while it waits for its command to complete,
+/// so the interrupt handler can never use the ports (or HBAs) themselves.
+struct IrqState
+{
+    hbas: Vec<IrqHba>,
+    waiters: Vec<Waiter>,
+    /// (HBA, Port, PxIS): Errors reported by on_interrupt, which the port did not look at yet
+    errors: Vec<(usize, usize, u32)>,
+    /// (HBA, Port): Ports with PxIS.PCS or PxIS.PRCS set, for the hot-plug worker
+    port_changes: Vec<(usize, u8)>,
+    /// The hot-plug worker, while it waits for port changes
+    hotplug_task: Option<Rc<RefCell<Task>>>
+}
+
+/// A HBA, as far as the interrupt handler knows it
+struct IrqHba
+{
+    /// Index in AHCI_DEVICES
+    hba_idx: usize,
+    irq: u8,
+    abar: *mut HbaMemory,
+    /// While coalescing is enabled: (CCC_CTL.INT, CCC_PORTS)
+    ccc: Option<(u8, u32)>,
+    stats: InterruptStats
+}
+
+/// How often on_interrupt had something to do for a HBA. Meant to compare the interrupt load, e.g. with and without coalescing.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct InterruptStats
 {
-    let mut someone_errored = false;
-
-    super::on_each_device(|i, hba| {
-
-		println!("INTERRUPT HBA {}", i);
-		println!(
-			"- BOH {}, NCS {}, NP  {}",
-			hba.abar_ptr.ghc.cap2.get_boh(),
-			hba.abar_ptr.ghc.cap.get_ncs_adjusted(),
-			hba.abar_ptr.ghc.cap.get_np_adjusted());
-		println!("- IS {:032b}, PI  {:032b}", hba.abar_ptr.ghc.is, hba.abar_ptr.ghc.pi);
-        let mut all_none = true;
-		for (j, port) in hba.ports.iter().enumerate()
-		{
-			if let Some(port) = port
-			{
-                all_none = false;
-				println!("- Port {}", j);
-				println!(
-					"  - {}, {}, CR {}, FR {}",
-					port.hba_idx == i,
-					port.hba_port_idx == j,
-					hba.abar_ptr.ports[port.hba_port_idx].cmd.get_cr(),
-					hba.abar_ptr.ports[port.hba_port_idx].cmd.get_fr());
-				println!("  - CI  {}, CCS {}", hba.abar_ptr.ports[port.hba_port_idx].ci.get(), hba.abar_ptr.ports[port.hba_port_idx].cmd.get_ccs());
-				println!(
-					"  - STS {:x}, SSTS {:03x}, SIG {:08x}, SERR {:08x}",
-					hba.abar_ptr.ports[port.hba_port_idx].tfd.get() & 0xf,
-					hba.abar_ptr.ports[port.hba_port_idx].ssts.get(),
-					hba.abar_ptr.ports[port.hba_port_idx].sig.get(),
-					hba.abar_ptr.ports[port.hba_port_idx].serr.get());
-				
-                if hba.abar_ptr.ports[port.hba_port_idx].is.get_raw() & 0xfd_00_00_00 != 0
+    /// Interrupts with any bit of GHC.IS set
+    pub interrupts: u64,
+    /// The ones among them raised by command completion coalescing
+    pub coalesced: u64,
+    /// How often each port was serviced
+    pub ports: [u64; 32]
+}
+
+// Unsafe Note: Rc and raw pointers are not Send. eduOS runs on a single core and
+// IRQ_STATE is only ever locked with interrupts disabled, so there is nobody to share them with.
+unsafe impl Send for IrqState {}
+
+static IRQ_STATE: SpinlockIrqSave<IrqState> = SpinlockIrqSave::new(IrqState {
+    hbas: Vec::new(),
+    waiters: Vec::new(),
+    errors: Vec::new(),
+    port_changes: Vec::new(),
+    hotplug_task: None
+});
+
+fn register_hba(hba_idx: usize, irq: u8, abar: &mut HbaMemory)
+{
+    IRQ_STATE.lock().hbas.push(IrqHba {
+        hba_idx,
+        irq,
+        abar: abar as *mut HbaMemory,
+        ccc: None,
+        stats: InterruptStats::default()
+    });
+}
+
+fn unregister_hba(abar: &mut HbaMemory)
+{
+    let abar = abar as *mut HbaMemory;
+    IRQ_STATE.lock().hbas.retain(|it| !core::ptr::eq(it.abar, abar));
+}
+
+/// Tells on_interrupt about the coalesced interrupt of the HBA: (CCC_CTL.INT, CCC_PORTS), None if disabled.
+/// 
+/// Disabling wakes up every task waiting on the HBA, so no completion coalesced so far goes unnoticed.
+/// They look at their command again and go back to sleep, if it is still running.
+fn set_coalescing(abar: &mut HbaMemory, ccc: Option<(u8, u32)>)
+{
+    let abar = abar as *mut HbaMemory;
+    let mut state = IRQ_STATE.lock();
+    let IrqState { hbas, waiters, .. } = &mut *state;
+    if let Some(hba) = hbas.iter_mut().find(|it| core::ptr::eq(it.abar, abar))
+    {
+        hba.ccc = ccc;
+        if ccc.is_none()
+        {
+            let hba_idx = hba.hba_idx;
+            waiters.retain(|it| {
+
+                if it.hba_idx == hba_idx
+                {
+                    scheduler::wakeup_task(it.task.clone());
+                    false
+                }
+                else
                 {
-                    println!("Hba <TODO> Port {} throw an error", port.hba_port_idx);
-                    someone_errored = true;
+                    true
                 }
-			}
-		}
-        println!("All None? {}", all_none);
-	});
+            });
+        }
+    }
+}
+
+/// The interrupt statistics of the HBA at `hba_idx`
+pub fn interrupt_stats(hba_idx: usize) -> Option<InterruptStats>
+{
+    IRQ_STATE.lock().hbas.iter().find(|it| it.hba_idx == hba_idx).map(|it| it.stats)
+}
 
-    if someone_errored
+/// Starts counting from 0 again, e.g. before a benchmark
+pub fn reset_interrupt_stats(hba_idx: usize)
+{
+    if let Some(it) = IRQ_STATE.lock().hbas.iter_mut().find(|it| it.hba_idx == hba_idx)
     {
-        panic!("A Port reported an error (unhandled)")
+        it.stats = InterruptStats::default();
     }
 }
 
-static AHCI_DEVICES: Spinlock<Vec<AhciDevice2>> = Spinlock::new(Vec::new());
+/// Removes and returns the PxIS bits of all errors on the port since the last call
+fn take_port_error(hba_idx: usize, port_idx: usize) -> Option<u32>
+{
+    let mut state = IRQ_STATE.lock();
+    let idx = state.errors.iter().position(|it| it.0 == hba_idx && it.1 == port_idx)?;
+    Some(state.errors.swap_remove(idx).2)
+}
 
-pub fn with_ahci_devices<F>(mut func: F)
-    where F: FnMut(&Vec<AhciDevice2>)
+/// Blocks the hot-plug worker until a port changed. Returns (HBA, Port) of every port, which changed.
+pub(super) fn wait_for_port_changes() -> Vec<(usize, u8)>
 {
-    const PIC2_DATA: u16 = 0xa1;
+    loop
+    {
+        // Interrupts are disabled for the same reason as in wait_for_any
+        let changes = irqsave(|| {
 
-    // Mask IRQ 9 - 11
-    const MASK: u8 = 8 | 4 | 2;
-    let old_data = unsafe {
-        let it = x86::io::inb(PIC2_DATA);
-        x86::io::outb(PIC2_DATA, it | MASK);
-        it
-    };
+            let mut state = IRQ_STATE.lock();
+            if state.port_changes.is_empty()
+            {
+                state.hotplug_task = Some(scheduler::block_current_task());
+                drop(state);
+                scheduler::reschedule();
+                None
+            }
+            else
+            {
+                Some(core::mem::take(&mut state.port_changes))
+            }
+        });
+        if let Some(it) = changes
+        {
+            return it;
+        }
+    }
+}
+
+/// The IRQs with an AHCI handler in the IDT (see ahci_handler_* in irq.rs)
+pub const AHCI_IRQS: [u8; 3] = [9, 10, 11];
+/// PxIS bits, which report an error: TFES, HBFS, HBDS, IFS, INFS, OFS, IPMS
+const PORT_IS_ERROR_MASK: u32 = 0xfd_00_00_00;
+/// PxIS.TFES: The device reported an error in the task file
+const PORT_IS_TFES: u32 = 1u32 << 30;
+/// PxIS.PRCS: PhyRdy changed, the link went up or down
+const PORT_IS_PRCS: u32 = 1u32 << 22;
+/// PxIS.PCS: Port Connect Change, a COMINIT was received
+const PORT_IS_PCS: u32 = 1u32 << 6;
+/// PxSIG of an ATA device (a disk)
+const SIG_ATA: u32 = 0x00_00_01_01;
+/// PxSIG of an ATAPI device
+const SIG_ATAPI: u32 = 0xeb_14_01_01;
+/// Signature of a port multiplier (answering a software reset to its control port)
+const SIG_PM: u32 = 0x96_69_01_01;
+/// Port multiplier General Status and Control Register 2: Port Information (Bits 0..=3: count of device ports)
+const GSCR_PORT_INFO: u16 = 2;
+/// Port multiplier Port Status and Control Registers, one set per device port
+const PSCR_SSTATUS: u16 = 0;
+const PSCR_SERROR: u16 = 1;
+const PSCR_SCONTROL: u16 = 2;
+/// PxFBS.EN: FIS-based switching enabled
+const FBS_EN: u32 = 1u32 << 0;
+/// PxFBS.DEC: Device Error Clear
+const FBS_DEC: u32 = 1u32 << 1;
+/// PxFBS.SDE: Single Device Error
+const FBS_SDE: u32 = 1u32 << 2;
+/// PxFBS.DEV: the port multiplier port the next command issued goes to
+const FBS_DEV_SHIFT: u32 = 8;
+/// PxFBS.DWE: the port multiplier port of the device, which failed (valid with SDE set)
+const FBS_DWE_SHIFT: u32 = 16;
+/// PxSERR.DIAG.N: PhyRdy Change, clears PxIS.PRCS
+const SERR_DIAG_N: u32 = 1u32 << 16;
+/// PxSERR.DIAG.X: Exchanged, clears PxIS.PCS
+const SERR_DIAG_X: u32 = 1u32 << 26;
+
+/// Wakes up every task, whose command timed out. Called by the timer interrupt.
+#[doc(hidden)]
+pub fn on_timer(ticks: u64)
+{
+    let mut state = IRQ_STATE.lock();
+    state.waiters.retain(|it| {
+
+        if it.deadline <= ticks
+        {
+            scheduler::wakeup_task(it.task.clone());
+            false
+        }
+        else
+        {
+            true
+        }
+    });
+}
+
+/// Called for IRQ `num`. Only the HBAs routed to `num` are looked at, and only their ports flagged in GHC.IS.
+/// The coalesced interrupt (CCC_CTL.INT) stands for every port in CCC_PORTS, as their completions do not set their bit.
+///
+/// 10.7.2.1: PxIS is cleared first, GHC.IS (just the bits handled) after it. Otherwise the HBA sets the bit in GHC.IS right again.
+///
+/// No printing in here, it takes way too long and the serial port may be in use by the task we interrupted.
+#[doc(hidden)]
+pub fn on_interrupt(num: u8)
+{
+    let mut state = IRQ_STATE.lock();
+    let IrqState { hbas, waiters, errors, port_changes, hotplug_task } = &mut *state;
+
+    for it in hbas.iter_mut()
+    {
+        if it.irq != num
+        {
+            continue;
+        }
+
+        // Unsafe Note: The ABAR stays mapped until the AhciDevice2 is dropped, which unregisters it first.
+        // The owning task may have a reference to it, but it does not run, while we do.
+        let hba = unsafe { &mut *it.abar };
+
+        // Ports not implemented never set their bit, but who knows
+        let implemented = if hba.ports.len() >= 32 { u32::MAX } else { (1u32 << hba.ports.len()) - 1 };
+        let ccc_mask = it.ccc.map_or(0, |(int, _)| 1u32 << int);
+        let pending = hba.ghc.is.get_raw() & (implemented | ccc_mask);
+        if pending == 0
+        {
+            continue;
+        }
+        it.stats.interrupts += 1;
 
-    // Why not IrqSafeSpinlock? Because I need at least PIT
-    let lock = AHCI_DEVICES.lock();
-    func(lock.as_ref());
+        let mut remaining = pending & !ccc_mask;
+        if let Some((_, ccc_ports)) = it.ccc.filter(|_| pending & ccc_mask != 0)
+        {
+            it.stats.coalesced += 1;
+            remaining |= ccc_ports;
+        }
+        remaining &= implemented;
 
-    // TODO: REMOVE
-    // let last_irq = crate::arch::irq::irq_nested_disable();
-    // loop {
-    //     unsafe { x86::halt() };
-    // }
-    // crate::arch::irq::irq_nested_enable(last_irq);
+        while remaining != 0
+        {
+            let port_idx = remaining.trailing_zeros() as usize;
+            remaining &= remaining - 1;
 
-    unsafe {
+            it.stats.ports[port_idx] += 1;
+            on_port_interrupt(it.hba_idx, port_idx, &mut hba.ports[port_idx], waiters, errors, port_changes, hotplug_task);
+        }
 
-        x86::io::outb(PIC2_DATA, old_data);
+        hba.ghc.is.clear_raw(pending);
     }
 }
 
-pub fn with_ahci_devices_mut<F>(mut func: F)
-    where F: FnMut(&mut Vec<AhciDevice2>)
+/// The part of on_interrupt for one port: clears PxIS, records errors and connect changes and wakes up whoever waits for the port.
+fn on_port_interrupt(
+    hba_idx: usize,
+    port_idx: usize,
+    port: &PortRegister,
+    waiters: &mut Vec<Waiter>,
+    errors: &mut Vec<(usize, usize, u32)>,
+    port_changes: &mut Vec<(usize, u8)>,
+    hotplug_task: &mut Option<Rc<RefCell<Task>>>)
 {
-    const PIC2_DATA: u16 = 0xa1;
+    // PxIS first, or the HBA sets the bit in IS again
+    let status = port.is.get_raw();
+    port.is.clear_raw(status);
 
-    // Mask IRQ 9 - 11
-    const MASK: u8 = 8 | 4 | 2;
-    let old_data = unsafe {
-        let it = x86::io::inb(PIC2_DATA);
-        x86::io::outb(PIC2_DATA, it | MASK);
-        it
-    };
+    // Connect changes are up to the hot-plug worker, as setting up a port takes way too long for an interrupt handler
+    let mut gone = false;
+    if status & (PORT_IS_PCS | PORT_IS_PRCS) != 0
+    {
+        port.serr.set(SERR_DIAG_N | SERR_DIAG_X);
+        if !port_changes.contains(&(hba_idx, port_idx as u8))
+        {
+            port_changes.push((hba_idx, port_idx as u8));
+        }
+        if let Some(task) = hotplug_task.take()
+        {
+            scheduler::wakeup_task(task);
+        }
+        gone = port.ssts.get() & 0xf != 3;
+    }
 
-    // Why not IrqSafeSpinlock? Because I need at least PIT
-    let mut lock = AHCI_DEVICES.lock();
-    func(lock.as_mut());
+    // The waiting task recovers the port, we just tell it about the error.
+    // A removed device will never complete its commands, so they fail as well.
+    let failed = status & PORT_IS_ERROR_MASK != 0 || gone;
+    if failed
+    {
+        match errors.iter_mut().find(|it| it.0 == hba_idx && it.1 == port_idx)
+        {
+            Some(it) => it.2 |= status,
+            None => errors.push((hba_idx, port_idx, status))
+        }
+    }
 
-    unsafe {
+    // Non queued commands complete by clearing PxCI, queued ones (NCQ) by clearing PxSACT (through a Set Device Bits FIS)
+    let running = port.ci.get() | port.sact.get();
+    waiters.retain(|it| {
 
-        x86::io::outb(PIC2_DATA, old_data);
-    }
+        if it.hba_idx == hba_idx && it.port_idx == port_idx && (failed || running & it.slots != it.slots)
+        {
+            scheduler::wakeup_task(it.task.clone());
+            false
+        }
+        else
+        {
+            true
+        }
+    });
+}
+
+/// Only locked to add a HBA or to get one, never while working with it.
+/// Each HBA has a lock of its own, and so has each of its ports.
+static AHCI_DEVICES: Spinlock<Vec<Arc<AhciDevice2>>> = Spinlock::new(Vec::new());
+
+// No need to mask the AHCI interrupts anymore, on_interrupt does not touch AHCI_DEVICES.
+/// Calls `func` with every HBA. AHCI_DEVICES is not locked meanwhile, so `func` may wait for a port as long as it wants.
+pub fn with_ahci_devices<F>(mut func: F)
+    where F: FnMut(&[Arc<AhciDevice2>])
+{
+    let devices = AHCI_DEVICES.lock().clone();
+    func(&devices);
+}
+
+/// Adds the HBAs found by init
+pub(super) fn add_ahci_devices(devices: Vec<AhciDevice2>)
+{
+    AHCI_DEVICES.lock().extend(devices.into_iter().map(Arc::new));
+}
+
+pub(super) fn ahci_device_count() -> usize
+{
+    AHCI_DEVICES.lock().len()
+}
+
+/// The HBA at `hba_idx`, the index used by `on_each_device` and PortEvent
+pub fn get_ahci_device(hba_idx: usize) -> Option<Arc<AhciDevice2>>
+{
+    AHCI_DEVICES.lock().get(hba_idx).cloned()
+}
+
+/// Calls `func` with the disk at (`hba_idx`, `port_idx`, `pmp`), holding the lock of its port only.
+/// `pmp` is None for a disk attached directly, see PortEvent.
+/// 
+/// Returns None, if there is no such disk.
+pub fn with_port<F, R>(hba_idx: usize, port_idx: usize, pmp: Option<u8>, func: F) -> Option<R>
+    where F: FnOnce(&mut AhciPort2) -> R
+{
+    let device = get_ahci_device(hba_idx)?;
+    let mut slot = device.ports.get(port_idx)?.lock();
+    slot.get_mut(pmp).map(func)
 }
 
 // pub(super) static PORTS: Spinlock<Vec<AhciPort2>> = Spinlock::new(Vec::new());
@@ -0,0 +1,102 @@
+// NEW
+
+/// SCSI commands sent to ATAPI devices through the PACKET command (ACMD of the command table).
+/// Every CDB is padded to 16 bytes, the device only looks at the first 12.
+pub struct Cdb;
+
+impl Cdb
+{
+    pub const TEST_UNIT_READY: u8 = 0x00;
+    pub const INQUIRY: u8 = 0x12;
+    pub const READ_CAPACITY_10: u8 = 0x25;
+    pub const READ_10: u8 = 0x28;
+
+    pub fn test_unit_ready() -> [u8; 16]
+    {
+        let mut cdb = [0u8; 16];
+        cdb[0] = Self::TEST_UNIT_READY;
+        cdb
+    }
+
+    pub fn inquiry(allocation_length: u8) -> [u8; 16]
+    {
+        let mut cdb = [0u8; 16];
+        cdb[0] = Self::INQUIRY;
+        cdb[4] = allocation_length;
+        cdb
+    }
+
+    pub fn read_capacity_10() -> [u8; 16]
+    {
+        let mut cdb = [0u8; 16];
+        cdb[0] = Self::READ_CAPACITY_10;
+        cdb
+    }
+
+    /// Everything in SCSI is big endian
+    pub fn read_10(lba: u32, blocks: u16) -> [u8; 16]
+    {
+        let mut cdb = [0u8; 16];
+        cdb[0] = Self::READ_10;
+        cdb[2..6].copy_from_slice(&lba.to_be_bytes());
+        cdb[7..9].copy_from_slice(&blocks.to_be_bytes());
+        cdb
+    }
+}
+
+/// The standard INQUIRY data (SPC 6.4.2), the first 36 bytes
+#[derive(Debug, Clone)]
+pub struct InquiryData
+{
+    /// 5: CD/DVD device
+    pub peripheral_device_type: u8,
+    pub removable: bool,
+    vendor: [u8; 8],
+    product: [u8; 16],
+    revision: [u8; 4]
+}
+
+impl InquiryData
+{
+    /// Even, the buffer for it is made of u16
+    pub const LEN: usize = 36;
+
+    pub fn parse(bytes: &[u8; Self::LEN]) -> Self
+    {
+        let mut vendor = [0u8; 8];
+        let mut product = [0u8; 16];
+        let mut revision = [0u8; 4];
+        vendor.copy_from_slice(&bytes[8..16]);
+        product.copy_from_slice(&bytes[16..32]);
+        revision.copy_from_slice(&bytes[32..36]);
+
+        Self {
+            peripheral_device_type: bytes[0] & 0x1f,
+            removable: bytes[1] & 0x80 != 0,
+            vendor,
+            product,
+            revision
+        }
+    }
+
+    /// Strings are padded with spaces, which are cut off here
+    fn as_str(bytes: &[u8]) -> &str
+    {
+        core::str::from_utf8(bytes).unwrap_or("").trim()
+    }
+
+    pub fn vendor(&self) -> &str
+    {
+        Self::as_str(&self.vendor)
+    }
+
+    pub fn product(&self) -> &str
+    {
+        Self::as_str(&self.product)
+    }
+
+    pub fn revision(&self) -> &str
+    {
+        Self::as_str(&self.revision)
+    }
+}
@@ -0,0 +1,271 @@
+// NEW
+
+// The disks of the AHCI ports as BlockDevices: every ATA and ATAPI device (directly attached or behind a port multiplier)
+// is registered, once it is identified, and unregistered, once it is gone.
+
+use super::{
+    ahci2::AhciPort2,
+    hotplug::{subscribe, PortEvent},
+    on_each_port,
+    with_port,
+    DiskError
+};
+use crate::{
+    LogLevel,
+    LOGGER,
+    drivers::block::{self, BlockDevice},
+    synch::spinlock::Spinlock
+};
+use alloc::{
+    string::String,
+    sync::Arc,
+    vec::Vec
+};
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+/// (HBA index, Port index, port multiplier port), as in PortEvent
+type Location = (usize, usize, Option<u8>);
+
+/// The name every AHCI disk is registered under, to unregister it again
+static NAMES: Spinlock<Vec<(Location, String)>> = Spinlock::new(Vec::new());
+
+/// What a disk is recognized by, as a disk plugged into the same port later on is a different one.
+/// The serial number alone is not enough, some disks (and most emulated ones) leave it empty.
+struct Identity
+{
+    serial: String,
+    model: String,
+    /// 0 for ATAPI devices, their size comes with the medium
+    size: u64
+}
+
+impl Identity
+{
+    fn of(port: &AhciPort2) -> Self
+    {
+        Self {
+            serial: String::from(port.identify.as_ref().map_or("", |it| it.serial())),
+            model: String::from(port.identify.as_ref().map_or("", |it| it.model())),
+            size: Self::size_of(port)
+        }
+    }
+
+    fn size_of(port: &AhciPort2) -> u64
+    {
+        if port.atapi { 0 } else { port.size }
+    }
+
+    fn matches(&self, port: &AhciPort2) -> bool
+    {
+        port.identify.as_ref().map_or(("", ""), |it| (it.serial(), it.model())) == (self.serial.as_str(), self.model.as_str())
+            && Self::size_of(port) == self.size
+    }
+}
+
+/// A disk on an AHCI port. Every call locks the port for as long as it takes, apart from the size:
+/// the buffer cache asks for it on every lookup.
+pub struct AhciBlockDevice
+{
+    location: Location,
+    /// Checked on every call
+    identity: Identity,
+    /// Taken at registration and updated by every call locking the port, as the medium of an ATAPI device may change
+    block_size: AtomicU32,
+    block_count: AtomicU64
+}
+
+impl AhciBlockDevice
+{
+    fn new(location: Location, port: &AhciPort2) -> Self
+    {
+        let it = Self {
+            location,
+            identity: Identity::of(port),
+            block_size: AtomicU32::new(512),
+            block_count: AtomicU64::new(0)
+        };
+        it.update_size(port);
+        it
+    }
+
+    fn update_size(&self, port: &AhciPort2)
+    {
+        self.block_size.store(port.logical_sector_size(), Ordering::Relaxed);
+        self.block_count.store(port.size / port.logical_sector_size() as u64, Ordering::Relaxed);
+    }
+
+    /// Calls `func` with the port, if it still holds this disk
+    fn with_port<F, R>(&self, func: F) -> Result<R, DiskError>
+        where F: FnOnce(&mut AhciPort2) -> Result<R, DiskError>
+    {
+        let (hba_idx, port_idx, pmp) = self.location;
+        with_port(hba_idx, port_idx, pmp, |port| {
+
+            if !self.identity.matches(port)
+            {
+                return Err(DiskError::PortNotReady);
+            }
+            let result = func(port);
+            self.update_size(port);
+            result
+        }).unwrap_or(Err(DiskError::PortNotReady))
+    }
+
+    /// AHCI transfers 2 byte aligned buffers only, checked before the port is locked and the DMA set up
+    fn check_alignment(buffer: &[u8]) -> Result<(), DiskError>
+    {
+        if buffer.as_ptr() as usize & 1 != 0 || buffer.len() & 1 != 0
+        {
+            Err(DiskError::MisalignedBuffer)
+        }
+        else
+        {
+            Ok(())
+        }
+    }
+
+    /// The device may stop early, the sector it stopped at is the one it had trouble with
+    fn check_transferred(port: &AhciPort2, first_block: u64, expected: usize, transferred: usize) -> Result<(), DiskError>
+    {
+        if transferred == expected
+        {
+            Ok(())
+        }
+        else
+        {
+            Err(DiskError::MediaError { lba: first_block + (transferred / port.logical_sector_size() as usize) as u64 })
+        }
+    }
+}
+
+impl BlockDevice for AhciBlockDevice
+{
+    fn block_size(&self) -> u32
+    {
+        self.block_size.load(Ordering::Relaxed)
+    }
+
+    fn block_count(&self) -> u64
+    {
+        self.block_count.load(Ordering::Relaxed)
+    }
+
+    fn read_blocks(&self, first_block: u64, buffer: &mut [u8]) -> Result<(), DiskError>
+    {
+        Self::check_alignment(buffer)?;
+        self.with_port(|port| {
+
+            let transferred = port.read_u8(first_block, buffer)?;
+            Self::check_transferred(port, first_block, buffer.len(), transferred)
+        })
+    }
+
+    fn write_blocks(&self, first_block: u64, buffer: &[u8]) -> Result<(), DiskError>
+    {
+        Self::check_alignment(buffer)?;
+        self.with_port(|port| {
+
+            let transferred = port.write_u8(first_block, buffer)?;
+            Self::check_transferred(port, first_block, buffer.len(), transferred)
+        })
+    }
+
+    fn flush(&self) -> Result<(), DiskError>
+    {
+        self.with_port(|port| port.flush())
+    }
+
+    fn is_read_only(&self) -> bool
+    {
+        self.with_port(|port| Ok(port.is_read_only())).unwrap_or(true)
+    }
+
+    fn trim(&self, first_block: u64, block_count: u64) -> Result<(), DiskError>
+    {
+        // AhciPort2::trim splits the ranges further, they only have to fit its u32
+        let mut ranges = Vec::new();
+        let mut lba = first_block;
+        let end = first_block.checked_add(block_count).ok_or(DiskError::OutOfRange { lba: first_block })?;
+        while lba < end
+        {
+            let count = (end - lba).min(u32::MAX as u64);
+            ranges.push((lba, count as u32));
+            lba += count;
+        }
+        self.with_port(|port| port.trim(&ranges))
+    }
+}
+
+/// The port multiplier itself is no disk
+fn is_disk(port: &AhciPort2) -> bool
+{
+    port.identify.is_some() || port.atapi
+}
+
+fn register(location: Location, port: &AhciPort2)
+{
+    // Plugged in while register_block_devices was walking the ports, it is already there
+    if NAMES.lock().iter().any(|(it, _)| *it == location)
+    {
+        return;
+    }
+    let name = block::register(Arc::new(AhciBlockDevice::new(location, port)));
+    info!(
+        "{}: HBA {}, Port {}{} ({}), {} Blocks of {} Bytes",
+        name,
+        location.0,
+        location.1,
+        location.2.map_or(String::new(), |it| alloc::format!(".{}", it)),
+        port.identify.as_ref().map_or("", |it| it.model()),
+        port.size / port.logical_sector_size() as u64,
+        port.logical_sector_size()
+    );
+    NAMES.lock().push((location, name));
+}
+
+fn unregister(location: Location)
+{
+    let name = {
+        let mut names = NAMES.lock();
+        match names.iter().position(|(it, _)| *it == location)
+        {
+            Some(pos) => names.remove(pos).1,
+            None => return
+        }
+    };
+    block::unregister(&name);
+    info!("{}: Gone", name);
+}
+
+/// Runs on the hot-plug worker, no AHCI lock held
+fn on_port_event(event: PortEvent)
+{
+    match event
+    {
+        PortEvent::Connected { hba_idx, port_idx, pmp } =>
+        {
+            // Done under the lock of the port, so it cannot be gone while it is registered
+            with_port(hba_idx, port_idx, pmp, |port| {
+
+                if is_disk(port)
+                {
+                    register((hba_idx, port_idx, pmp), port);
+                }
+            });
+        },
+        PortEvent::Disconnected { hba_idx, port_idx, pmp } => unregister((hba_idx, port_idx, pmp))
+    }
+}
+
+/// Registers every disk found during init and subscribes to the ones plugged in (or out) later
+pub fn register_block_devices()
+{
+    subscribe(on_port_event);
+    on_each_port(|hba_idx, port_idx, port| {
+
+        if is_disk(port)
+        {
+            register((hba_idx, port_idx, port.pmp), port);
+        }
+    });
+}
@@ -0,0 +1,129 @@
+// NEW
+
+// Without CAP.S64A the HBA only sees the first 4 GiB of physical memory.
+// Everything it reads or writes (command lists, received FIS areas, command tables and data) has to be down there.
+
+use crate::arch::x86_64::mm::paging::{
+    BasePageSize,
+    PageSize
+};
+#[cfg(not(all(test, not(target_os = "none"))))]
+use crate::arch::x86_64::mm::{
+    paging::{
+        self,
+        PageTableEntryFlags
+    },
+    physicalmem,
+    virtualmem
+};
+use super::scatter_gather::PhysicalRuns;
+
+/// The first byte a HBA without CAP.S64A cannot address
+pub const LIMIT_32BIT: usize = 1 << 32;
+
+/// Allocates `pages` physically contiguous pages the HBA can address, mapped like every other structure shared with it.
+///
+/// Returns: (virtual address, physical address)
+#[cfg(not(all(test, not(target_os = "none"))))]
+pub fn allocate(pages: usize, is_64bit_aware: bool) -> (usize, usize)
+{
+    let size = pages * BasePageSize::SIZE;
+    let phys = if is_64bit_aware
+    {
+        physicalmem::allocate(size)
+    }
+    else
+    {
+        physicalmem::allocate_below(size, LIMIT_32BIT)
+    };
+    let virt = virtualmem::allocate(size);
+    paging::map::<BasePageSize>(virt, phys, pages, PageTableEntryFlags::WRITABLE | PageTableEntryFlags::CACHE_DISABLE | PageTableEntryFlags::WRITE_THROUGH);
+
+    (virt, phys)
+}
+
+/// Frees what allocate returned
+#[cfg(not(all(test, not(target_os = "none"))))]
+pub fn deallocate(virt: usize, pages: usize)
+{
+    let size = pages * BasePageSize::SIZE;
+    let phys = paging::get_physical_address::<BasePageSize>(virt);
+    paging::unmap::<BasePageSize>(virt, pages);
+
+    virtualmem::deallocate(virt, size);
+    physicalmem::deallocate(phys, size);
+}
+
+/// The tests on the host hand plain host memory to the emulated HBA (see emulation), the HBA is 64 bit aware there
+#[cfg(all(test, not(target_os = "none")))]
+pub fn allocate(pages: usize, _is_64bit_aware: bool) -> (usize, usize)
+{
+    let virt = unsafe { alloc::alloc::alloc_zeroed(host_layout(pages)) } as usize;
+    assert_ne!(virt, 0);
+    (virt, virt)
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+pub fn deallocate(virt: usize, pages: usize)
+{
+    unsafe { alloc::alloc::dealloc(virt as *mut u8, host_layout(pages)) };
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+fn host_layout(pages: usize) -> alloc::alloc::Layout
+{
+    alloc::alloc::Layout::from_size_align(pages * BasePageSize::SIZE, BasePageSize::SIZE).unwrap()
+}
+
+/// Can the HBA reach every byte of the buffer?
+pub fn is_addressable(buffer: usize, len: usize, is_64bit_aware: bool) -> bool
+{
+    is_64bit_aware
+        || PhysicalRuns::new(buffer, len).all(|(phys, len)| phys as usize + len as usize <= LIMIT_32BIT)
+}
+
+/// A copy of a buffer the HBA cannot reach, below 4 GiB.
+/// The data is copied in on creation, for reads it has to be copied back with `copy_to`.
+pub struct BounceBuffer
+{
+    virt: usize,
+    len: usize
+}
+
+impl BounceBuffer
+{
+    /// Unsafe Note: `buffer` must be readable for `len` bytes
+    pub unsafe fn new(buffer: *const u8, len: usize) -> Self
+    {
+        let pages = Self::pages(len);
+        let (virt, _) = allocate(pages, false);
+        core::ptr::copy_nonoverlapping(buffer, virt as *mut u8, len);
+        Self { virt, len }
+    }
+
+    fn pages(len: usize) -> usize
+    {
+        (len + BasePageSize::SIZE - 1) / BasePageSize::SIZE
+    }
+
+    /// The virtual address, to hand to the HBA instead of the original buffer
+    pub fn as_u64(&self) -> u64
+    {
+        self.virt as u64
+    }
+
+    /// Unsafe Note: `buffer` must be writable for as many bytes as the bounce buffer was created with
+    pub unsafe fn copy_to(&self, buffer: *mut u8)
+    {
+        core::ptr::copy_nonoverlapping(self.virt as *const u8, buffer, self.len);
+    }
+}
+
+impl Drop for BounceBuffer
+{
+    /// The command using the buffer must have completed
+    fn drop(&mut self)
+    {
+        deallocate(self.virt, Self::pages(self.len));
+    }
+}
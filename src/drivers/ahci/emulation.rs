@@ -0,0 +1,890 @@
+// NEW
+
+// A software model of a HBA with a disk behind every port, so the driver code can be tested by `cargo test` on the host, without QEMU.
+// Physical addresses are host addresses: the command lists, command tables and buffers the model looks at are plain memory of the test.
+// The model works on the registers as the spec lays them out (raw words), not through the driver's wrappers, so it does not share their bugs.
+//
+// There is no trap on register writes, so the model runs, whenever `step` (or `issue`, for a write to PxCI) is called,
+// or the driver waits for it (AhciPort2::wait_ms, AhciPort2::wait_for_any) inside of `run_driver`.
+// It executes every slot set in PxCI of a started port: it reads the H2D FIS, moves the data through the PRDT,
+// sets PRDBC, writes the D2H FIS into the received FIS area and clears the bit in PxCI.
+// Errors are injected per disk with `Fault`.
+//
+// The default target (.cargo/config.toml) is the kernel's, the tests run on the host, with a std built for it:
+// cargo test --lib --target x86_64-unknown-linux-gnu -Z build-std=std
+
+extern crate std;
+
+use super::{
+    HbaMemory,
+    PortRegister,
+    ahci2::AhciPort2,
+    fis::{
+        CommandHeader,
+        CommandListStructure,
+        CommandTable2,
+        ReceivedFis,
+        RegH2D,
+        Type
+    },
+    scatter_gather::PhysicalRuns,
+    DiskError,
+    IdentifyData
+};
+use alloc::{
+    alloc::{alloc_zeroed, dealloc, Layout},
+    vec::Vec,
+    vec
+};
+use core::{
+    cell::Cell,
+    ptr::null_mut
+};
+
+pub const SECTOR_SIZE: usize = 512;
+
+// Offsets (in u32) of the port registers, AHCI 3.3
+const PX_CLB: usize = 0;
+const PX_CLBU: usize = 1;
+const PX_FB: usize = 2;
+const PX_FBU: usize = 3;
+const PX_IS: usize = 4;
+const PX_CMD: usize = 6;
+const PX_TFD: usize = 8;
+const PX_SIG: usize = 9;
+const PX_SSTS: usize = 10;
+const PX_SCTL: usize = 11;
+const PX_SACT: usize = 13;
+const PX_CI: usize = 14;
+
+const CMD_ST: u32 = 1 << 0;
+const CMD_CLO: u32 = 1 << 3;
+const CMD_FRE: u32 = 1 << 4;
+const CMD_FR: u32 = 1 << 14;
+const CMD_CR: u32 = 1 << 15;
+
+const IS_DHRS: u32 = 1 << 0;
+const IS_TFES: u32 = 1 << 30;
+
+const STS_ERR: u8 = 0x01;
+const STS_DRQ: u8 = 0x08;
+const STS_DRDY: u8 = 0x40;
+const STS_BSY: u8 = 0x80;
+const ERR_ABRT: u8 = 0x04;
+const ERR_IDNF: u8 = 0x10;
+
+/// An error the disk runs into with its next command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault
+{
+    /// The command fails with STS.ERR and this error register (PxIS.TFES)
+    TaskFileError { error: u8 },
+    /// The disk never finishes the command, BSY stays set until a COMRESET
+    StuckBusy,
+    /// The command completes, but only this many bytes are transferred (PRDBC)
+    ShortTransfer { bytes: u32 }
+}
+
+pub struct EmulatedDisk
+{
+    /// The backing store, SECTOR_SIZE bytes per sector
+    pub data: Vec<u8>,
+    pub fault: Option<Fault>,
+    stuck: bool
+}
+
+impl EmulatedDisk
+{
+    pub fn new(sector_count: usize) -> Self
+    {
+        Self { data: vec![0u8; sector_count * SECTOR_SIZE], fault: None, stuck: false }
+    }
+
+    pub fn sector_count(&self) -> u64
+    {
+        (self.data.len() / SECTOR_SIZE) as u64
+    }
+
+    /// IDENTIFY DEVICE data: 48 bit LBA, write cache, 512 byte sectors
+    fn identify(&self) -> [u16; 256]
+    {
+        let mut words = [0u16; 256];
+        let sectors = self.sector_count();
+        let mut ata_string = |first: usize, text: &[u8], len: usize| {
+
+            for i in 0..len
+            {
+                let byte = |j: usize| *text.get(j).unwrap_or(&b' ') as u16;
+                words[first + i] = byte(i * 2) << 8 | byte(i * 2 + 1);
+            }
+        };
+        ata_string(10, b"EMU0001", 10);
+        ata_string(23, b"1.0", 4);
+        ata_string(27, b"EMULATED AHCI DISK", 20);
+
+        words[0] = 0x00_40;
+        words[49] = 1 << 9; // LBA
+        words[60] = sectors.min(0x0f_ff_ff_ff) as u16;
+        words[61] = (sectors.min(0x0f_ff_ff_ff) >> 16) as u16;
+        words[82] = 1 << 14 | 1 << 5;
+        words[83] = 1 << 14 | 1 << 10;
+        words[85] = 1 << 5;
+        words[86] = 1 << 10;
+        words[100] = sectors as u16;
+        words[101] = (sectors >> 16) as u16;
+        words[102] = (sectors >> 32) as u16;
+        words[103] = (sectors >> 48) as u16;
+        words[106] = 0x40_00;
+        words
+    }
+}
+
+/// Zeroed host memory with the alignment the HBA asks for, freed on drop
+pub struct HostMemory
+{
+    ptr: *mut u8,
+    layout: Layout
+}
+
+impl HostMemory
+{
+    pub fn new(size: usize, align: usize) -> Self
+    {
+        let layout = Layout::from_size_align(size, align).unwrap();
+        let ptr = unsafe { alloc_zeroed(layout) };
+        assert!(!ptr.is_null());
+        Self { ptr, layout }
+    }
+
+    /// The "physical" address, as the model sees the host memory
+    pub fn addr(&self) -> u64
+    {
+        self.ptr as u64
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8]
+    {
+        unsafe { core::slice::from_raw_parts_mut(self.ptr, self.layout.size()) }
+    }
+}
+
+impl Drop for HostMemory
+{
+    fn drop(&mut self)
+    {
+        unsafe { dealloc(self.ptr, self.layout) };
+    }
+}
+
+/// What the model did with a command
+enum Outcome
+{
+    Done { transferred: u32, lba: u64 },
+    Error { error: u8, lba: u64 },
+    Stuck
+}
+
+std::thread_local! {
+    /// The model inside of `run_driver`, per thread, as the tests run in parallel
+    static RUNNING: Cell<*mut EmulatedHba> = Cell::new(null_mut());
+}
+
+/// Called by AhciPort2::wait_ms and AhciPort2::wait_for_any on every spin: a millisecond passes and the model inside of `run_driver` reacts
+pub fn on_wait()
+{
+    crate::arch::x86_64::kernel::inc_ticks();
+    RUNNING.with(|it| {
+
+        if let Some(hba) = unsafe { it.get().as_mut() }
+        {
+            hba.step();
+        }
+    });
+}
+
+pub struct EmulatedHba
+{
+    /// GHC (0x100 bytes) and the port registers (0x80 bytes each)
+    regs: Vec<u32>,
+    pub disks: Vec<Option<EmulatedDisk>>,
+    /// Command list (1 KiB) and received FIS area (256 bytes) of each started port
+    port_memory: Vec<Option<(HostMemory, HostMemory)>>,
+    /// Set after a task file error, until the port is stopped (AHCI 6.2.2.1)
+    halted: Vec<bool>
+}
+
+impl EmulatedHba
+{
+    /// 32 command slots, 64 bit addressing (host addresses are 64 bit). A port without a disk reports no device.
+    pub fn new(disks: Vec<Option<EmulatedDisk>>) -> Self
+    {
+        let port_count = disks.len();
+        assert!(port_count > 0 && port_count <= 32);
+
+        let mut it = Self {
+            regs: vec![0u32; (0x100 + port_count * 0x80) / 4],
+            port_memory: (0..port_count).map(|_| None).collect(),
+            halted: vec![false; port_count],
+            disks
+        };
+        // CAP: S64A, NCS 32, NP
+        it.regs[0] = 1 << 31 | 31 << 8 | (port_count as u32 - 1);
+        // PI
+        it.regs[3] = if port_count == 32 { u32::MAX } else { (1u32 << port_count) - 1 };
+        // VS: 1.3
+        it.regs[4] = 0x00_01_03_00;
+        for i in 0..port_count
+        {
+            it.link_up(i);
+        }
+        it
+    }
+
+    fn reg(&mut self, port_idx: usize, offset: usize) -> &mut u32
+    {
+        &mut self.regs[(0x100 + port_idx * 0x80) / 4 + offset]
+    }
+
+    /// The registers as the driver sees them
+    pub fn hba(&mut self) -> &mut HbaMemory
+    {
+        let port_count = self.disks.len();
+        unsafe { &mut *core::ptr::from_raw_parts_mut(self.regs.as_mut_ptr() as *mut (), port_count) }
+    }
+
+    pub fn port(&mut self, port_idx: usize) -> &mut PortRegister
+    {
+        &mut self.hba().ports[port_idx]
+    }
+
+    /// Gives the port its command list and received FIS area and starts it (FRE, then ST), as AhciPort2 does.
+    pub fn start_port(&mut self, port_idx: usize)
+    {
+        let clb = HostMemory::new(1024, 1024);
+        let fb = HostMemory::new(256, 256);
+        let port = self.port(port_idx);
+        port.clb.set(clb.addr() as u32);
+        port.clbu.set((clb.addr() >> 32) as u32);
+        port.fb.set(fb.addr() as u32);
+        port.fbu.set((fb.addr() >> 32) as u32);
+        port.cmd.set_fre(true);
+        port.cmd.set_st(true);
+        self.port_memory[port_idx] = Some((clb, fb));
+        self.step();
+    }
+
+    pub fn command_header(&mut self, port_idx: usize, slot: u8) -> &mut CommandHeader
+    {
+        let clb = self.port_memory[port_idx].as_ref().expect("Port not started").0.addr();
+        unsafe { &mut *(clb as *mut CommandHeader).add(slot as usize) }
+    }
+
+    pub fn received_fis(&self, port_idx: usize) -> &ReceivedFis
+    {
+        let fb = self.port_memory[port_idx].as_ref().expect("Port not started").1.addr();
+        unsafe { &*(fb as *const ReceivedFis) }
+    }
+
+    /// An AhciPort2 in `memory` on the started port, as AhciPort2::new leaves it, with the size of the disk (IDENTIFY is not run).
+    /// Its commands go through the driver's own path, inside of `run_driver` the model executes them.
+    pub fn driver_port<'a>(&mut self, port_idx: usize, memory: &'a mut HostMemory) -> &'a mut AhciPort2
+    {
+        let sectors = self.disks[port_idx].as_ref().expect("No disk").sector_count();
+        let (clb, fb) = self.port_memory[port_idx].as_ref().expect("Port not started");
+        let (clb, fb) = (clb.addr(), fb.addr());
+        let (cap, cap2) = (self.hba().ghc.cap.clone(), self.hba().ghc.cap2.clone());
+        let slots = (self.regs[0] >> 8 & 0x1f) as u8 + 1;
+        let port_mem: *mut PortRegister = self.port(port_idx);
+
+        assert!(memory.as_mut_slice().len() >= core::mem::size_of::<AhciPort2>());
+        let port = unsafe {
+            AhciPort2::init_memory(
+                memory.as_mut_slice().as_mut_ptr() as *mut AhciPort2,
+                0,
+                port_idx,
+                port_mem,
+                cap,
+                cap2,
+                &mut *(clb as *mut CommandListStructure),
+                &mut *(fb as *mut ReceivedFis),
+                slots,
+                true)
+        };
+        port.size = sectors * SECTOR_SIZE as u64;
+        port
+    }
+
+    /// The next command of the disk on the port runs into `fault`
+    pub fn inject(&mut self, port_idx: usize, fault: Fault)
+    {
+        self.disks[port_idx].as_mut().expect("No disk").fault = Some(fault);
+    }
+
+    /// Runs driver code on the registers of the port, the model steps whenever it waits for the HBA
+    pub fn run_driver<F, R>(&mut self, port_idx: usize, func: F) -> R
+        where F: FnOnce(&PortRegister) -> R
+    {
+        let port: *const PortRegister = self.port(port_idx);
+        RUNNING.with(|it| it.set(self as *mut Self));
+        // Unsafe Note: the registers change through shared references, as with the real HBA
+        let result = func(unsafe { &*port });
+        RUNNING.with(|it| it.set(null_mut()));
+        result
+    }
+
+    /// A write of `slots` to PxCI, as done by the driver to issue commands
+    pub fn issue(&mut self, port_idx: usize, slots: u32)
+    {
+        self.port(port_idx).ci.set(slots);
+        self.step();
+    }
+
+    /// Reacts to whatever the driver did to the registers since the last step, then runs every command issued.
+    ///
+    /// - PxCMD.ST cleared: PxCI and PxSACT are cleared, the port leaves the error state (AHCI 3.3.7)
+    /// - PxCMD.CLO set: BSY and DRQ are cleared in PxTFD, CLO clears itself
+    /// - PxSCTL.DET = 1 (COMRESET): the link goes down and a stuck disk is reset, the link comes back with DET = 0
+    /// - PxCI set for a stuck disk: BSY is set again, the command never completes
+    pub fn step(&mut self)
+    {
+        for i in 0..self.disks.len()
+        {
+            self.step_port(i);
+        }
+    }
+
+    fn step_port(&mut self, i: usize)
+    {
+        if *self.reg(i, PX_SCTL) & 0xf == 1
+        {
+            *self.reg(i, PX_SSTS) = 0;
+            *self.reg(i, PX_TFD) = (STS_BSY | STS_DRDY) as u32;
+            if let Some(disk) = self.disks[i].as_mut()
+            {
+                disk.stuck = false;
+            }
+            return;
+        }
+        if *self.reg(i, PX_SSTS) & 0xf != 3
+        {
+            self.link_up(i);
+        }
+
+        let cmd = *self.reg(i, PX_CMD);
+        let mut new_cmd = cmd & !(CMD_CR | CMD_FR | CMD_CLO);
+        if cmd & CMD_FRE != 0
+        {
+            new_cmd |= CMD_FR;
+        }
+        if cmd & CMD_CLO != 0
+        {
+            *self.reg(i, PX_TFD) &= !((STS_BSY | STS_DRQ) as u32);
+        }
+        if cmd & CMD_ST == 0
+        {
+            *self.reg(i, PX_CI) = 0;
+            *self.reg(i, PX_SACT) = 0;
+            *self.reg(i, PX_CMD) = new_cmd;
+            self.halted[i] = false;
+            return;
+        }
+        *self.reg(i, PX_CMD) = new_cmd | CMD_CR;
+
+        // The HBA sets BSY sending the command, a hung disk never clears it
+        if self.disks[i].as_ref().map_or(false, |it| it.stuck) && *self.reg(i, PX_CI) != 0
+        {
+            *self.reg(i, PX_TFD) |= STS_BSY as u32;
+        }
+        let stuck = self.disks[i].as_ref().map_or(true, |it| it.stuck);
+        if stuck || self.halted[i] || *self.reg(i, PX_TFD) as u8 & (STS_BSY | STS_DRQ) != 0
+        {
+            return;
+        }
+
+        let mut pending = *self.reg(i, PX_CI);
+        while pending != 0
+        {
+            let slot = pending.trailing_zeros() as u8;
+            pending &= pending - 1;
+
+            match self.execute(i, slot)
+            {
+                Outcome::Done { transferred, lba } =>
+                {
+                    self.command_header(i, slot).set_prdbc(transferred);
+                    self.send_d2h(i, STS_DRDY, 0, lba);
+                    *self.reg(i, PX_CI) &= !(1u32 << slot);
+                    *self.reg(i, PX_IS) |= IS_DHRS;
+                }
+                Outcome::Error { error, lba } =>
+                {
+                    self.send_d2h(i, STS_DRDY | STS_ERR, error, lba);
+                    *self.reg(i, PX_IS) |= IS_TFES;
+                    self.halted[i] = true;
+                }
+                Outcome::Stuck =>
+                {
+                    *self.reg(i, PX_TFD) = STS_BSY as u32;
+                    self.disks[i].as_mut().unwrap().stuck = true;
+                }
+            }
+            // GHC.IS
+            self.regs[2] |= 1u32 << i;
+
+            // The HBA stops at an error (or a hung device)
+            if *self.reg(i, PX_TFD) as u8 & (STS_BSY | STS_ERR) != 0
+            {
+                break;
+            }
+        }
+    }
+
+    /// After power on or a COMRESET: a disk signals its presence with a D2H FIS
+    fn link_up(&mut self, i: usize)
+    {
+        if self.disks[i].is_some()
+        {
+            *self.reg(i, PX_SSTS) = 0x1_23; // Active, Gen 2, Present and communicating
+            *self.reg(i, PX_SIG) = 0x01_01; // ATA
+            *self.reg(i, PX_TFD) = (STS_DRDY | 0x10) as u32;
+        }
+        else
+        {
+            *self.reg(i, PX_SSTS) = 0;
+            *self.reg(i, PX_SIG) = u32::MAX;
+            *self.reg(i, PX_TFD) = 0x7f;
+        }
+    }
+
+    /// Updates PxTFD and, if FIS receive is on, the D2H Register FIS of the received FIS area
+    fn send_d2h(&mut self, i: usize, status: u8, error: u8, lba: u64)
+    {
+        *self.reg(i, PX_TFD) = (error as u32) << 8 | status as u32;
+        if *self.reg(i, PX_CMD) & CMD_FRE == 0
+        {
+            return;
+        }
+        let fb = *self.reg(i, PX_FB) as u64 | (*self.reg(i, PX_FBU) as u64) << 32;
+        let rfis = unsafe { &mut (*(fb as *mut ReceivedFis)).rfis };
+        rfis.fis_type.set(Type::REG_D2H);
+        rfis.status.set(status);
+        rfis.error.set(error);
+        rfis.lba0.set(lba as u8);
+        rfis.lba1.set((lba >> 8) as u8);
+        rfis.lba2.set((lba >> 16) as u8);
+        rfis.lba3.set((lba >> 24) as u8);
+        rfis.lba4.set((lba >> 32) as u8);
+        rfis.lba5.set((lba >> 40) as u8);
+        rfis.device.set(0x40);
+    }
+
+    fn execute(&mut self, i: usize, slot: u8) -> Outcome
+    {
+        let clb = *self.reg(i, PX_CLB) as u64 | (*self.reg(i, PX_CLBU) as u64) << 32;
+        let header = unsafe { &*(clb as *const CommandHeader).add(slot as usize) };
+        let ctba = header.get_ctba() as u64 | (unsafe { header.get_ctbau() } as u64) << 32;
+        let prdtl = header.get_prdtl() as usize;
+        let table: &CommandTable2 = unsafe { &*core::ptr::from_raw_parts(ctba as *const (), prdtl) };
+        let fis = unsafe { &*(table.cfis.as_ptr() as *const RegH2D) };
+
+        let prds: Vec<(u64, usize)> = table.prdt.iter()
+            .map(|it| {
+
+                let it = it.get();
+                (it.get_dba() as u64 | (it.get_dbau() as u64) << 32, it.get_dbc_adjusted() as usize)
+            })
+            .collect();
+        let prd_len: usize = prds.iter().map(|it| it.1).sum();
+
+        let disk = self.disks[i].as_mut().unwrap();
+        let mut limit = usize::MAX;
+        match disk.fault.take()
+        {
+            Some(Fault::TaskFileError { error }) => return Outcome::Error { error, lba: 0 },
+            Some(Fault::StuckBusy) => return Outcome::Stuck,
+            Some(Fault::ShortTransfer { bytes }) => limit = bytes as usize,
+            None => ()
+        }
+
+        let command = fis.command.get();
+        let ext = matches!(command, 0x24 | 0x25 | 0x34 | 0x35 | 0x42 | 0xea);
+        let lba = if ext
+        {
+            fis.lba0.get() as u64
+            | (fis.lba1.get() as u64) << 8
+            | (fis.lba2.get() as u64) << 16
+            | (fis.lba3.get() as u64) << 24
+            | (fis.lba4.get() as u64) << 32
+            | (fis.lba5.get() as u64) << 40
+        }
+        else
+        {
+            fis.lba0.get() as u64
+            | (fis.lba1.get() as u64) << 8
+            | (fis.lba2.get() as u64) << 16
+            | ((fis.device.get() & 0xf) as u64) << 24
+        };
+        let count = match (ext, fis.countl.get() as u64 | (fis.counth.get() as u64) << 8)
+        {
+            (true, 0) => 65536,
+            (false, it) if it & 0xff == 0 => 256,
+            (false, it) => it & 0xff,
+            (true, it) => it
+        };
+        let in_range = lba + count <= disk.sector_count();
+
+        match command
+        {
+            // IDENTIFY DEVICE
+            0xec =>
+            {
+                let bytes: Vec<u8> = disk.identify().iter().flat_map(|it| it.to_le_bytes()).collect();
+                let transferred = Self::scatter(&prds, &bytes[..bytes.len().min(limit)]);
+                Outcome::Done { transferred, lba: 0 }
+            }
+            // READ SECTOR(S) (EXT), READ DMA (EXT)
+            0x20 | 0x24 | 0x25 | 0xc8 =>
+            {
+                if !in_range
+                {
+                    return Outcome::Error { error: ERR_IDNF, lba };
+                }
+                let start = lba as usize * SECTOR_SIZE;
+                let len = (count as usize * SECTOR_SIZE).min(prd_len).min(limit);
+                let transferred = Self::scatter(&prds, &disk.data[start..start + len]);
+                Outcome::Done { transferred, lba }
+            }
+            // WRITE SECTOR(S) (EXT), WRITE DMA (EXT)
+            0x30 | 0x34 | 0x35 | 0xca =>
+            {
+                if !in_range
+                {
+                    return Outcome::Error { error: ERR_IDNF, lba };
+                }
+                let start = lba as usize * SECTOR_SIZE;
+                let len = (count as usize * SECTOR_SIZE).min(prd_len).min(limit);
+                let transferred = Self::gather(&prds, &mut disk.data[start..start + len]);
+                Outcome::Done { transferred, lba }
+            }
+            // READ VERIFY SECTOR(S) (EXT)
+            0x40 | 0x42 if !in_range => Outcome::Error { error: ERR_IDNF, lba },
+            // READ VERIFY SECTOR(S) (EXT), FLUSH CACHE (EXT), SET FEATURES, CHECK POWER MODE, standby and idle
+            0x40 | 0x42 | 0xe7 | 0xea | 0xef | 0xe5 | 0xe0 | 0xe1 | 0xe2 | 0xe3 => Outcome::Done { transferred: 0, lba },
+            _ => Outcome::Error { error: ERR_ABRT, lba: 0 }
+        }
+    }
+
+    /// Copies `data` into the PRDT buffers. Returns: bytes copied
+    fn scatter(prds: &[(u64, usize)], data: &[u8]) -> u32
+    {
+        let mut done = 0usize;
+        for &(address, len) in prds
+        {
+            let len = len.min(data.len() - done);
+            unsafe { core::ptr::copy_nonoverlapping(data[done..].as_ptr(), address as *mut u8, len) };
+            done += len;
+        }
+        done as u32
+    }
+
+    /// Copies the PRDT buffers into `data`. Returns: bytes copied
+    fn gather(prds: &[(u64, usize)], data: &mut [u8]) -> u32
+    {
+        let mut done = 0usize;
+        for &(address, len) in prds
+        {
+            let len = len.min(data.len() - done);
+            unsafe { core::ptr::copy_nonoverlapping(address as *const u8, data[done..].as_mut_ptr(), len) };
+            done += len;
+        }
+        done as u32
+    }
+}
+
+/// A buffer spread over separate host pages in reverse order: virtually contiguous (from `VIRT_BASE` on), but every page is its own physical run.
+/// The translation is handed to PhysicalRuns::with_translation, as paging would be for a real buffer.
+struct ScatteredBuffer
+{
+    pages: Vec<HostMemory>
+}
+
+impl ScatteredBuffer
+{
+    const VIRT_BASE: usize = 0x4000_0000;
+    const PAGE: usize = 4096;
+
+    fn new(page_count: usize) -> Self
+    {
+        Self { pages: (0..page_count).map(|_| HostMemory::new(Self::PAGE, Self::PAGE)).collect() }
+    }
+
+    fn translate(&self, virt: usize) -> usize
+    {
+        let offset = virt - Self::VIRT_BASE;
+        let page = self.pages.len() - 1 - offset / Self::PAGE;
+        self.pages[page].addr() as usize + offset % Self::PAGE
+    }
+
+    fn len(&self) -> usize
+    {
+        self.pages.len() * Self::PAGE
+    }
+
+    fn runs(&self) -> Vec<(u64, u32)>
+    {
+        PhysicalRuns::with_translation(Self::VIRT_BASE, self.len(), |it| self.translate(it)).collect()
+    }
+
+    /// Byte `idx` of the buffer, as seen through its virtual addresses
+    fn byte(&mut self, idx: usize) -> &mut u8
+    {
+        let phys = self.translate(Self::VIRT_BASE + idx);
+        unsafe { &mut *(phys as *mut u8) }
+    }
+}
+
+/// Builds the command in `slot` with the driver's own code (collect_runs, fill_command) and issues it.
+/// Returns the command table, which has to live until the command completed.
+fn submit(hba: &mut EmulatedHba, port_idx: usize, slot: u8, fis: &RegH2D, write: bool, runs: &[(u64, u32)]) -> Result<HostMemory, DiskError>
+{
+    let (runs, count) = AhciPort2::collect_runs(runs.iter().copied())?;
+    let mut table = HostMemory::new(4096, 128);
+    let cmd_tbl: &mut CommandTable2 = unsafe { &mut *core::ptr::from_raw_parts_mut(table.as_mut_slice().as_mut_ptr() as *mut (), count.max(1)) };
+    AhciPort2::fill_command(hba.command_header(port_idx, slot), cmd_tbl, table.addr(), true, 0, write, fis, None, &runs[..count]);
+    hba.issue(port_idx, 1u32 << slot);
+    Ok(table)
+}
+
+fn rw_fis(command: u8, lba: u64, count: u16) -> RegH2D
+{
+    let fis = RegH2D::default();
+    fis.pmport_cc.set(0x80);
+    fis.command.set(command);
+    fis.lba0.set(lba as u8);
+    fis.lba1.set((lba >> 8) as u8);
+    fis.lba2.set((lba >> 16) as u8);
+    fis.lba3.set((lba >> 24) as u8);
+    fis.lba4.set((lba >> 32) as u8);
+    fis.lba5.set((lba >> 40) as u8);
+    fis.device.set(0x40);
+    fis.countl.set(count as u8);
+    fis.counth.set((count >> 8) as u8);
+    fis
+}
+
+fn single_disk(sectors: usize) -> EmulatedHba
+{
+    let mut hba = EmulatedHba::new(vec![Some(EmulatedDisk::new(sectors))]);
+    hba.start_port(0);
+    hba
+}
+
+#[test]
+fn identify_is_parsed()
+{
+    let mut hba = single_disk(2048);
+    let mut buffer = HostMemory::new(512, 2);
+    let _table = submit(&mut hba, 0, 0, &rw_fis(0xec, 0, 0), false, &[(buffer.addr(), 512)]).unwrap();
+
+    assert_eq!(hba.port(0).ci.get(), 0);
+    assert_eq!(hba.command_header(0, 0).get_prdbc(), 512);
+    let mut words = [0u16; 256];
+    for (i, it) in buffer.as_mut_slice().chunks(2).enumerate()
+    {
+        words[i] = u16::from_le_bytes([it[0], it[1]]);
+    }
+    let data = IdentifyData::parse(&words);
+    assert_eq!(data.model(), "EMULATED AHCI DISK");
+    assert!(data.lba48);
+    assert_eq!(data.sectors, 2048);
+    assert_eq!(data.logical_sector_size, 512);
+}
+
+#[test]
+fn write_and_read_back_scattered()
+{
+    let mut hba = single_disk(2048);
+
+    // 4 pages, none next to each other: 4 PRDT entries
+    let mut source = ScatteredBuffer::new(4);
+    let runs = source.runs();
+    assert_eq!(runs.len(), 4);
+    for i in 0..source.len()
+    {
+        *source.byte(i) = (i % 251) as u8;
+    }
+    let sectors = (source.len() / SECTOR_SIZE) as u16;
+    let _table = submit(&mut hba, 0, 3, &rw_fis(0x35, 100, sectors), true, &runs).unwrap();
+    assert_eq!(hba.port(0).ci.get(), 0);
+    assert_eq!(hba.command_header(0, 3).get_prdbc() as usize, source.len());
+    let disk = hba.disks[0].as_ref().unwrap();
+    assert!((0..source.len()).all(|i| disk.data[100 * SECTOR_SIZE + i] == (i % 251) as u8));
+
+    let mut target = ScatteredBuffer::new(4);
+    let runs = target.runs();
+    let _table = submit(&mut hba, 0, 5, &rw_fis(0x25, 100, sectors), false, &runs).unwrap();
+    assert_eq!(hba.command_header(0, 5).get_prdbc() as usize, target.len());
+    assert!((0..target.len()).all(|i| *target.byte(i) == (i % 251) as u8));
+    assert!(hba.port(0).is.get_dhrs());
+}
+
+#[test]
+fn too_many_runs_are_refused()
+{
+    let runs = (0..1000u64).map(|it| (it * 0x2000, 0x1000u32));
+    assert!(matches!(AhciPort2::collect_runs(runs), Err(DiskError::MisalignedBuffer)));
+}
+
+#[test]
+fn empty_slot_skips_issued_and_queued()
+{
+    let mut hba = single_disk(16);
+    // Nothing is executed without ST
+    hba.port(0).cmd.set_st(false);
+    hba.step();
+    hba.port(0).cmd.set_st(true);
+    let port = hba.port(0);
+    port.ci.set(0b1011);
+    port.sact.set(0b0100);
+    assert_eq!(AhciPort2::find_empty_slot(port, 32), Some(4));
+    // The last of 32 slots is as good as any other
+    port.ci.set(0x7f_ff_ff_ff);
+    port.sact.set(0);
+    assert_eq!(AhciPort2::find_empty_slot(port, 32), Some(31));
+    assert_eq!(AhciPort2::find_empty_slot(port, 31), None);
+    assert_eq!(AhciPort2::find_empty_slot(port, 8), None);
+    port.ci.set(u32::MAX);
+    assert_eq!(AhciPort2::find_empty_slot(port, 32), None);
+}
+
+#[test]
+fn driver_writes_and_reads_back()
+{
+    let mut hba = single_disk(64);
+    let mut memory = HostMemory::new(4096, 4096);
+    let port = hba.driver_port(0, &mut memory);
+    assert_eq!(port.cmd_slot_count, 32);
+
+    let source: Vec<u8> = (0..8 * SECTOR_SIZE).map(|it| (it % 251) as u8).collect();
+    assert_eq!(hba.run_driver(0, |_| port.write_u8(10, &source)), Ok(source.len()));
+    let disk = hba.disks[0].as_ref().unwrap();
+    assert_eq!(&disk.data[10 * SECTOR_SIZE..18 * SECTOR_SIZE], &source[..]);
+
+    let mut target = vec![0u8; source.len()];
+    assert_eq!(hba.run_driver(0, |_| port.read_u8(10, &mut target)), Ok(target.len()));
+    assert_eq!(target, source);
+
+    let mut past_the_end = vec![0u8; 2 * SECTOR_SIZE];
+    assert!(matches!(hba.run_driver(0, |_| port.read_u8(63, &mut past_the_end)), Err(DiskError::OutOfRange { lba: 63 })));
+}
+
+#[test]
+fn driver_uses_the_last_slot()
+{
+    let mut hba = single_disk(16);
+    let mut memory = HostMemory::new(4096, 4096);
+    let port = hba.driver_port(0, &mut memory);
+    hba.disks[0].as_mut().unwrap().data[..SECTOR_SIZE].fill(0x5a);
+
+    // Slots 0..=30 are taken by queued commands (which the model never completes)
+    hba.port(0).sact.set(0x7f_ff_ff_ff);
+    let mut buffer = vec![0u8; SECTOR_SIZE];
+    assert_eq!(hba.run_driver(0, |_| port.read_u8(0, &mut buffer)), Ok(SECTOR_SIZE));
+    assert!(buffer.iter().all(|it| *it == 0x5a));
+    assert_eq!(hba.command_header(0, 31).get_prdbc() as usize, SECTOR_SIZE);
+
+    hba.port(0).sact.set(u32::MAX);
+    assert!(matches!(hba.run_driver(0, |_| port.read_u8(0, &mut buffer)), Err(DiskError::NoFreeSlot)));
+}
+
+#[test]
+fn driver_recovers_from_a_hung_disk()
+{
+    let mut hba = single_disk(16);
+    let mut memory = HostMemory::new(4096, 4096);
+    let port = hba.driver_port(0, &mut memory);
+    port.command_timeout = 100;
+    hba.disks[0].as_mut().unwrap().data[SECTOR_SIZE..2 * SECTOR_SIZE].fill(0xa5);
+
+    // The first try times out, the recovery resets the disk (COMRESET, the model has no CLO) and the retry succeeds
+    hba.inject(0, Fault::StuckBusy);
+    let mut buffer = vec![0u8; SECTOR_SIZE];
+    assert_eq!(hba.run_driver(0, |_| port.read_u8(1, &mut buffer)), Ok(SECTOR_SIZE));
+    assert!(buffer.iter().all(|it| *it == 0xa5));
+    assert_eq!(hba.port(0).ci.get(), 0);
+    assert!(hba.port(0).cmd.get_st());
+}
+
+#[test]
+fn task_file_error()
+{
+    let mut hba = single_disk(16);
+    hba.inject(0, Fault::TaskFileError { error: 0x40 });
+    let mut buffer = HostMemory::new(512, 2);
+    let _table = submit(&mut hba, 0, 0, &rw_fis(0x25, 1, 1), false, &[(buffer.addr(), 512)]).unwrap();
+
+    let port = hba.port(0);
+    assert!(port.is.get_tfes());
+    assert_eq!(port.ci.get(), 1);
+    assert_eq!(port.tfd.get() & 0xff_ff, 0x40_41);
+    assert_eq!(hba.received_fis(0).rfis.error.get(), 0x40);
+    assert!(buffer.as_mut_slice().iter().all(|it| *it == 0));
+
+    // The driver's recovery: stopping the port drops the command, the next one runs again
+    assert!(hba.run_driver(0, |port| AhciPort2::recover_impl(port, true)));
+    assert_eq!(hba.port(0).ci.get(), 0);
+    assert!(hba.port(0).cmd.get_st());
+    let _table = submit(&mut hba, 0, 0, &rw_fis(0x25, 1, 1), false, &[(buffer.addr(), 512)]).unwrap();
+    assert_eq!(hba.port(0).ci.get(), 0);
+    assert_eq!(hba.port(0).tfd.get() & 0xff, 0x40);
+}
+
+#[test]
+fn read_past_the_end_is_idnf()
+{
+    let mut hba = single_disk(16);
+    let buffer = HostMemory::new(1024, 2);
+    let _table = submit(&mut hba, 0, 0, &rw_fis(0x25, 15, 2), false, &[(buffer.addr(), 1024)]).unwrap();
+    assert!(hba.port(0).is.get_tfes());
+    assert_eq!((hba.port(0).tfd.get() >> 8) as u8, ERR_IDNF);
+}
+
+#[test]
+fn stuck_busy_until_comreset()
+{
+    let mut hba = single_disk(16);
+    hba.inject(0, Fault::StuckBusy);
+    let buffer = HostMemory::new(512, 2);
+    let _table = submit(&mut hba, 0, 0, &rw_fis(0x25, 0, 1), false, &[(buffer.addr(), 512)]).unwrap();
+    hba.step();
+    assert_eq!(hba.port(0).ci.get(), 1);
+    assert_eq!(hba.port(0).tfd.get() & 0x80, 0x80);
+
+    // The driver's recovery: CLO clears BSY, but the disk is still hung
+    assert!(hba.run_driver(0, |port| AhciPort2::recover_impl(port, true)));
+    assert!(!hba.port(0).cmd.get_clo());
+    assert_eq!(hba.port(0).tfd.get() & 0x88, 0);
+    let _table = submit(&mut hba, 0, 0, &rw_fis(0x25, 0, 1), false, &[(buffer.addr(), 512)]).unwrap();
+    assert_eq!(hba.port(0).ci.get(), 1);
+    assert_eq!(hba.port(0).tfd.get() & 0x80, 0x80);
+
+    // Without CLO it goes straight to a COMRESET, which gets the disk going again
+    assert!(hba.run_driver(0, |port| AhciPort2::recover_impl(port, false)));
+    assert_eq!(hba.port(0).ssts.get() & 0xf, 3);
+    assert_eq!(hba.port(0).tfd.get() & 0x88, 0);
+    assert!(hba.port(0).cmd.get_st());
+
+    let _table = submit(&mut hba, 0, 0, &rw_fis(0x25, 0, 1), false, &[(buffer.addr(), 512)]).unwrap();
+    assert_eq!(hba.port(0).ci.get(), 0);
+}
+
+#[test]
+fn short_prdbc()
+{
+    let mut hba = single_disk(16);
+    hba.inject(0, Fault::ShortTransfer { bytes: 512 });
+    let buffer = HostMemory::new(4096, 2);
+    let _table = submit(&mut hba, 0, 0, &rw_fis(0x25, 0, 8), false, &[(buffer.addr(), 4096)]).unwrap();
+    assert_eq!(hba.port(0).ci.get(), 0);
+    assert_eq!(hba.command_header(0, 0).get_prdbc(), 512);
+}
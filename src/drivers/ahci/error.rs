@@ -0,0 +1,55 @@
+// NEW
+
+use core::fmt::{
+    Result,
+    Formatter,
+    Display
+};
+
+/// Everything, which can go wrong with a command sent to a disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiskError
+{
+    /// The device reported an error (PxTFD.STS.ERR or .DF), which does not point to a sector.
+    /// Also covers interface errors, which did not go away by retrying.
+    DeviceFault { status: u8, error: u8 },
+    /// The device was unable to read or write the sector at `lba` (ERROR.UNC or ERROR.IDNF)
+    MediaError { lba: u64 },
+    /// The command did not complete in time, even after recovering the port
+    Timeout,
+    /// Every command slot usable is in use
+    NoFreeSlot,
+    /// The buffer is null, empty, not 2 byte aligned, its length is not a multiple of the logical sector size
+    /// or it is scattered over more physical memory runs than a command table holds
+    MisalignedBuffer,
+    /// The request reaches past the last sector of the device. `lba` is the first sector requested.
+    OutOfRange { lba: u64 },
+    /// The port could not be (re)started, or there is no device communicating
+    PortNotReady,
+    /// The device does not support the command, according to IDENTIFY
+    Unsupported,
+    /// An argument is outside of what the command (or HBA) accepts
+    InvalidArgument,
+    /// The command may destroy data or change the device, but was not explicitly allowed to
+    PermissionDenied,
+}
+
+impl Display for DiskError
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result
+    {
+        match self
+        {
+            Self::DeviceFault { status, error } => write!(f, "Device Fault (Status {:02x}, Error {:02x})", status, error),
+            Self::MediaError { lba } => write!(f, "Media Error at LBA {}", lba),
+            Self::Timeout => write!(f, "Timeout"),
+            Self::NoFreeSlot => write!(f, "No free command slot"),
+            Self::MisalignedBuffer => write!(f, "Misaligned Buffer"),
+            Self::OutOfRange { lba } => write!(f, "LBA {} out of range", lba),
+            Self::PortNotReady => write!(f, "Port not ready"),
+            Self::Unsupported => write!(f, "Not supported by the device"),
+            Self::InvalidArgument => write!(f, "Invalid argument"),
+            Self::PermissionDenied => write!(f, "Permission denied")
+        }
+    }
+}
@@ -188,17 +188,10 @@ impl CommandTable2Ptr
 {
     pub const MAX_PRDT_ENTRIES: u32 = CommandTable2::MAX_PRDT_ENTRIES_ON_ONE_PAGE;
 
-    pub fn new(num_prdt: u32) -> Self
+    /// Below 4 GiB, unless the HBA is 64 bit aware
+    pub fn new(num_prdt: u32, is_64bit_aware: bool) -> Self
     {
-        use crate::arch::x86_64::mm::{
-            paging::{
-                self,
-                BasePageSize,
-                PageTableEntryFlags
-            },
-            physicalmem,
-            virtualmem
-        };
+        use crate::drivers::ahci::dma;
 
         // Per spec 65535 entires are allowed. But I have to limit it, as I want to fit it in one Page
         // One PRDT is 16 bytes. The base size (0 prdt) of CommandTable2 is 128. Padding in both included.
@@ -208,9 +201,7 @@ impl CommandTable2Ptr
         assert!(num_prdt > 0 && num_prdt < 248, "num_prdt must be between 0..<248");
 
         // Allocate PRDT memory 
-        let pmem = physicalmem::allocate(4096);
-        let vmem = virtualmem::allocate(4096);
-        paging::map::<BasePageSize>(vmem, pmem, 1, PageTableEntryFlags::WRITABLE | PageTableEntryFlags::CACHE_DISABLE | PageTableEntryFlags::WRITE_THROUGH);
+        let (vmem, _) = dma::allocate(1, is_64bit_aware);
 
         let prdt = core::ptr::from_raw_parts_mut(vmem as *mut (), num_prdt as usize);
         unsafe { (prdt as *mut u8).write_bytes(0, (128 + (num_prdt * 16)) as usize) };
@@ -251,19 +242,9 @@ impl CommandTable2Ptr
 
 impl Drop for CommandTable2Ptr
 {
+    /// Frees the page allocated in new. The command using this table must have completed.
     fn drop(&mut self)
     {
-        use crate::arch::x86_64::mm::{
-            paging::{
-                self,
-                BasePageSize,
-                PageTableEntryFlags
-            },
-            physicalmem,
-            virtualmem
-        };
-        let pmem = physicalmem::allocate(4096);
-        let vmem = virtualmem::allocate(4096);
-        paging::map::<BasePageSize>(vmem, pmem, 1, PageTableEntryFlags::WRITABLE | PageTableEntryFlags::CACHE_DISABLE | PageTableEntryFlags::WRITE_THROUGH);
+        crate::drivers::ahci::dma::deallocate(self.as_usize(), 1);
     }
 }
@@ -15,6 +15,9 @@ pub use ex_capabilities::*;
 mod bohc;
 pub use bohc::*;
 
+mod ccc_control;
+pub use ccc_control::*;
+
 mod interrupt_status;
 pub use interrupt_status::*;
 
@@ -43,7 +46,7 @@ pub struct GenericHostControl
     /// VerSion
     pub vs: Register<u32>,
     /// Command Completion Coalescing ConTroL
-    pub ccc_ctl: Register<u32>,
+    pub ccc_ctl: CommandCompletionCoalescingControl,
     /// Command Completion Coalescing PORTS
     pub ccc_ports: Register<u32>,
     /// Enclosure Management LOCation
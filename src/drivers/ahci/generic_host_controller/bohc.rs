@@ -32,21 +32,21 @@ impl BiosOsHandoffControl
     }
 
     /// BIOS Busy
-    pub fn set_bb(&mut self, value: bool)
+    pub fn set_bb(&self, value: bool)
     {
         const MASK: u32 = 1u32 << 4;
         if value
         {
-            self.0 |= MASK;
+            self.0.set(self.0.get() | MASK);
         }
         else
         {
-            self.0 &= !MASK;
+            self.0.set(self.0.get() & !MASK);
         }
     }
 
     /// BIOS Busy
-    pub fn with_bb(mut self, value: bool) -> Self
+    pub fn with_bb(self, value: bool) -> Self
     {
         self.set_bb(value);
         self
@@ -60,7 +60,7 @@ impl BiosOsHandoffControl
 
     /* Comment Out Reason: While it is RW, the doc gives the impression, this is only to be set by the firmware/bios
     /// OS Ownership Change
-    pub fn set_ooc(&mut self)
+    pub fn set_ooc(&self)
     {
         todo!();
     }
@@ -79,21 +79,21 @@ impl BiosOsHandoffControl
     }
 
     /// SMI on OS Ownership Change Enable
-    pub fn set_sooe(&mut self, value: bool)
+    pub fn set_sooe(&self, value: bool)
     {
         const MASK: u32 = 1u32 << 2;
         if value
         {
-            self.0 |= MASK;
+            self.0.set(self.0.get() | MASK);
         }
         else
         {
-            self.0 &= !MASK;
+            self.0.set(self.0.get() & !MASK);
         }
     }
 
     /// SMI on OS Ownership Change Enable
-    pub fn with_sooe(mut self, value: bool) -> Self
+    pub fn with_sooe(self, value: bool) -> Self
     {
         self.set_sooe(value);
         self
@@ -106,21 +106,21 @@ impl BiosOsHandoffControl
     }
 
     /// OS Owned Semaphore
-    pub fn set_oos(&mut self, value: bool)
+    pub fn set_oos(&self, value: bool)
     {
         const MASK: u32 = 1u32 << 1;
         if value
         {
-            self.0 |= MASK;
+            self.0.set(self.0.get() | MASK);
         }
         else
         {
-            self.0 &= !MASK;
+            self.0.set(self.0.get() & !MASK);
         }
     }
 
     /// OS Owned Semaphore
-    pub fn with_oos(mut self, value: bool) -> Self
+    pub fn with_oos(self, value: bool) -> Self
     {
         self.set_oos(value);
         self
@@ -133,21 +133,21 @@ impl BiosOsHandoffControl
     }
 
     /// BIOS Owned Semaphore
-    pub fn set_bos(&mut self, value: bool)
+    pub fn set_bos(&self, value: bool)
     {
         const MASK: u32 = 1u32;
         if value
         {
-            self.0 |= MASK;
+            self.0.set(self.0.get() | MASK);
         }
         else
         {
-            self.0 &= !MASK;
+            self.0.set(self.0.get() & !MASK);
         }
     }
 
     /// BIOS Owned Semaphore
-    pub fn with_bos(mut self, value: bool) -> Self
+    pub fn with_bos(self, value: bool) -> Self
     {
         self.set_bos(value);
         self
@@ -0,0 +1,161 @@
+// NEW
+
+use core::fmt::{
+    Display,
+    Binary,
+    LowerHex,
+    UpperHex,
+    Formatter,
+    Result
+};
+use crate::drivers::util::Register;
+
+/// CCC_CTL: Command Completion Coalescing Control
+///
+/// TV and CC may only be changed, while EN is cleared.
+#[repr(transparent)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandCompletionCoalescingControl(Register<u32>);
+impl CommandCompletionCoalescingControl
+{
+    pub fn from_raw(value: u32) -> Self
+    {
+        Self(Register::new(value))
+    }
+
+    pub fn get_raw(&self) -> u32
+    {
+        self.0.get()
+    }
+
+    /// Timeout Value: in milliseconds, 0 is reserved
+    pub fn get_tv(&self) -> u16
+    {
+        (self.0.get() >> 16) as u16
+    }
+
+    /// Timeout Value: in milliseconds, 0 is reserved
+    pub fn set_tv(&self, value: u16)
+    {
+        self.0.set(self.0.get() & 0x00_00_ff_ff | (value as u32) << 16);
+    }
+
+    /// Timeout Value: in milliseconds, 0 is reserved
+    pub fn with_tv(self, value: u16) -> Self
+    {
+        self.set_tv(value);
+        self
+    }
+
+    /// Command Completions: how many completions raise the interrupt, 0 disables coalescing
+    pub fn get_cc(&self) -> u8
+    {
+        (self.0.get() >> 8) as u8
+    }
+
+    /// Command Completions: how many completions raise the interrupt, 0 disables coalescing
+    pub fn set_cc(&self, value: u8)
+    {
+        self.0.set(self.0.get() & 0xff_ff_00_ff | (value as u32) << 8);
+    }
+
+    /// Command Completions: how many completions raise the interrupt, 0 disables coalescing
+    pub fn with_cc(self, value: u8) -> Self
+    {
+        self.set_cc(value);
+        self
+    }
+
+    /// Interrupt: the bit in GHC.IS (and MSI vector) the coalesced interrupt uses. Read only.
+    pub fn get_int(&self) -> u8
+    {
+        ((self.0.get() >> 3) & 0x1f) as u8
+    }
+
+    /// Enable
+    pub fn get_en(&self) -> bool
+    {
+        self.0.get() & 1u32 != 0
+    }
+
+    /// Enable
+    pub fn set_en(&self, value: bool)
+    {
+        const MASK: u32 = 1u32;
+        if value
+        {
+            self.0.set(self.0.get() | MASK);
+        }
+        else
+        {
+            self.0.set(self.0.get() & !MASK);
+        }
+    }
+
+    /// Enable
+    pub fn with_en(self, value: bool) -> Self
+    {
+        self.set_en(value);
+        self
+    }
+}
+
+impl Display for CommandCompletionCoalescingControl
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result
+    {
+        if f.alternate()
+        {
+            write!(f, "Enable: {}, Command Completions: {}, Timeout Value: {} ms, Interrupt: {}", self.get_en(), self.get_cc(), self.get_tv(), self.get_int())
+        }
+        else
+        {
+            write!(f, "en: {}, cc: {}, tv: {}, int: {}", self.get_en(), self.get_cc(), self.get_tv(), self.get_int())
+        }
+    }
+}
+
+impl Binary for CommandCompletionCoalescingControl
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result
+    {
+        if f.alternate()
+        {
+            write!(f, "{:#032b}", self.0)
+        }
+        else
+        {
+            write!(f, "{:032b}", self.0)
+        }
+    }
+}
+
+impl LowerHex for CommandCompletionCoalescingControl
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result
+    {
+        if f.alternate()
+        {
+            write!(f, "{:#08x}", self.0)
+        }
+        else
+        {
+            write!(f, "{:08x}", self.0)
+        }
+    }
+}
+
+impl UpperHex for CommandCompletionCoalescingControl
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result
+    {
+        if f.alternate()
+        {
+            write!(f, "{:#08X}", self.0)
+        }
+        else
+        {
+            write!(f, "{:08X}", self.0)
+        }
+    }
+}
@@ -34,7 +34,7 @@ impl GlobalHbaControl
     /// This is an illegal operation, when CAP.SAM is set.
     /// 
     /// This precondition is not checked by this function.
-    pub unsafe fn with_ae(mut self, value: bool) -> Self
+    pub unsafe fn with_ae(self, value: bool) -> Self
     {
         self.set_ae(value);
         self
@@ -47,12 +47,12 @@ impl GlobalHbaControl
     /// This is an illegal operation, when CAP.SAM is set.
     /// 
     /// This precondition is not checked by this function.
-    pub unsafe fn set_ae(&mut self, value: bool)
+    pub unsafe fn set_ae(&self, value: bool)
     {
         const SET_MASK: u32 = 1u32 << 31;
         if value
         {
-            self.0 |= SET_MASK;
+            self.0.set(self.0.get() | SET_MASK);
         }
         else
         {
@@ -77,14 +77,14 @@ impl GlobalHbaControl
     }
 
     /// Interrupt Enable
-    pub fn with_ie(mut self, value: bool) -> Self
+    pub fn with_ie(self, value: bool) -> Self
     {
         self.set_ie(value);
         self
     }
 
     /// Interrupt Enable
-    pub fn set_ie(&mut self, value: bool)
+    pub fn set_ie(&self, value: bool)
     {
         // fc: discard the lowest 2 bits from the read value
         // 0: RW1, while not explicitly set, always write 0
@@ -102,7 +102,7 @@ impl GlobalHbaControl
     /// HBA Reset
     /// 
     /// Always writes a true/1, as writing false/0 shall have no effect for the HBA and the HBA is supposed to reset this value to false/0 when it is done resetting.
-    pub fn with_hr(mut self) -> Self
+    pub fn with_hr(self) -> Self
     {
         self.set_hr();
         self
@@ -111,9 +111,9 @@ impl GlobalHbaControl
     /// HBA Reset
     /// 
     /// Always writes a true/1, as writing false/0 shall have no effect for the HBA and the HBA is supposed to reset this value to false/0 when it is done resetting.
-    pub fn set_hr(&mut self)
+    pub fn set_hr(&self)
     {
-        self.0 |= 1;
+        self.0.set(self.0.get() | 1);
     }
 }
 
@@ -20,6 +20,17 @@ impl InterfaceSpeed
 
     /// 6 Gbps
     pub const GEN3: Self = Self(3);
+
+    /// Only the lower 4 bits are kept
+    pub const fn from_raw(value: u8) -> Self
+    {
+        Self(value & 0xf)
+    }
+
+    pub const fn get_raw(self) -> u8
+    {
+        self.0
+    }
 }
 
 impl core::fmt::Binary for InterfaceSpeed
@@ -15,13 +15,25 @@ use core::fmt::{
 pub struct InterruptStatus(Register<u32>);
 impl InterruptStatus
 {
+    /// Every port with a pending interrupt has its bit set
+    pub fn get_raw(&self) -> u32
+    {
+        self.0.get()
+    }
+
+    /// Clears the pending interrupts of all ports set in `value`
+    pub fn clear_raw(&self, value: u32)
+    {
+        self.0.set(value)
+    }
+
     pub fn get(&self, port_index: u8) -> bool
     {
         debug_assert!((0u8..=31u8).contains(&port_index));
         self.0.get() & (1u32 << port_index) != 0u32
     }
 
-    pub fn clear(&mut self, port_index: u8)
+    pub fn clear(&self, port_index: u8)
     {
         debug_assert!((0u8..=31u8).contains(&port_index));
         self.0.set(1u32 << port_index)
@@ -15,6 +15,11 @@ use core::fmt::{
 pub struct PortsImplemented(Register<u32>);
 impl PortsImplemented
 {
+    pub fn get_raw(&self) -> u32
+    {
+        self.0.get()
+    }
+
     pub fn get(&self, idx: u8) -> bool
     {
         debug_assert!((0u8..=31u8).contains(&idx));
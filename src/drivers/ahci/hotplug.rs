@@ -0,0 +1,57 @@
+// NEW
+
+use super::ahci2::{
+    wait_for_port_changes,
+    get_ahci_device
+};
+use crate::synch::spinlock::Spinlock;
+use alloc::vec::Vec;
+
+/// What happened to a disk. The indices are the same as in `on_each_device` (HBA) and `AhciDevice2::ports`.
+/// `pmp` is the port multiplier port of a disk in `PortSlot::pm_ports`, None for one directly attached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortEvent
+{
+    /// A disk was plugged in and identified
+    Connected { hba_idx: usize, port_idx: usize, pmp: Option<u8> },
+    /// The disk is gone, the port is torn down
+    Disconnected { hba_idx: usize, port_idx: usize, pmp: Option<u8> }
+}
+
+static SUBSCRIBERS: Spinlock<Vec<fn(PortEvent)>> = Spinlock::new(Vec::new());
+
+/// `subscriber` is called by the hot-plug worker for every port change from now on.
+/// Disks found during init are not reported, use `on_each_device` for them.
+///
+/// It runs in task context without any AHCI lock held, so it may use the ports.
+pub fn subscribe(subscriber: fn(PortEvent))
+{
+    SUBSCRIBERS.lock().push(subscriber);
+}
+
+/// The task handling PxIS.PCS and PxIS.PRCS, which on_interrupt only records.
+pub(super) extern "C" fn hotplug_worker()
+{
+    loop
+    {
+        for (hba_idx, port_idx) in wait_for_port_changes()
+        {
+            // Only the port is locked, I/O on the others goes on
+            let events = match get_ahci_device(hba_idx)
+            {
+                Some(dev) => dev.on_port_change(hba_idx, port_idx),
+                None => Vec::new()
+            };
+
+            // Copied, so a subscriber may subscribe someone else
+            let subscribers = SUBSCRIBERS.lock().clone();
+            for event in events
+            {
+                for it in &subscribers
+                {
+                    it(event);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,158 @@
+// NEW
+
+/// The interesting parts of the 256 words returned by ATA_CMD_IDENTIFY (ATA8-ACS 7.16)
+#[derive(Debug, Clone)]
+pub struct IdentifyData
+{
+    /// Words 27..=46, byte-swapped ATA string
+    model: [u8; 40],
+    /// Words 10..=19, byte-swapped ATA string
+    serial: [u8; 20],
+    /// Words 23..=26, byte-swapped ATA string
+    firmware: [u8; 8],
+    /// 48 bit LBA (Word 83 Bit 10), otherwise only 28 bit LBA
+    pub lba48: bool,
+    /// Count of user addressable logical sectors (Words 100..=103 or 60..=61)
+    pub sectors: u64,
+    /// Maximum count of NCQ commands in flight (Word 75), 0 if NCQ is not supported (Word 76 Bit 8)
+    pub queue_depth: u8,
+    /// DATA SET MANAGEMENT with the TRIM bit (Word 169 Bit 0)
+    pub trim: bool,
+    /// Maximum count of 512 byte blocks of LBA ranges in a single DATA SET MANAGEMENT command (Word 105), 0 if not reported
+    pub dsm_max_blocks: u16,
+    /// Word 82 Bit 5
+    pub write_cache_supported: bool,
+    /// Word 85 Bit 5
+    pub write_cache_enabled: bool,
+    /// Word 82 Bit 0
+    pub smart_supported: bool,
+    /// Word 85 Bit 0
+    pub smart_enabled: bool,
+    /// Device Sleep (Word 78 Bit 8)
+    pub devsleep: bool,
+    /// In bytes (Word 106, Words 117..=118), 512 if the device does not report it
+    pub logical_sector_size: u32,
+    /// In bytes (Word 106), the logical sector size if the device does not report it
+    pub physical_sector_size: u32,
+}
+
+impl IdentifyData
+{
+    pub fn parse(words: &[u16; 256]) -> Self
+    {
+        // Words containing feature bits are not valid, if they are all 0 or all 1
+        let valid = |idx: usize| words[idx] != 0 && words[idx] != 0xff_ff;
+        let bit = |idx: usize, bit: u16| valid(idx) && words[idx] & (1u16 << bit) != 0;
+
+        let sectors48 =
+            words[100] as u64
+            | ((words[101] as u64) << 16)
+            | ((words[102] as u64) << 32)
+            | ((words[103] as u64) << 48);
+        let lba48 = bit(83, 10) && sectors48 != 0;
+        let sectors = if lba48
+        {
+            sectors48
+        }
+        else
+        {
+            words[60] as u64 | ((words[61] as u64) << 16)
+        };
+
+        let queue_depth = if bit(76, 8) { (words[75] & 0x1f) as u8 + 1 } else { 0 };
+
+        // Word 106 is valid, if bit 14 is set and bit 15 is cleared
+        let word106 = if words[106] & 0xc0_00 == 0x40_00 { words[106] } else { 0 };
+        // Bit 12: the logical sector is longer than 256 words, its size in words is in 117..=118.
+        // A device claiming that, but reporting less (usually 0), gets the 512 bytes every other device has.
+        let logical_sector_size = if word106 & (1u16 << 12) != 0
+        {
+            (words[117] as u32 | ((words[118] as u32) << 16)).checked_mul(2).filter(|it| *it >= 512).unwrap_or(512)
+        }
+        else
+        {
+            512
+        };
+        // Bit 13: multiple logical sectors per physical sector, Bits 0..=3: log2 of that count
+        let physical_sector_size = if word106 & (1u16 << 13) != 0
+        {
+            logical_sector_size << (word106 & 0xf)
+        }
+        else
+        {
+            logical_sector_size
+        };
+
+        Self {
+            model: Self::ata_string(&words[27..=46]),
+            serial: Self::ata_string(&words[10..=19]),
+            firmware: Self::ata_string(&words[23..=26]),
+            lba48,
+            sectors,
+            queue_depth,
+            trim: bit(169, 0),
+            dsm_max_blocks: if words[105] == 0xff_ff { 0 } else { words[105] },
+            write_cache_supported: bit(82, 5),
+            write_cache_enabled: bit(85, 5),
+            smart_supported: bit(82, 0),
+            smart_enabled: bit(85, 0),
+            devsleep: bit(78, 8),
+            logical_sector_size,
+            physical_sector_size
+        }
+    }
+
+    /// ATA strings store the first character in the high byte of each word
+    fn ata_string<const N: usize>(words: &[u16]) -> [u8; N]
+    {
+        debug_assert_eq!(words.len() * 2, N);
+        let mut output = [0u8; N];
+        for (i, word) in words.iter().enumerate()
+        {
+            output[i * 2] = (word >> 8) as u8;
+            output[i * 2 + 1] = *word as u8;
+        }
+        output
+    }
+
+    /// Strings are padded with spaces, which are cut off here
+    fn as_str(bytes: &[u8]) -> &str
+    {
+        core::str::from_utf8(bytes).unwrap_or("").trim()
+    }
+
+    pub fn model(&self) -> &str
+    {
+        Self::as_str(&self.model)
+    }
+
+    pub fn serial(&self) -> &str
+    {
+        Self::as_str(&self.serial)
+    }
+
+    pub fn firmware(&self) -> &str
+    {
+        Self::as_str(&self.firmware)
+    }
+
+    /// In bytes
+    pub fn capacity(&self) -> u64
+    {
+        self.sectors * self.logical_sector_size as u64
+    }
+}
+
+#[cfg(not(target_os = "none"))]
+#[test]
+fn long_logical_sector_without_size()
+{
+    let mut words = [0u16; 256];
+    // Word 106 valid, Bit 12 set, but Words 117..=118 left at 0
+    words[106] = 0x40_00 | (1u16 << 12);
+    assert_eq!(IdentifyData::parse(&words).logical_sector_size, 512);
+
+    // 2048 words: 4 KiB native
+    words[117] = 2048;
+    assert_eq!(IdentifyData::parse(&words).logical_sector_size, 4096);
+}
@@ -70,16 +70,16 @@ macro_rules! define_register
 
         paste::paste!(
             #[doc = $name_long]
-            pub fn [<set_ $name_short>](&mut self, value: bool)
+            pub fn [<set_ $name_short>](&self, value: bool)
             {
                 const MASK: u32 = 1u32 << $bit;
                 if value
                 {
-                    self.0 |= MASK;
+                    self.0.set(self.0.get() | MASK);
                 }
                 else
                 {
-                    self.0 &= !MASK;
+                    self.0.set(self.0.get() & !MASK);
                 }
             }
         );
@@ -98,7 +98,7 @@ macro_rules! define_register
 
         paste::paste!(
             #[doc = $name_long]
-            pub fn [<clear_ $name_short>](&mut self)
+            pub fn [<clear_ $name_short>](&self)
             {
                 self.0.set(1u32 << $bit);
             }
@@ -118,10 +118,10 @@ macro_rules! define_register
 
         paste::paste!(
             #[doc = $name_long]
-            pub fn [<set_ $name_short>](&mut self)
+            pub fn [<set_ $name_short>](&self)
             {
                 // This is in a register, where RW are present
-                self.0 |= 1u32 << $bit;
+                self.0.set(self.0.get() | 1u32 << $bit);
             }
         );
 
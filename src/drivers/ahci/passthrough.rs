@@ -0,0 +1,129 @@
+// NEW
+
+// ATA pass-through: a taskfile built by somebody else (hdparm-like tools, SMART readers, secure erase...) sent as is.
+// The driver only checks, that the command fits handle_fis (non queued, no PACKET, no control FIS)
+// and whether it may destroy data or change the device, which has to be asked for explicitly.
+
+use super::fis::RegH2D;
+
+/// Which way the data of a pass-through command goes
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataDirection
+{
+    /// Non-data command, the buffer has to be empty
+    None = 0,
+    /// Device to memory (reads)
+    FromDevice = 1,
+    /// Memory to device (writes)
+    ToDevice = 2
+}
+
+impl DataDirection
+{
+    pub fn from_raw(value: u32) -> Option<Self>
+    {
+        match value
+        {
+            0 => Some(Self::None),
+            1 => Some(Self::FromDevice),
+            2 => Some(Self::ToDevice),
+            _ => None
+        }
+    }
+}
+
+/// The registers after a pass-through command completed.
+///
+/// Status and Error are the ones of PxTFD, so they are current for every protocol.
+/// LBA, Count and Device are from the last D2H Register FIS received. A PIO data-in command ends without one,
+/// its values may be the ones of an earlier command.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TaskfileResult
+{
+    pub status: u8,
+    pub error: u8,
+    pub device: u8,
+    /// Count 15:0
+    pub count: u16,
+    /// LBA 47:0
+    pub lba: u64
+}
+
+/// Every data transfer is in 512 byte blocks, no matter the logical sector size
+pub const PASSTHROUGH_BLOCK_SIZE: usize = 512;
+
+/// A single PRDT entry, so a buffer needing a bounce buffer still fits a single command
+pub const PASSTHROUGH_MAX_LEN: usize = super::scatter_gather::MAX_PRD_BYTES;
+
+/// The 512 byte blocks a command transfers, according to its Count field.
+/// None for commands using Count for something else (or not at all), e.g. IDENTIFY or SMART.
+pub fn transfer_blocks(fis: &RegH2D) -> Option<usize>
+{
+    let count = fis.countl.get() as usize | (fis.counth.get() as usize) << 8;
+    match fis.command.get()
+    {
+        // 28 bit: 8 bit Count, 0 is 256
+        0x20 | 0x30 // READ SECTOR(S), WRITE SECTOR(S)
+        | 0xc4 | 0xc5 // READ MULTIPLE, WRITE MULTIPLE
+        | 0xc8 | 0xca // READ DMA, WRITE DMA
+            => Some(match count & 0xff { 0 => 256, it => it }),
+        // 48 bit: 16 bit Count, 0 is 65536
+        0x24 | 0x25 | 0x29 // READ SECTOR(S) EXT, READ DMA EXT, READ MULTIPLE EXT
+        | 0x34 | 0x35 | 0x39 // WRITE SECTOR(S) EXT, WRITE DMA EXT, WRITE MULTIPLE EXT
+        | 0x3d | 0xce // WRITE DMA FUA EXT, WRITE MULTIPLE FUA EXT
+        | 0x2f | 0x47 // READ LOG (DMA) EXT, in log pages of 512 bytes
+        | 0x3f | 0x57 // WRITE LOG (DMA) EXT
+        | 0x06 // DATA SET MANAGEMENT, in blocks of 512 bytes of ranges
+            => Some(match count { 0 => 65536, it => it }),
+        _ => None
+    }
+}
+
+/// Commands handle_fis cannot run:
+/// queued ones (NCQ) need PxSACT, PACKET needs the ACMD (and an ATAPI device) and DEVICE RESET is a reset, not a command.
+pub fn is_unsupported_command(command: u8) -> bool
+{
+    matches!(command,
+        0x08 // DEVICE RESET
+        | 0x60 // READ FPDMA QUEUED
+        | 0x61 // WRITE FPDMA QUEUED
+        | 0x63 // NCQ NON-DATA
+        | 0x64 // SEND FPDMA QUEUED
+        | 0x65 // RECEIVE FPDMA QUEUED
+        | 0xa0 // PACKET
+    )
+}
+
+/// Only commands known to neither write the media nor change the settings of the device run without the privileged flag.
+/// Everything else (writes, DATA SET MANAGEMENT, SECURITY, SANITIZE, DOWNLOAD MICROCODE, SET FEATURES, SET MAX, opcodes I never heard of...)
+/// needs it.
+pub fn is_privileged_command(fis: &RegH2D) -> bool
+{
+    match fis.command.get()
+    {
+        0x00 // NOP
+        | 0x20 | 0x24 | 0x25 | 0x29 // READ SECTOR(S) (EXT), READ DMA EXT, READ MULTIPLE EXT
+        | 0x27 // READ NATIVE MAX ADDRESS EXT
+        | 0x2f // READ LOG EXT
+        | 0x40 | 0x42 // READ VERIFY SECTOR(S) (EXT)
+        | 0x47 // READ LOG DMA EXT
+        | 0x90 // EXECUTE DEVICE DIAGNOSTIC
+        | 0xc4 | 0xc8 // READ MULTIPLE, READ DMA
+        | 0xe0 | 0xe1 | 0xe2 | 0xe3 // STANDBY IMMEDIATE, IDLE IMMEDIATE, STANDBY, IDLE
+        | 0xe5 // CHECK POWER MODE
+        | 0xe7 | 0xea // FLUSH CACHE (EXT)
+        | 0xec | 0xa1 // IDENTIFY DEVICE, IDENTIFY PACKET DEVICE
+        | 0xf8 // READ NATIVE MAX ADDRESS
+            => false,
+        // SMART: reading and the self-tests are fine, writing logs and switching features on or off is not
+        0xb0 => !matches!(fis.featurel.get(),
+            0xd0 // READ DATA
+            | 0xd1 // READ THRESHOLDS
+            | 0xd4 // EXECUTE OFF-LINE IMMEDIATE
+            | 0xd5 // READ LOG
+            | 0xda // RETURN STATUS
+        ),
+        _ => true
+    }
+}
@@ -8,6 +8,7 @@ use interrupt_enable::*;
 
 mod command;
 use command::*;
+pub use command::IccState;
 
 use crate::{
     arch::x86_64::{
@@ -286,7 +287,7 @@ impl Port
             cmd_table.zeroed();
             cmd_table.prdt[0].set(super::fis::PhysicalRegionDescriptorTable::new((dst as u64) + 2048, true, 512));
 
-            let mut fis = super::fis::RegH2D::default();
+            let fis = super::fis::RegH2D::default();
             fis.pmport_cc.set(0x80);
             fis.command.set(0xEC); // ATA_CMD_IDENTIFY
             fis.countl.set(1);
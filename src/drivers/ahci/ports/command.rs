@@ -134,7 +134,7 @@ impl Command
     }
 
     /// Interface Communication Control
-    pub fn set_icc(&mut self, value: IccState)
+    pub fn set_icc(&self, value: IccState)
     {
         const MASK: u32 = Command::adjust_mask_for_set(0x0f_ff_ff_ffu32);
         self.0.set((self.0.get() & MASK) | ((value.0 as u32) << 28));
@@ -149,7 +149,7 @@ impl Command
     /// Aggressive Slumber/Partial
     /// 
     /// Unsafe Note: If CAP.SALP is 0, this bit is readonly reserved
-    pub unsafe fn set_asp(&mut self, value: bool)
+    pub unsafe fn set_asp(&self, value: bool)
     {
         const MASK: u32 = Command::adjust_mask_for_set(!(1u32 << 27));
         self.0.set((self.0.get() & MASK) | if value { 1u32 << 27 } else { 0 });
@@ -164,7 +164,7 @@ impl Command
     /// Aggressive Link Power Management Enable
     /// 
     /// Unsafe Note: If CAP.SALP is set to 0, this bit is readonly reserved
-    pub unsafe fn set_alpe(&mut self, value: bool)
+    pub unsafe fn set_alpe(&self, value: bool)
     {
         const MASK: u32 = Command::adjust_mask_for_set(!(1u32 << 26));
         self.0.set((self.0.get() & MASK) | if value { 1u32 << 26 } else { 0 });
@@ -177,7 +177,7 @@ impl Command
     }
 
     /// Drive LED on ATAPI Enable
-    pub fn set_dlae(&mut self, value: bool)
+    pub fn set_dlae(&self, value: bool)
     {
         const BIT_INDEX: u8 = 25;
         const MASK: u32 = Command::adjust_mask_for_set(!(1u32 << BIT_INDEX));
@@ -191,7 +191,7 @@ impl Command
     }
 
     /// Device is ATAPI
-    pub fn set_atapi(&mut self, value: bool)
+    pub fn set_atapi(&self, value: bool)
     {
         const BIT_INDEX: u8 = 24;
         const MASK: u32 = Command::adjust_mask_for_set(!(1u32 << BIT_INDEX));
@@ -207,7 +207,7 @@ impl Command
     /// Automatic Partial to Slumber Transitions Enabled
     /// 
     /// Unsafe Note: If CAP2.APST is 0, this bit is reserved
-    pub unsafe fn set_apste(&mut self, value: bool)
+    pub unsafe fn set_apste(&self, value: bool)
     {
         const BIT_INDEX: u8 = 23;
         const MASK: u32 = Command::adjust_mask_for_set(!(1u32 << BIT_INDEX));
@@ -253,7 +253,7 @@ impl Command
     /// Port Multiplier Attached
     /// 
     /// Unsafe Note: PxCMD.ST shall be 0 when this bit is set to 1
-    pub unsafe fn set_pma(&mut self, value: bool)
+    pub unsafe fn set_pma(&self, value: bool)
     {
         const BIT_INDEX: u8 = 17;
         const MASK: u32 = Command::adjust_mask_for_set(!(1u32 << BIT_INDEX));
@@ -299,7 +299,7 @@ impl Command
     }
 
     /// FIS Receive Enable
-    pub fn set_fre(&mut self, value: bool)
+    pub fn set_fre(&self, value: bool)
     {
         const BIT_INDEX: u8 = 4;
         const MASK: u32 = Command::adjust_mask_for_set(!(1u32 << BIT_INDEX));
@@ -313,10 +313,10 @@ impl Command
     }
 
     /// Command List Override
-    pub fn set_clo(&mut self)
+    pub fn set_clo(&self)
     {
         const ALL_OKAY: bool = Command::adjust_mask_for_set(0x8) == 0;
-        assert!(ALL_OKAY, "The adjusted Mask for PxCMD changed. drivers::ahci::ports::Command::set_clo(&mut self) needs adjustments!");
+        assert!(ALL_OKAY, "The adjusted Mask for PxCMD changed. drivers::ahci::ports::Command::set_clo(&self) needs adjustments!");
         self.0.set(self.0.get() | 0x8);
     }
 
@@ -329,7 +329,7 @@ impl Command
     /// Power On Device
     /// 
     /// Unsafe Note: If PxCMD.CPD is 0, this field is read only
-    pub unsafe fn set_pod(&mut self, value: bool)
+    pub unsafe fn set_pod(&self, value: bool)
     {
         const BIT_INDEX: u8 = 2;
         const MASK: u32 = Command::adjust_mask_for_set(!(1u32 << BIT_INDEX));
@@ -345,7 +345,7 @@ impl Command
     /// Spin-Ud Device
     /// 
     /// Unsafe Note: Read Only 1 if CAP.SSS is unset.
-    pub unsafe fn set_sud(&mut self, value: bool)
+    pub unsafe fn set_sud(&self, value: bool)
     {
         const BIT_INDEX: u8 = 1;
         const MASK: u32 = Command::adjust_mask_for_set(!(1u32 << BIT_INDEX));
@@ -359,7 +359,7 @@ impl Command
     }
 
     /// Start
-    pub fn set_st(&mut self, value: bool)
+    pub fn set_st(&self, value: bool)
     {
         const BIT_INDEX: u8 = 0;
         const MASK: u32 = Command::adjust_mask_for_set(!(1u32 << BIT_INDEX));
@@ -35,16 +35,16 @@ impl InterruptEnable
     /// Cold Port Detect Status
     /// 
     /// Unsafe Note: If cold presence is not supported, this field is read only, check PxCMD.CPD
-    pub unsafe fn set_cpds(&mut self, value: bool)
+    pub unsafe fn set_cpds(&self, value: bool)
     {
         const MASK: u32 = 1u32 << 1;
         if value
         {
-            self.0 |= MASK;
+            self.0.set(self.0.get() | MASK);
         }
         else
         {
-            self.0 &= !MASK;
+            self.0.set(self.0.get() & !MASK);
         }
     }
 
@@ -55,16 +55,16 @@ impl InterruptEnable
     }
 
     /// Task File Error Status
-    pub fn set_tfes(&mut self, value: bool)
+    pub fn set_tfes(&self, value: bool)
     {
         const MASK: u32 = 1u32 << 30;
         if value
         {
-            self.0 |= MASK;
+            self.0.set(self.0.get() | MASK);
         }
         else
         {
-            self.0 &= !MASK;
+            self.0.set(self.0.get() & !MASK);
         }
     }
 
@@ -75,16 +75,16 @@ impl InterruptEnable
     }
 
     /// Host Bus Fatal Error Status
-    pub fn set_hbfs(&mut self, value: bool)
+    pub fn set_hbfs(&self, value: bool)
     {
         const MASK: u32 = 1u32 << 29;
         if value
         {
-            self.0 |= MASK;
+            self.0.set(self.0.get() | MASK);
         }
         else
         {
-            self.0 &= !MASK;
+            self.0.set(self.0.get() & !MASK);
         }
     }
 
@@ -95,16 +95,16 @@ impl InterruptEnable
     }
 
     /// Host Bus Data Error Status
-    pub fn set_hdbs(&mut self, value: bool)
+    pub fn set_hdbs(&self, value: bool)
     {
         const MASK: u32 = 1u32 << 28;
         if value
         {
-            self.0 |= MASK;
+            self.0.set(self.0.get() | MASK);
         }
         else
         {
-            self.0 &= !MASK;
+            self.0.set(self.0.get() & !MASK);
         }
     }
 
@@ -115,16 +115,16 @@ impl InterruptEnable
     }
 
     /// Interface Fatal Error Status
-    pub fn set_ifs(&mut self, value: bool)
+    pub fn set_ifs(&self, value: bool)
     {
         const MASK: u32 = 1u32 << 27;
         if value
         {
-            self.0 |= MASK;
+            self.0.set(self.0.get() | MASK);
         }
         else
         {
-            self.0 &= !MASK;
+            self.0.set(self.0.get() & !MASK);
         }
     }
 
@@ -135,16 +135,16 @@ impl InterruptEnable
     }
 
     /// Interface Non-fatal Error Status
-    pub fn set_infs(&mut self, value: bool)
+    pub fn set_infs(&self, value: bool)
     {
         const MASK: u32 = 1u32 << 26;
         if value
         {
-            self.0 |= MASK;
+            self.0.set(self.0.get() | MASK);
         }
         else
         {
-            self.0 &= !MASK;
+            self.0.set(self.0.get() & !MASK);
         }
     }
 
@@ -155,16 +155,16 @@ impl InterruptEnable
     }
 
     /// Overflow Status
-    pub fn set_ofs(&mut self, value: bool)
+    pub fn set_ofs(&self, value: bool)
     {
         const MASK: u32 = 1u32 << 24;
         if value
         {
-            self.0 |= MASK;
+            self.0.set(self.0.get() | MASK);
         }
         else
         {
-            self.0 &= !MASK;
+            self.0.set(self.0.get() & !MASK);
         }
     }
 
@@ -175,16 +175,16 @@ impl InterruptEnable
     }
 
     /// Incorrect Port Multiplier Status
-    pub fn set_ipms(&mut self, value: bool)
+    pub fn set_ipms(&self, value: bool)
     {
         const MASK: u32 = 1u32 << 23;
         if value
         {
-            self.0 |= MASK;
+            self.0.set(self.0.get() | MASK);
         }
         else
         {
-            self.0 &= !MASK;
+            self.0.set(self.0.get() & !MASK);
         }
     }
 
@@ -195,16 +195,16 @@ impl InterruptEnable
     }
 
     /// PhyRdy Change Interrupt Enable
-    pub fn set_prce(&mut self, value: bool)
+    pub fn set_prce(&self, value: bool)
     {
         const MASK: u32 = 1u32 << 22;
         if value
         {
-            self.0 |= MASK;
+            self.0.set(self.0.get() | MASK);
         }
         else
         {
-            self.0 &= !MASK;
+            self.0.set(self.0.get() & !MASK);
         }
     }
 
@@ -217,16 +217,16 @@ impl InterruptEnable
     /// Device Mechanical Presence Status
     /// 
     /// Unsafe Note: If Mechanical Presence Switch is not supported, this field is read only. See CAP.SMPS
-    pub unsafe fn set_dmps(&mut self, value: bool)
+    pub unsafe fn set_dmps(&self, value: bool)
     {
         const MASK: u32 = 1u32 << 7;
         if value
         {
-            self.0 |= MASK;
+            self.0.set(self.0.get() | MASK);
         }
         else
         {
-            self.0 &= !MASK;
+            self.0.set(self.0.get() & !MASK);
         }
     }
 
@@ -237,16 +237,16 @@ impl InterruptEnable
     }
 
     /// Port Change Interrupt Enable
-    pub fn set_pce(&mut self, value: bool)
+    pub fn set_pce(&self, value: bool)
     {
         const MASK: u32 = 1u32 << 6;
         if value
         {
-            self.0 |= MASK;
+            self.0.set(self.0.get() | MASK);
         }
         else
         {
-            self.0 &= !MASK;
+            self.0.set(self.0.get() & !MASK);
         }
     }
 
@@ -257,16 +257,16 @@ impl InterruptEnable
     }
 
     /// Descriptor Processed
-    pub fn set_dps(&mut self, value: bool)
+    pub fn set_dps(&self, value: bool)
     {
         const MASK: u32 = 1u32 << 5;
         if value
         {
-            self.0 |= MASK;
+            self.0.set(self.0.get() | MASK);
         }
         else
         {
-            self.0 &= !MASK;
+            self.0.set(self.0.get() & !MASK);
         }
     }
 
@@ -276,16 +276,16 @@ impl InterruptEnable
         self.0.get() & (1u32 << 4) != 0
     }
 
-    pub fn set_ufe(&mut self, value: bool)
+    pub fn set_ufe(&self, value: bool)
     {
         const MASK: u32 = 1u32 << 4;
         if value
         {
-            self.0 |= MASK;
+            self.0.set(self.0.get() | MASK);
         }
         else
         {
-            self.0 &= !MASK;
+            self.0.set(self.0.get() & !MASK);
         }
     }
 
@@ -296,16 +296,16 @@ impl InterruptEnable
     }
 
     /// Set Device Bits Interrupt
-    pub fn set_sdbs(&mut self, value: bool)
+    pub fn set_sdbs(&self, value: bool)
     {
         const MASK: u32 = 1u32 << 3;
         if value
         {
-            self.0 |= MASK;
+            self.0.set(self.0.get() | MASK);
         }
         else
         {
-            self.0 &= !MASK;
+            self.0.set(self.0.get() & !MASK);
         }
     }
 
@@ -316,16 +316,16 @@ impl InterruptEnable
     }
 
     /// DMA Setup FIS Interrupt
-    pub fn set_dss(&mut self, value: bool)
+    pub fn set_dss(&self, value: bool)
     {
         const MASK: u32 = 1u32 << 2;
         if value
         {
-            self.0 |= MASK;
+            self.0.set(self.0.get() | MASK);
         }
         else
         {
-            self.0 &= !MASK;
+            self.0.set(self.0.get() & !MASK);
         }
     }
 
@@ -336,16 +336,16 @@ impl InterruptEnable
     }
 
     /// PIO Setup FIS Interrupt
-    pub fn set_pss(&mut self, value: bool)
+    pub fn set_pss(&self, value: bool)
     {
         const MASK: u32 = 1u32 << 1;
         if value
         {
-            self.0 |= MASK;
+            self.0.set(self.0.get() | MASK);
         }
         else
         {
-            self.0 &= !MASK;
+            self.0.set(self.0.get() & !MASK);
         }
     }
 
@@ -356,16 +356,16 @@ impl InterruptEnable
     }
 
     /// Device to Host Register FIS Interrupt
-    pub fn set_dhrs(&mut self, value: bool)
+    pub fn set_dhrs(&self, value: bool)
     {
         const MASK: u32 = 1u32;
         if value
         {
-            self.0 |= MASK;
+            self.0.set(self.0.get() | MASK);
         }
         else
         {
-            self.0 &= !MASK;
+            self.0.set(self.0.get() & !MASK);
         }
     }
 }
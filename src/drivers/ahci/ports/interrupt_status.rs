@@ -29,12 +29,20 @@ impl InterruptStatus
     /// Clears all the bits, which are not resereved and are cleared through a write of 1.
     /// 
     /// Notable exception (unexhaustive list): PhyRdy Change Status (PRCS): Cleared by `PxSERR.DIAG.N`
-    pub fn clear_all(&mut self)
+    pub fn clear_all(&self)
     {
         // All clearable (Type RWC) bits are 1
         self.0.set(0xfd_80_00_afu32);
     }
 
+    /// Clears the bits set in `value`, ignoring every bit which is not cleared through a write of 1.
+    /// 
+    /// Meant to acknowledge exactly the interrupts read through `get_raw`, without losing one which arrived in between.
+    pub fn clear_raw(&self, value: u32)
+    {
+        self.0.set(value & 0xfd_80_00_afu32);
+    }
+
     /// Cold Port Detect Status
     pub fn get_cpds(&self) -> bool
     {
@@ -42,7 +50,7 @@ impl InterruptStatus
     }
 
     /// Cold Port Detect Status
-    pub fn clear_cpds(&mut self)
+    pub fn clear_cpds(&self)
     {
         // TODO: Test If Implementation is correct
         self.0.set(1u32 << 31);
@@ -55,7 +63,7 @@ impl InterruptStatus
     }
 
     /// Task File Error Status
-    pub fn clear_tfes(&mut self)
+    pub fn clear_tfes(&self)
     {
         self.0.set(1u32 << 30);
     }
@@ -67,7 +75,7 @@ impl InterruptStatus
     }
 
     /// Host Bus Fatal Error Status
-    pub fn clear_hbfs(&mut self)
+    pub fn clear_hbfs(&self)
     {
         self.0.set(1u32 << 29);
     }
@@ -79,7 +87,7 @@ impl InterruptStatus
     }
 
     /// Host Bus Data Error Status
-    pub fn clear_hdbs(&mut self)
+    pub fn clear_hdbs(&self)
     {
         self.0.set(1u32 << 28);
     }
@@ -91,7 +99,7 @@ impl InterruptStatus
     }
 
     /// Interface Fatal Error Status
-    pub fn clear_ifs(&mut self)
+    pub fn clear_ifs(&self)
     {
         self.0.set(1u32 << 27);
     }
@@ -103,7 +111,7 @@ impl InterruptStatus
     }
 
     /// Interface Non-fatal Error Status
-    pub fn clear_infs(&mut self)
+    pub fn clear_infs(&self)
     {
         self.0.set(1u32 << 26);
     }
@@ -115,7 +123,7 @@ impl InterruptStatus
     }
 
     /// Overflow Status
-    pub fn clear_ofs(&mut self)
+    pub fn clear_ofs(&self)
     {
         self.0.set(1u32 << 24);
     }
@@ -127,7 +135,7 @@ impl InterruptStatus
     }
 
     /// Incorrect Port Multiplier Status
-    pub fn clear_imps(&mut self)
+    pub fn clear_imps(&self)
     {
         self.0.set(1u32 << 23);
     }
@@ -145,7 +153,7 @@ impl InterruptStatus
     }
 
     /// Device Mechanical Presence Status
-    pub fn clear_dmps(&mut self)
+    pub fn clear_dmps(&self)
     {
         // Thinking about it:
         // Does this really need to be exposed?
@@ -168,7 +176,7 @@ impl InterruptStatus
     }
 
     /// Descriptor Processed
-    pub fn clear_dps(&mut self)
+    pub fn clear_dps(&self)
     {
         self.0.set(1u32 << 5);
     }
@@ -186,7 +194,7 @@ impl InterruptStatus
     }
 
     /// Set Device Bits Interrupt
-    pub fn clear_sdbs(&mut self)
+    pub fn clear_sdbs(&self)
     {
         self.0.set(1u32 << 3);
     }
@@ -198,7 +206,7 @@ impl InterruptStatus
     }
 
     /// DMA Setup FIS Interrupt
-    pub fn clear_dss(&mut self)
+    pub fn clear_dss(&self)
     {
         self.0.set(1u32 << 2);
     }
@@ -210,7 +218,7 @@ impl InterruptStatus
     }
 
     /// PIO Setup FIS Interrupt
-    pub fn clear_pss(&mut self)
+    pub fn clear_pss(&self)
     {
         self.0.set(1u32 << 1);
     }
@@ -222,7 +230,7 @@ impl InterruptStatus
     }
 
     /// Device to Host Register FIS Interrupt
-    pub fn clear_dhrs(&mut self)
+    pub fn clear_dhrs(&self)
     {
         self.0.set(1u32);
     }
@@ -0,0 +1,112 @@
+// NEW
+
+use super::InterfaceSpeed;
+
+use core::fmt::{
+    Result,
+    Formatter,
+    Display
+};
+
+/// The deepest link power state a port may enter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkPowerState
+{
+    /// Never leave the active state (what the port is set up with)
+    Active,
+    Partial,
+    /// Partial is allowed as well
+    Slumber,
+    /// Partial and Slumber are allowed as well
+    DevSleep
+}
+
+/// How a port manages the power of its link
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PowerPolicy
+{
+    pub deepest: LinkPowerState,
+    /// Aggressive Link Power Management: the HBA enters the low power state on its own, as soon as the port is idle.
+    /// Otherwise only the device or `enter_link_state` trigger a transition.
+    pub aggressive: bool
+}
+
+impl PowerPolicy
+{
+    pub const ACTIVE: Self = Self { deepest: LinkPowerState::Active, aggressive: false };
+}
+
+/// PxSSTS.IPM: Interface Power Management
+///
+/// Like InterfaceSpeed a struct, as the reserved values may still show up.
+#[repr(transparent)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterfacePowerState(pub u8);
+impl InterfacePowerState
+{
+    /// No device or no communication established
+    pub const NOT_PRESENT: Self = Self(0);
+    pub const ACTIVE: Self = Self(1);
+    pub const PARTIAL: Self = Self(2);
+    pub const SLUMBER: Self = Self(6);
+    pub const DEVSLEEP: Self = Self(8);
+}
+
+impl Display for InterfacePowerState
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result
+    {
+        match *self
+        {
+            Self::NOT_PRESENT => write!(f, "Not Present"),
+            Self::ACTIVE => write!(f, "Active"),
+            Self::PARTIAL => write!(f, "Partial"),
+            Self::SLUMBER => write!(f, "Slumber"),
+            Self::DEVSLEEP => write!(f, "DevSleep"),
+            Self(it) => write!(f, "Reserved/Unknown (Value: {:x})", it)
+        }
+    }
+}
+
+/// The state of the link, as reported by PxSSTS
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LinkStatus
+{
+    /// DET: 0 no device, 1 device but no communication, 3 device and communication, 4 offline
+    pub detection: u8,
+    /// SPD: the negotiated speed, 0 without a link
+    pub speed: InterfaceSpeed,
+    pub power: InterfacePowerState
+}
+
+impl LinkStatus
+{
+    pub fn from_ssts(ssts: u32) -> Self
+    {
+        Self {
+            detection: (ssts & 0xf) as u8,
+            speed: InterfaceSpeed::from_raw(((ssts >> 4) & 0xf) as u8),
+            power: InterfacePowerState(((ssts >> 8) & 0xf) as u8)
+        }
+    }
+
+    pub fn is_up(&self) -> bool
+    {
+        self.detection == 3
+    }
+}
+
+impl Display for LinkStatus
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result
+    {
+        if self.is_up()
+        {
+            write!(f, "Up, {}, {}", self.speed, self.power)
+        }
+        else
+        {
+            write!(f, "Down (DET {:x})", self.detection)
+        }
+    }
+}
@@ -0,0 +1,73 @@
+// NEW
+
+use crate::arch::x86_64::mm::paging::{
+    self,
+    BasePageSize,
+    PageSize
+};
+
+/// A single PRDT entry can describe up to 4 MiB (22 bit Data Byte Count)
+pub const MAX_PRD_BYTES: usize = 0x40_00_00;
+
+/// Splits a virtually contiguous buffer into physically contiguous runs, one per PRDT entry:
+/// (physical address, length in bytes)
+///
+/// Neighbouring pages are merged, if they are neighbours in physical memory as well, up to MAX_PRD_BYTES.
+/// As the buffer and its length are even, so is every run.
+pub struct PhysicalRuns<F>
+    where F: Fn(usize) -> usize
+{
+    virt: usize,
+    remaining: usize,
+    translate: F
+}
+
+impl PhysicalRuns<fn(usize) -> usize>
+{
+    /// For buffers mapped in the current page table
+    pub fn new(virt: usize, len: usize) -> Self
+    {
+        Self::with_translation(virt, len, paging::get_physical_address::<BasePageSize>)
+    }
+}
+
+impl<F> PhysicalRuns<F>
+    where F: Fn(usize) -> usize
+{
+    /// `translate` turns a virtual address into a physical one
+    pub fn with_translation(virt: usize, len: usize, translate: F) -> Self
+    {
+        Self { virt, remaining: len, translate }
+    }
+}
+
+impl<F> Iterator for PhysicalRuns<F>
+    where F: Fn(usize) -> usize
+{
+    type Item = (u64, u32);
+
+    fn next(&mut self) -> Option<Self::Item>
+    {
+        if self.remaining == 0
+        {
+            return None;
+        }
+
+        let phys = (self.translate)(self.virt);
+        // Up to the end of the first page
+        let mut len = (BasePageSize::SIZE - (self.virt & (BasePageSize::SIZE - 1))).min(self.remaining);
+        while len < self.remaining && len < MAX_PRD_BYTES
+        {
+            // self.virt + len is page aligned here
+            if (self.translate)(self.virt + len) != phys + len
+            {
+                break;
+            }
+            len = (len + BasePageSize::SIZE).min(self.remaining).min(MAX_PRD_BYTES);
+        }
+
+        self.virt += len;
+        self.remaining -= len;
+        Some((phys as u64, len as u32))
+    }
+}
@@ -0,0 +1,310 @@
+// NEW
+
+// A disk self-test, which leaves the data on the disk as it was:
+// READ VERIFY over (a part of) the disk, then writing a pattern to a scratch region picked by whoever runs it,
+// reading it back, comparing and restoring the original data. Sector 0 (and with it the partition table) is never written.
+
+use super::{
+    ahci2::AhciPort2,
+    on_each_port,
+    with_port,
+    DiskError
+};
+use crate::arch::x86_64::kernel::{
+    fw_cfg::boot_parameter,
+    get_ticks
+};
+use alloc::{
+    string::String,
+    vec::Vec,
+    vec
+};
+use core::fmt::{
+    Display,
+    Formatter,
+    Result
+};
+
+/// The boot parameter to run the self-test on boot (see `arch::x86_64::kernel::fw_cfg`), e.g. `-fw_cfg name=opt/eduos/selftest,string=2048,,64`:
+/// "<first scratch sector>,<scratch sectors>[,<sectors to verify>]". Without the last one the whole disk is verified.
+pub const BOOT_PARAMETER: &str = "opt/eduos/selftest";
+
+/// The scratch region is handled in pieces of this size, so the buffers stay small
+const CHUNK_BYTES: usize = 64 * 1024;
+
+/// Sectors verified at once. The port is locked per chunk (of either step), so the disk stays usable during the test.
+const VERIFY_CHUNK_SECTORS: u64 = 64 * 1024;
+
+/// (HBA index, Port index, port multiplier port) of a disk under test
+type Location = (usize, usize, Option<u8>);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SelfTest
+{
+    /// First sector of the scratch region, never 0
+    pub scratch_lba: u64,
+    /// Length of the scratch region in logical sectors, 0 skips the read/write/compare
+    pub scratch_sectors: u64,
+    /// Sectors to verify from sector 0 on, None for the whole disk
+    pub verify_sectors: Option<u64>
+}
+
+/// Why a step of the self-test failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SelfTestError
+{
+    Disk(DiskError),
+    /// The sector did not read back, what was written to it
+    Mismatch { lba: u64 },
+    /// The original data could not be written back, the sectors from `lba` on may hold the test pattern now.
+    /// `err` is None, if writing it back worked, but it did not read back.
+    RestoreFailed { lba: u64, err: Option<DiskError> },
+    /// The scratch region includes sector 0
+    InvalidScratchRegion
+}
+
+impl From<DiskError> for SelfTestError
+{
+    fn from(err: DiskError) -> Self
+    {
+        Self::Disk(err)
+    }
+}
+
+impl Display for SelfTestError
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result
+    {
+        match self
+        {
+            Self::Disk(err) => write!(f, "{}", err),
+            Self::Mismatch { lba } => write!(f, "Sector {} did not read back what was written", lba),
+            Self::RestoreFailed { lba, err: Some(err) } => write!(f, "Restoring the data from sector {} on failed ({}), it is lost", lba, err),
+            Self::RestoreFailed { lba, err: None } => write!(f, "The data restored from sector {} on did not read back, it is lost", lba),
+            Self::InvalidScratchRegion => write!(f, "The scratch region must not include sector 0")
+        }
+    }
+}
+
+/// A step of the self-test: None if skipped, else its result and how long it took in ms
+pub type StepResult = Option<(core::result::Result<(), SelfTestError>, u64)>;
+
+/// The outcome for a single disk
+#[derive(Debug, Clone)]
+pub struct PortReport
+{
+    pub hba_idx: usize,
+    pub port_idx: usize,
+    pub pmp: Option<u8>,
+    pub model: String,
+    /// READ VERIFY, skipped for ATAPI devices
+    pub verify: StepResult,
+    /// Read/Write/Compare on the scratch region, skipped for read only devices
+    pub read_write: StepResult
+}
+
+impl PortReport
+{
+    /// Every step run passed
+    pub fn passed(&self) -> bool
+    {
+        [&self.verify, &self.read_write].iter().all(|it| matches!(it, None | Some((Ok(()), _))))
+    }
+}
+
+impl Display for PortReport
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result
+    {
+        write!(f, "HBA {}, Port {}", self.hba_idx, self.port_idx)?;
+        if let Some(pmp) = self.pmp
+        {
+            write!(f, ".{}", pmp)?;
+        }
+        write!(f, " ({}): {}", self.model, if self.passed() { "PASS" } else { "FAIL" })?;
+
+        for (name, step) in [("verify", &self.verify), ("read/write/compare", &self.read_write)]
+        {
+            match step
+            {
+                None => write!(f, ", {}: skipped", name)?,
+                Some((Ok(()), ms)) => write!(f, ", {}: ok in {} ms", name, ms)?,
+                Some((Err(err), ms)) => write!(f, ", {}: {} after {} ms", name, err, ms)?
+            }
+        }
+        Ok(())
+    }
+}
+
+impl SelfTest
+{
+    /// The configuration given with BOOT_PARAMETER, None if it is not set (or not understood)
+    pub fn from_boot_parameter() -> Option<Self>
+    {
+        Self::parse(&boot_parameter(BOOT_PARAMETER)?)
+    }
+
+    /// "<first scratch sector>,<scratch sectors>[,<sectors to verify>]", None for anything else or a scratch region starting at 0
+    pub fn parse(config: &str) -> Option<Self>
+    {
+        let mut parts = config.split(',').map(|it| it.trim().parse::<u64>());
+        let scratch_lba = parts.next()?.ok()?;
+        let scratch_sectors = parts.next()?.ok()?;
+        let verify_sectors = match parts.next()
+        {
+            Some(it) => Some(it.ok()?),
+            None => None
+        };
+        if parts.next().is_some() || scratch_lba == 0
+        {
+            return None;
+        }
+        Some(Self { scratch_lba, scratch_sectors, verify_sectors })
+    }
+
+    /// Runs the self-test on every identified disk (port multiplier ports included), one after the other.
+    pub fn run(&self) -> Vec<PortReport>
+    {
+        // Only the list of disks is made with the ports locked, the tests lock them per chunk
+        let mut disks = Vec::new();
+        on_each_port(|hba_idx, port_idx, port| {
+
+            // The port multiplier itself, there is nothing to test
+            if port.identify.is_none() && !port.atapi
+            {
+                return;
+            }
+            let report = PortReport {
+                hba_idx,
+                port_idx,
+                pmp: port.pmp,
+                model: String::from(port.identify.as_ref().map_or("ATAPI", |it| it.model())),
+                verify: None,
+                read_write: None
+            };
+            disks.push((report, port.atapi, port.is_read_only()));
+        });
+
+        disks.into_iter().map(|(mut report, atapi, read_only)| {
+
+            let location = (report.hba_idx, report.port_idx, report.pmp);
+            if !atapi
+            {
+                report.verify = Some(Self::timed(|| self.verify(location)));
+            }
+            if !read_only && self.scratch_sectors != 0
+            {
+                report.read_write = Some(Self::timed(|| self.read_write_compare(location)));
+            }
+            report
+        }).collect()
+    }
+
+    /// Calls `func` with the port locked, PortNotReady if the disk is gone
+    fn with_port<F, R>(location: Location, func: F) -> core::result::Result<R, SelfTestError>
+        where F: FnOnce(&mut AhciPort2) -> core::result::Result<R, SelfTestError>
+    {
+        let (hba_idx, port_idx, pmp) = location;
+        with_port(hba_idx, port_idx, pmp, func).unwrap_or(Err(SelfTestError::Disk(DiskError::PortNotReady)))
+    }
+
+    fn timed<F>(step: F) -> (core::result::Result<(), SelfTestError>, u64)
+        where F: FnOnce() -> core::result::Result<(), SelfTestError>
+    {
+        let start = get_ticks();
+        let result = step();
+        (result, get_ticks() - start)
+    }
+
+    fn verify(&self, location: Location) -> core::result::Result<(), SelfTestError>
+    {
+        let sectors = Self::with_port(location, |port| Ok(port.size / port.logical_sector_size() as u64))?;
+        let end = self.verify_sectors.map_or(sectors, |it| it.min(sectors));
+        let mut lba = 0;
+        while lba < end
+        {
+            let count = (end - lba).min(VERIFY_CHUNK_SECTORS);
+            Self::with_port(location, |port| Ok(port.verify(lba, count)?))?;
+            lba += count;
+        }
+        Ok(())
+    }
+
+    /// Chunk by chunk: backup, write the pattern, read it back and compare, write the backup back.
+    /// The backup is written back after a failed write as well, as a part of the chunk may have been written.
+    fn read_write_compare(&self, location: Location) -> core::result::Result<(), SelfTestError>
+    {
+        if self.scratch_lba == 0
+        {
+            return Err(SelfTestError::InvalidScratchRegion);
+        }
+        let sector_size = Self::with_port(location, |port| Ok(port.logical_sector_size() as usize))?;
+        let chunk_sectors = (CHUNK_BYTES / sector_size).max(1) as u64;
+
+        // u16, so the buffers are 2 byte aligned
+        let mut backup = vec![0u16; chunk_sectors as usize * sector_size / 2];
+        let mut pattern = backup.clone();
+        let mut check = backup.clone();
+
+        let end = self.scratch_lba.checked_add(self.scratch_sectors).ok_or(DiskError::OutOfRange { lba: self.scratch_lba })?;
+        let mut lba = self.scratch_lba;
+        while lba < end
+        {
+            let count = (end - lba).min(chunk_sectors);
+            let words = count as usize * sector_size / 2;
+            let (backup, pattern, check) = (&mut backup[..words], &mut pattern[..words], &mut check[..words]);
+
+            // A chunk is tested and restored under a single lock, so nobody sees the pattern (or writes it in between)
+            Self::with_port(location, |port| {
+
+                port.read_u16(lba, backup)?;
+
+                // Different for every sector (and every chunk), so a write landing on the wrong sector is noticed
+                for (i, it) in pattern.iter_mut().enumerate()
+                {
+                    let sector = lba + (i * 2 / sector_size) as u64;
+                    *it = (sector as u16 ^ 0xa5a5).wrapping_add(i as u16);
+                }
+                let tested = port.write_u16(lba, pattern)
+                    .and_then(|_| port.read_u16(lba, check))
+                    .map_err(SelfTestError::from)
+                    .and_then(|_| match check.iter().zip(pattern.iter()).position(|(a, b)| a != b)
+                    {
+                        Some(i) => Err(SelfTestError::Mismatch { lba: lba + (i * 2 / sector_size) as u64 }),
+                        None => Ok(())
+                    });
+
+                // The original data goes back, no matter how the test went
+                if let Err(err) = port.write_u16(lba, backup).and_then(|_| port.read_u16(lba, check))
+                {
+                    return Err(SelfTestError::RestoreFailed { lba, err: Some(err) });
+                }
+                if check != backup
+                {
+                    return Err(SelfTestError::RestoreFailed { lba, err: None });
+                }
+                tested
+            })?;
+
+            lba += count;
+        }
+        Self::with_port(location, |port| Ok(port.flush()?))
+    }
+}
+
+#[cfg(not(target_os = "none"))]
+#[test]
+fn parse_config()
+{
+    assert_eq!(SelfTest::parse("2048,64"), Some(SelfTest { scratch_lba: 2048, scratch_sectors: 64, verify_sectors: None }));
+    assert_eq!(SelfTest::parse(" 2048, 64 ,1000"), Some(SelfTest { scratch_lba: 2048, scratch_sectors: 64, verify_sectors: Some(1000) }));
+    assert_eq!(SelfTest::parse("2048,0"), Some(SelfTest { scratch_lba: 2048, scratch_sectors: 0, verify_sectors: None }));
+
+    // Sector 0 is never written
+    assert_eq!(SelfTest::parse("0,64"), None);
+    assert_eq!(SelfTest::parse("2048"), None);
+    assert_eq!(SelfTest::parse("2048,64,1000,1"), None);
+    assert_eq!(SelfTest::parse("2048,-64"), None);
+    assert_eq!(SelfTest::parse("2048,64,"), None);
+    assert_eq!(SelfTest::parse(""), None);
+}
@@ -0,0 +1,96 @@
+// NEW
+
+use alloc::vec::Vec;
+
+/// One entry of the SMART attribute table.
+/// Apart from the layout, everything about the attributes is vendor specific.
+#[derive(Debug, Clone, Copy)]
+pub struct SmartAttribute
+{
+    pub id: u8,
+    /// Bit 0: Pre-failure (exceeding the threshold predicts a failure), Bit 1: Updated online
+    pub flags: u16,
+    /// Normalized value, higher is better
+    pub current: u8,
+    /// The lowest normalized value seen so far
+    pub worst: u8,
+    /// From SMART READ THRESHOLDS, 0 if there is none
+    pub threshold: u8,
+    /// 48 bit raw value, its meaning depends on the attribute (and vendor)
+    pub raw: u64
+}
+
+impl SmartAttribute
+{
+    pub fn is_prefailure(&self) -> bool
+    {
+        self.flags & 1 != 0
+    }
+
+    /// A threshold of 0 is never exceeded
+    pub fn threshold_exceeded(&self) -> bool
+    {
+        self.threshold != 0 && self.current <= self.threshold
+    }
+}
+
+/// The attribute table of SMART READ DATA, merged with the thresholds of SMART READ THRESHOLDS
+#[derive(Debug, Clone)]
+pub struct SmartData
+{
+    pub attributes: Vec<SmartAttribute>
+}
+
+impl SmartData
+{
+    /// Both tables hold up to 30 entries of 12 bytes, starting at byte 2. Unused entries have the id 0.
+    const ENTRIES: usize = 30;
+    const ENTRY_SIZE: usize = 12;
+    const TABLE_OFFSET: usize = 2;
+
+    pub fn parse(data: &[u16; 256], thresholds: &[u16; 256]) -> Self
+    {
+        let byte = |words: &[u16; 256], i: usize| (words[i / 2] >> ((i % 2) * 8)) as u8;
+
+        let mut attributes = Vec::new();
+        for entry in 0..Self::ENTRIES
+        {
+            // Byte 0: ID, 1..=2: Flags, 3: Current, 4: Worst, 5..=10: Raw, 11: Reserved
+            let at = Self::TABLE_OFFSET + entry * Self::ENTRY_SIZE;
+            let id = byte(data, at);
+            if id == 0
+            {
+                continue;
+            }
+
+            // The thresholds are usually at the same index, but only the ID counts
+            // Byte 0: ID, 1: Threshold
+            let threshold = (0..Self::ENTRIES)
+                .map(|it| Self::TABLE_OFFSET + it * Self::ENTRY_SIZE)
+                .find(|it| byte(thresholds, *it) == id)
+                .map_or(0, |it| byte(thresholds, it + 1));
+
+            let raw = (0..6).fold(0u64, |acc, i| acc | (byte(data, at + 5 + i) as u64) << (i * 8));
+            attributes.push(SmartAttribute {
+                id,
+                flags: byte(data, at + 1) as u16 | (byte(data, at + 2) as u16) << 8,
+                current: byte(data, at + 3),
+                worst: byte(data, at + 4),
+                threshold,
+                raw
+            });
+        }
+        Self { attributes }
+    }
+
+    /// Every attribute at or below its threshold
+    pub fn exceeded(&self) -> impl Iterator<Item = &SmartAttribute>
+    {
+        self.attributes.iter().filter(|it| it.threshold_exceeded())
+    }
+
+    pub fn any_exceeded(&self) -> bool
+    {
+        self.exceeded().next().is_some()
+    }
+}
@@ -0,0 +1,137 @@
+// NEW
+
+// Disks without the controller: a BlockDevice is addressed by its name (sda, sdb...) and reads or writes whole blocks.
+// Whoever finds a disk (so far only the AHCI driver) registers it here, filesystems and tools only need the name.
+
+use crate::synch::spinlock::Spinlock;
+use alloc::{
+    string::String,
+    sync::Arc,
+    vec::Vec
+};
+
+pub mod cache;
+
+/// Not AHCI specific, despite where it lives
+pub use super::ahci::DiskError;
+
+/// A disk, or anything else made of blocks of the same size.
+///
+/// Every method takes `&self`, the device does its own locking, so it may be shared between tasks.
+/// Buffers are a multiple of `block_size` long. AHCI disks additionally want them 2 byte aligned, else they fail with MisalignedBuffer.
+pub trait BlockDevice: Send + Sync
+{
+    /// Size of a block in bytes, the unit of every block number and count
+    fn block_size(&self) -> u32;
+
+    /// Blocks on the device, 0 if there is no medium (yet)
+    fn block_count(&self) -> u64;
+
+    /// Reads `buffer.len() / block_size` blocks, starting with `first_block`
+    fn read_blocks(&self, first_block: u64, buffer: &mut [u8]) -> Result<(), DiskError>;
+
+    /// Writes `buffer.len() / block_size` blocks, starting with `first_block`.
+    /// Only on the medium after a successful flush.
+    fn write_blocks(&self, first_block: u64, buffer: &[u8]) -> Result<(), DiskError>;
+
+    /// Writes back whatever the device still caches
+    fn flush(&self) -> Result<(), DiskError>;
+
+    /// Every write fails, e.g. an optical drive
+    fn is_read_only(&self) -> bool
+    {
+        false
+    }
+
+    /// Tells the device the blocks no longer hold any data. Unsupported, unless the device says otherwise.
+    fn trim(&self, _first_block: u64, _block_count: u64) -> Result<(), DiskError>
+    {
+        Err(DiskError::Unsupported)
+    }
+}
+
+/// Sorted by name
+static DEVICES: Spinlock<Vec<(String, Arc<dyn BlockDevice>)>> = Spinlock::new(Vec::new());
+
+/// The name of the `index`th disk, the way Linux counts: sda to sdz, sdaa to sdaz, sdba...
+fn disk_name(index: usize) -> String
+{
+    let mut letters = Vec::new();
+    let mut n = index + 1;
+    while n > 0
+    {
+        n -= 1;
+        letters.push(b'a' + (n % 26) as u8);
+        n /= 26;
+    }
+    letters.reverse();
+
+    let mut name = String::from("sd");
+    name.extend(letters.into_iter().map(char::from));
+    name
+}
+
+/// Adds `device` under the first free name (sda, sdb...). A name freed by `unregister` is handed out again.
+///
+/// Returns: the name
+pub fn register(device: Arc<dyn BlockDevice>) -> String
+{
+    let mut devices = DEVICES.lock();
+    let name = (0..)
+        .map(disk_name)
+        .find(|name| devices.iter().all(|(it, _)| it != name))
+        .unwrap();
+
+    // Longer names sort after the shorter ones, as sdz comes before sdaa
+    let pos = devices.iter()
+        .position(|(it, _)| (it.len(), it.as_str()) > (name.len(), name.as_str()))
+        .unwrap_or(devices.len());
+    devices.insert(pos, (name.clone(), device));
+    name
+}
+
+/// Removes the device and drops its unpinned buffers from the cache.
+/// Whoever still holds it may keep using it, it is up to the device to fail from now on.
+pub fn unregister(name: &str) -> Option<Arc<dyn BlockDevice>>
+{
+    let device = {
+        let mut devices = DEVICES.lock();
+        let pos = devices.iter().position(|(it, _)| it == name)?;
+        devices.remove(pos).1
+    };
+    cache::buffer_cache().invalidate_device(&device);
+    Some(device)
+}
+
+/// The device called `name`
+pub fn get(name: &str) -> Option<Arc<dyn BlockDevice>>
+{
+    DEVICES.lock().iter().find(|(it, _)| it == name).map(|(_, it)| it.clone())
+}
+
+/// Every device with its name, in the order of the names.
+/// A copy, so the devices can be used without blocking anybody registering a new one.
+pub fn devices() -> Vec<(String, Arc<dyn BlockDevice>)>
+{
+    DEVICES.lock().clone()
+}
+
+/// Registers the disks found so far, keeps track of the ones coming and going and starts writing back the buffer cache
+pub fn init()
+{
+    super::ahci::register_block_devices();
+    crate::scheduler::spawn(cache::write_back_worker, crate::scheduler::task::NORMAL_PRIORITY)
+        .expect("Failed to spawn the buffer cache write-back worker");
+}
+
+#[cfg(not(target_os = "none"))]
+#[test]
+fn disk_names()
+{
+    assert_eq!(disk_name(0), "sda");
+    assert_eq!(disk_name(25), "sdz");
+    assert_eq!(disk_name(26), "sdaa");
+    assert_eq!(disk_name(51), "sdaz");
+    assert_eq!(disk_name(52), "sdba");
+    assert_eq!(disk_name(26 + 26 * 26), "sdaaa");
+}
@@ -0,0 +1,667 @@
+// NEW
+
+// The buffer cache: blocks read from a BlockDevice are kept in memory, keyed by (device, block),
+// so a filesystem reading the same block twice only goes to the disk once.
+// Writes go into the cache and are written back later, by the write-back worker, by sync or when the buffer is evicted.
+// The buffers not in use (unpinned) are evicted least recently used first, once the cache grows past its budget.
+
+use super::{
+    BlockDevice,
+    DiskError
+};
+use crate::{
+    LogLevel,
+    LOGGER,
+    arch::x86_64::kernel::get_ticks,
+    collections::irqsave,
+    scheduler::{self, task::Task},
+    synch::{
+        mutex::Mutex,
+        spinlock::{Spinlock, SpinlockIrqSave}
+    }
+};
+use alloc::{
+    collections::BTreeMap,
+    rc::Rc,
+    sync::Arc,
+    vec,
+    vec::Vec
+};
+use core::{
+    cell::RefCell,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering}
+};
+
+/// 4 MiB, until somebody calls `set_budget`
+pub const DEFAULT_BUDGET: usize = 4 * 1024 * 1024;
+/// How often the write-back worker writes the dirty buffers back, in ms
+pub const DEFAULT_WRITE_BACK_INTERVAL: u64 = 5_000;
+
+/// (device, block). The device is the address of its BlockDevice, which is not reused, as long as a buffer holds the device.
+type Key = (usize, u64);
+
+fn device_key(device: &Arc<dyn BlockDevice>) -> usize
+{
+    Arc::as_ptr(device) as *const () as usize
+}
+
+struct Buffer
+{
+    device: Arc<dyn BlockDevice>,
+    block: u64,
+    /// Block size in bytes, kept here so the cache never asks the device while it is locked
+    size: usize,
+    /// Locked for as long as the block is read or written, so nobody sees half a block.
+    /// A Mutex, as that takes as long as the disk does.
+    data: Mutex<BufferData>,
+    /// Only changed with `data` locked
+    dirty: AtomicBool,
+    /// The clock of the cache, when the buffer was looked up the last time
+    last_used: AtomicU64
+}
+
+struct BufferData
+{
+    /// u16, so the buffer is 2 byte aligned, as the AHCI disks want it
+    words: Vec<u16>,
+    /// false until the block was read (or the read failed)
+    valid: bool
+}
+
+impl BufferData
+{
+    fn bytes(&self) -> &[u8]
+    {
+        // Unsafe Note: Vec<u16> is twice as many bytes, any of them is a valid u8
+        unsafe { core::slice::from_raw_parts(self.words.as_ptr() as *const u8, self.words.len() * 2) }
+    }
+
+    fn bytes_mut(&mut self) -> &mut [u8]
+    {
+        // Unsafe Note: see bytes, any u8 written is part of a valid u16
+        unsafe { core::slice::from_raw_parts_mut(self.words.as_mut_ptr() as *mut u8, self.words.len() * 2) }
+    }
+}
+
+/// A block in the cache. It is pinned, as long as the handle (or a clone of it) lives: a pinned buffer is never evicted.
+#[derive(Clone)]
+pub struct BufferHandle
+{
+    buffer: Arc<Buffer>
+}
+
+impl BufferHandle
+{
+    pub fn block(&self) -> u64
+    {
+        self.buffer.block
+    }
+
+    pub fn device(&self) -> &Arc<dyn BlockDevice>
+    {
+        &self.buffer.device
+    }
+
+    /// Calls `func` with the content of the block
+    pub fn read<F, R>(&self, func: F) -> R
+        where F: FnOnce(&[u8]) -> R
+    {
+        func(self.buffer.data.lock().bytes())
+    }
+
+    /// Calls `func` with the content of the block and marks it dirty, it is written back later on
+    pub fn write<F, R>(&self, func: F) -> R
+        where F: FnOnce(&mut [u8]) -> R
+    {
+        let mut data = self.buffer.data.lock();
+        self.buffer.dirty.store(true, Ordering::Release);
+        func(data.bytes_mut())
+    }
+
+    /// Changed since it was last written back
+    pub fn is_dirty(&self) -> bool
+    {
+        self.buffer.dirty.load(Ordering::Acquire)
+    }
+}
+
+/// Counted since the start (or the last `reset_stats`)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats
+{
+    /// Lookups of a block already in the cache
+    pub hits: u64,
+    /// Lookups, which had to read the block from the device
+    pub misses: u64,
+    /// Buffers dropped to stay within the budget
+    pub evictions: u64,
+    /// Dirty buffers written to their device
+    pub write_backs: u64,
+    /// Writes, which failed. The buffer stays dirty, it is tried again next time.
+    pub write_back_errors: u64,
+    /// Buffers in the cache right now, the pinned ones included
+    pub buffers: usize,
+    /// Memory used by the blocks right now
+    pub bytes: usize,
+    /// What `bytes` should stay below
+    pub budget: usize
+}
+
+struct CacheState
+{
+    buffers: BTreeMap<Key, Arc<Buffer>>,
+    /// Counts up with every lookup, the LRU order
+    clock: u64,
+    stats: CacheStats
+}
+
+pub struct BufferCache
+{
+    state: Spinlock<CacheState>
+}
+
+impl BufferCache
+{
+    pub const fn new(budget: usize) -> Self
+    {
+        Self {
+            state: Spinlock::new(CacheState {
+                buffers: BTreeMap::new(),
+                clock: 0,
+                stats: CacheStats {
+                    hits: 0,
+                    misses: 0,
+                    evictions: 0,
+                    write_backs: 0,
+                    write_back_errors: 0,
+                    buffers: 0,
+                    bytes: 0,
+                    budget
+                }
+            })
+        }
+    }
+
+    /// The block `block` of `device`, read from the device unless it is in the cache already.
+    /// Several tasks looking up the same block get the same buffer, it is only read once.
+    ///
+    /// The buffer stays pinned, as long as the handle lives.
+    pub fn get(&self, device: &Arc<dyn BlockDevice>, block: u64) -> Result<BufferHandle, DiskError>
+    {
+        if block >= device.block_count()
+        {
+            return Err(DiskError::OutOfRange { lba: block });
+        }
+        let size = device.block_size() as usize;
+
+        let buffer = {
+            let mut state = self.state.lock();
+            state.clock += 1;
+            let now = state.clock;
+
+            let key = (device_key(device), block);
+            if let Some(it) = state.buffers.get(&key)
+            {
+                let it = it.clone();
+                it.last_used.store(now, Ordering::Relaxed);
+                state.stats.hits += 1;
+                it
+            }
+            else
+            {
+                let it = Arc::new(Buffer {
+                    device: device.clone(),
+                    block,
+                    size,
+                    data: Mutex::new(BufferData { words: vec![0u16; (size + 1) / 2], valid: false }),
+                    dirty: AtomicBool::new(false),
+                    last_used: AtomicU64::new(now)
+                });
+                state.buffers.insert(key, it.clone());
+                state.stats.misses += 1;
+                state.stats.bytes += size;
+                it
+            }
+        };
+
+        // Makes room for the new one, which is pinned by now
+        self.shrink();
+
+        // Whoever locks it first reads it, the others wait for the Mutex. A failed read is tried again by the next one.
+        {
+            let mut data = buffer.data.lock();
+            if !data.valid
+            {
+                buffer.device.read_blocks(block, data.bytes_mut())?;
+                data.valid = true;
+            }
+        }
+        Ok(BufferHandle { buffer })
+    }
+
+    /// Writes the buffer back, if it is dirty
+    fn write_back(&self, buffer: &Buffer) -> Result<(), DiskError>
+    {
+        let data = buffer.data.lock();
+        if !buffer.dirty.swap(false, Ordering::AcqRel)
+        {
+            return Ok(());
+        }
+
+        let result = buffer.device.write_blocks(buffer.block, data.bytes());
+        if result.is_err()
+        {
+            buffer.dirty.store(true, Ordering::Release);
+        }
+        drop(data);
+
+        let mut state = self.state.lock();
+        match result
+        {
+            Ok(()) => state.stats.write_backs += 1,
+            Err(_) => state.stats.write_back_errors += 1
+        }
+        result
+    }
+
+    /// Writes back a single buffer, e.g. for a filesystem ordering its writes
+    pub fn write_back_buffer(&self, handle: &BufferHandle) -> Result<(), DiskError>
+    {
+        self.write_back(&handle.buffer)
+    }
+
+    /// Writes every dirty buffer back, in the order of (device, block). Goes on after a failure.
+    ///
+    /// Returns the first error
+    pub fn write_back_all(&self) -> Result<(), DiskError>
+    {
+        // Written without the cache locked, the clones pin the buffers in the meantime
+        let dirty: Vec<Arc<Buffer>> = self.state.lock().buffers.values()
+            .filter(|it| it.dirty.load(Ordering::Acquire))
+            .cloned()
+            .collect();
+
+        let mut result = Ok(());
+        for it in dirty
+        {
+            if let Err(err) = self.write_back(&it)
+            {
+                warn!("Buffer Cache: Writing back block {} failed: {}", it.block, err);
+                result = result.and(Err(err));
+            }
+        }
+        result
+    }
+
+    /// Writes every dirty buffer back and flushes the devices, so it is on the media afterwards.
+    ///
+    /// Returns the first error
+    pub fn sync(&self) -> Result<(), DiskError>
+    {
+        let mut result = self.write_back_all();
+
+        let mut devices: Vec<Arc<dyn BlockDevice>> = Vec::new();
+        for it in self.state.lock().buffers.values()
+        {
+            if !devices.iter().any(|dev| device_key(dev) == device_key(&it.device))
+            {
+                devices.push(it.device.clone());
+            }
+        }
+        for it in devices.iter().filter(|it| !it.is_read_only())
+        {
+            result = result.and(it.flush());
+        }
+        result
+    }
+
+    /// The least recently used buffer nobody but the cache holds
+    fn pick_victim(buffers: &BTreeMap<Key, Arc<Buffer>>) -> Option<Key>
+    {
+        buffers.iter()
+            .filter(|(_, it)| Arc::strong_count(it) == 1)
+            .min_by_key(|(_, it)| it.last_used.load(Ordering::Relaxed))
+            .map(|(key, _)| *key)
+    }
+
+    /// Evicts until the cache fits its budget again. A dirty buffer is written back first.
+    ///
+    /// The budget is exceeded, as long as too many buffers are pinned or writing one back fails.
+    fn shrink(&self)
+    {
+        loop
+        {
+            let victim = {
+                let mut state = self.state.lock();
+                if state.stats.bytes <= state.stats.budget
+                {
+                    return;
+                }
+                let key = match Self::pick_victim(&state.buffers)
+                {
+                    Some(it) => it,
+                    None => return
+                };
+                if !state.buffers[&key].dirty.load(Ordering::Acquire)
+                {
+                    let it = state.buffers.remove(&key).unwrap();
+                    state.stats.bytes -= it.size;
+                    state.stats.evictions += 1;
+                    continue;
+                }
+                state.buffers[&key].clone()
+            };
+
+            // Evicted next round, once it is clean
+            if self.write_back(&victim).is_err()
+            {
+                return;
+            }
+        }
+    }
+
+    /// Changes the budget in bytes, evicting what no longer fits
+    pub fn set_budget(&self, budget: usize)
+    {
+        self.state.lock().stats.budget = budget;
+        self.shrink();
+    }
+
+    /// Drops every unpinned buffer of `device`, dirty or not. For devices, which are gone.
+    pub fn invalidate_device(&self, device: &Arc<dyn BlockDevice>)
+    {
+        let key = device_key(device);
+        let mut state = self.state.lock();
+        let mut freed = 0;
+        state.buffers.retain(|(dev, _), it| {
+
+            if *dev == key && Arc::strong_count(it) == 1
+            {
+                freed += it.size;
+                false
+            }
+            else
+            {
+                true
+            }
+        });
+        state.stats.bytes -= freed;
+    }
+
+    pub fn stats(&self) -> CacheStats
+    {
+        let state = self.state.lock();
+        CacheStats { buffers: state.buffers.len(), ..state.stats }
+    }
+
+    /// Zeroes the counters, the current figures (buffers, bytes, budget) stay
+    pub fn reset_stats(&self)
+    {
+        let mut state = self.state.lock();
+        state.stats = CacheStats { bytes: state.stats.bytes, budget: state.stats.budget, ..Default::default() };
+    }
+}
+
+static BUFFER_CACHE: BufferCache = BufferCache::new(DEFAULT_BUDGET);
+
+/// The cache every filesystem should go through, so they agree on the content of a block
+pub fn buffer_cache() -> &'static BufferCache
+{
+    &BUFFER_CACHE
+}
+
+struct WriteBackState
+{
+    /// The worker, while it waits for the next round
+    task: Option<Rc<RefCell<Task>>>,
+    deadline: u64,
+    interval: u64
+}
+
+// Unsafe Note: Rc is not Send. Same as IrqState of the AHCI driver:
+// a single core and WRITE_BACK is only locked with interrupts disabled.
+unsafe impl Send for WriteBackState {}
+
+static WRITE_BACK: SpinlockIrqSave<WriteBackState> = SpinlockIrqSave::new(WriteBackState {
+    task: None,
+    deadline: 0,
+    interval: DEFAULT_WRITE_BACK_INTERVAL
+});
+
+/// How often the dirty buffers are written back, in ms. Takes effect after the current round.
+pub fn set_write_back_interval(ms: u64)
+{
+    irqsave(|| WRITE_BACK.lock().interval = ms.max(1));
+}
+
+/// Wakes the write-back worker, once its time is up. Called by the timer interrupt.
+#[doc(hidden)]
+pub fn on_timer(ticks: u64)
+{
+    let mut state = WRITE_BACK.lock();
+    if ticks >= state.deadline
+    {
+        if let Some(task) = state.task.take()
+        {
+            scheduler::wakeup_task(task);
+        }
+    }
+}
+
+/// Writes the dirty buffers back every few seconds, so a crash does not lose more than that
+pub(super) extern "C" fn write_back_worker()
+{
+    loop
+    {
+        irqsave(|| {
+
+            let mut state = WRITE_BACK.lock();
+            state.deadline = get_ticks() + state.interval;
+            state.task = Some(scheduler::block_current_task());
+            drop(state);
+            scheduler::reschedule();
+        });
+
+        // Errors are logged, the buffers stay dirty for the next round
+        let _ = BUFFER_CACHE.write_back_all();
+    }
+}
+
+/// 16 blocks of 512 bytes in memory, counting what is done to them
+#[cfg(all(test, not(target_os = "none")))]
+struct Dummy
+{
+    data: Spinlock<Vec<u8>>,
+    reads: AtomicU64,
+    writes: AtomicU64,
+    flushes: AtomicU64
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+impl Dummy
+{
+    fn new() -> Arc<Self>
+    {
+        Arc::new(Self {
+            data: Spinlock::new(vec![0; 16 * 512]),
+            reads: AtomicU64::new(0),
+            writes: AtomicU64::new(0),
+            flushes: AtomicU64::new(0)
+        })
+    }
+
+    fn counts(&self) -> (u64, u64, u64)
+    {
+        (self.reads.load(Ordering::Relaxed), self.writes.load(Ordering::Relaxed), self.flushes.load(Ordering::Relaxed))
+    }
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+impl BlockDevice for Dummy
+{
+    fn block_size(&self) -> u32 { 512 }
+    fn block_count(&self) -> u64 { 16 }
+
+    fn read_blocks(&self, first_block: u64, buffer: &mut [u8]) -> Result<(), DiskError>
+    {
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        let start = first_block as usize * 512;
+        buffer.copy_from_slice(&self.data.lock()[start..start + buffer.len()]);
+        Ok(())
+    }
+
+    fn write_blocks(&self, first_block: u64, buffer: &[u8]) -> Result<(), DiskError>
+    {
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        let start = first_block as usize * 512;
+        self.data.lock()[start..start + buffer.len()].copy_from_slice(buffer);
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), DiskError>
+    {
+        self.flushes.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+#[cfg(not(target_os = "none"))]
+#[test]
+fn least_recently_used_unpinned_is_evicted_first()
+{
+    let device: Arc<dyn BlockDevice> = Dummy::new();
+    let buffer = |block: u64, last_used: u64| Arc::new(Buffer {
+        device: device.clone(),
+        block,
+        size: 512,
+        data: Mutex::new(BufferData { words: vec![0; 256], valid: true }),
+        dirty: AtomicBool::new(false),
+        last_used: AtomicU64::new(last_used)
+    });
+
+    let mut buffers = BTreeMap::new();
+    for (block, last_used) in [(0, 5), (1, 2), (2, 9), (3, 1)]
+    {
+        buffers.insert((device_key(&device), block), buffer(block, last_used));
+    }
+    assert_eq!(BufferCache::pick_victim(&buffers), Some((device_key(&device), 3)));
+
+    // Pinned, so the next oldest goes
+    let pinned = buffers[&(device_key(&device), 3)].clone();
+    assert_eq!(BufferCache::pick_victim(&buffers), Some((device_key(&device), 1)));
+    drop(pinned);
+
+    let pinned: Vec<_> = buffers.values().cloned().collect();
+    assert_eq!(BufferCache::pick_victim(&buffers), None);
+    drop(pinned);
+}
+
+#[cfg(not(target_os = "none"))]
+#[test]
+fn hits_and_misses()
+{
+    let cache = BufferCache::new(DEFAULT_BUDGET);
+    let dummy = Dummy::new();
+    dummy.data.lock()[512] = 0xab;
+    let device: Arc<dyn BlockDevice> = dummy.clone();
+
+    let first = cache.get(&device, 1).unwrap();
+    assert_eq!(first.read(|it| it[0]), 0xab);
+    let second = cache.get(&device, 1).unwrap();
+    assert_eq!(second.read(|it| it[0]), 0xab);
+    cache.get(&device, 2).unwrap();
+    assert!(matches!(cache.get(&device, 16), Err(DiskError::OutOfRange { lba: 16 })));
+
+    let stats = cache.stats();
+    assert_eq!((stats.hits, stats.misses, stats.buffers, stats.bytes), (1, 2, 2, 1024));
+    // Read once per block
+    assert_eq!(dummy.counts(), (2, 0, 0));
+
+    cache.reset_stats();
+    let stats = cache.stats();
+    assert_eq!((stats.hits, stats.misses, stats.buffers, stats.bytes), (0, 0, 2, 1024));
+}
+
+#[cfg(not(target_os = "none"))]
+#[test]
+fn sync_writes_back_the_dirty_buffers()
+{
+    let cache = BufferCache::new(DEFAULT_BUDGET);
+    let dummy = Dummy::new();
+    let device: Arc<dyn BlockDevice> = dummy.clone();
+
+    let handle = cache.get(&device, 3).unwrap();
+    cache.get(&device, 4).unwrap();
+    handle.write(|it| it[..4].copy_from_slice(&[1, 2, 3, 4]));
+    assert!(handle.is_dirty());
+    assert_eq!(dummy.counts(), (2, 0, 0));
+
+    // Only the dirty one is written, then the device is flushed
+    cache.sync().unwrap();
+    assert!(!handle.is_dirty());
+    assert_eq!(&dummy.data.lock()[3 * 512..3 * 512 + 4], &[1, 2, 3, 4]);
+    assert_eq!(dummy.counts(), (2, 1, 1));
+    assert_eq!(cache.stats().write_backs, 1);
+
+    cache.sync().unwrap();
+    assert_eq!(dummy.counts(), (2, 1, 2));
+}
+
+#[cfg(not(target_os = "none"))]
+#[test]
+fn sync_on_shutdown_reaches_every_device()
+{
+    // What drivers::shutdown does, before the disks are spun down
+    let cache = BufferCache::new(DEFAULT_BUDGET);
+    let (first, second) = (Dummy::new(), Dummy::new());
+    let (first_device, second_device): (Arc<dyn BlockDevice>, Arc<dyn BlockDevice>) = (first.clone(), second.clone());
+
+    // Still pinned by whoever wrote it, it goes to the disk nonetheless
+    let pinned = cache.get(&first_device, 5).unwrap();
+    pinned.write(|it| it[0] = 0x5a);
+    cache.get(&second_device, 5).unwrap();
+
+    cache.sync().unwrap();
+    assert!(!pinned.is_dirty());
+    assert_eq!(first.data.lock()[5 * 512], 0x5a);
+    // Both are flushed, the clean one as well: its disk may still cache earlier writes
+    assert_eq!(first.counts(), (1, 1, 1));
+    assert_eq!(second.counts(), (1, 0, 1));
+}
+
+#[cfg(not(target_os = "none"))]
+#[test]
+fn evicted_over_budget()
+{
+    let cache = BufferCache::new(2 * 512);
+    let dummy = Dummy::new();
+    let device: Arc<dyn BlockDevice> = dummy.clone();
+
+    cache.get(&device, 0).unwrap().write(|it| it[0] = 7);
+    cache.get(&device, 1).unwrap();
+
+    // The third does not fit: block 0 is the least recently used, it is written back and evicted
+    let pinned = cache.get(&device, 2).unwrap();
+    let stats = cache.stats();
+    assert_eq!((stats.evictions, stats.write_backs, stats.buffers, stats.bytes), (1, 1, 2, 1024));
+    assert_eq!(dummy.data.lock()[0], 7);
+
+    // Evicted, so it is read again
+    cache.get(&device, 0).unwrap();
+    assert_eq!(cache.stats().misses, 4);
+    assert_eq!(dummy.counts().0, 4);
+
+    // Pinned buffers stay, even over the budget
+    let pinned_too = cache.get(&device, 3).unwrap();
+    let stats = cache.stats();
+    assert_eq!((stats.buffers, stats.bytes), (2, 1024));
+    let pinned_three = cache.get(&device, 4).unwrap();
+    let stats = cache.stats();
+    assert_eq!((stats.buffers, stats.bytes), (3, 1536));
+
+    // Once unpinned, they go with the next lookup
+    drop((pinned, pinned_too, pinned_three));
+    cache.set_budget(512);
+    let stats = cache.stats();
+    assert_eq!((stats.buffers, stats.bytes), (1, 512));
+}
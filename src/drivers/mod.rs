@@ -3,6 +3,9 @@
 pub mod util;
 pub mod pci;
 pub mod ahci;
+pub mod block;
+
+use crate::{LogLevel, LOGGER};
 
 // "Late" addition. Should I keep it?
 pub use util::Register;
@@ -11,10 +14,28 @@ pub fn init()
 {
     pci::init();
     ahci::init();
-    ahci::on_interrupt(0);
+    block::init();
 }
 
 pub fn on_interrupt(num: u8)
 {
     ahci::on_interrupt(num);
 }
+
+pub fn on_timer(ticks: u64)
+{
+    ahci::on_timer(ticks);
+    block::cache::on_timer(ticks);
+}
+
+/// Called right before the system powers off, by a task (it may block): never on the idle task
+pub fn shutdown()
+{
+    debug_assert!(!crate::scheduler::is_idle_task(), "The idle task must not wait for the disks");
+    // The disks have to get the dirty buffers, before they are spun down
+    if let Err(err) = block::cache::buffer_cache().sync()
+    {
+        warn!("Buffer Cache: Sync failed: {}, some writes are lost", err);
+    }
+    ahci::shutdown();
+}
@@ -1,3 +1,4 @@
+use core::cell::UnsafeCell;
 use core::fmt::{
     Result,
     Formatter,
@@ -8,24 +9,25 @@ use core::fmt::{
     Debug
 };
 
+/// A memory mapped register. Changed through a shared reference, like a Cell, as the hardware changes it anyway.
 #[repr(transparent)]
-pub struct Register<T>(T);
+pub struct Register<T>(UnsafeCell<T>);
 // Should the getter and setter be unsafe? I mean, they both need to be aligned, and considering how this struct will be used, generally speaking it may not be guranteed.
 impl<T> Register<T>
 {
     pub const fn new(value: T) -> Self
     {
-        Self(value)
+        Self(UnsafeCell::new(value))
     }
 
     pub fn get(&self) -> T
     {
-        unsafe { core::ptr::read_volatile(&self.0) }
+        unsafe { core::ptr::read_volatile(self.0.get()) }
     }
 
-    pub fn set(&mut self, value: T)
+    pub fn set(&self, value: T)
     {
-        unsafe { core::ptr::write_volatile(&mut self.0, value) }
+        unsafe { core::ptr::write_volatile(self.0.get(), value) }
     }
 }
 
@@ -37,7 +39,7 @@ impl<T> Debug for Register<T>
     fn fmt(&self, f: &mut Formatter<'_>) -> Result
     {
         f.write_str("Register(")?;
-        self.0.fmt(f)?;
+        self.get().fmt(f)?;
         f.write_str(")")
     }
 }
@@ -47,7 +49,7 @@ impl<T> Clone for Register<T>
 {
     fn clone(&self) -> Self
     {
-        Self(self.get().clone())
+        Self::new(self.get())
     }
 }
 
@@ -77,7 +79,7 @@ impl<T> Display for Register<T>
 {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result
     {
-        self.0.fmt(f)
+        self.get().fmt(f)
     }
 }
 
@@ -86,7 +88,7 @@ impl<T> Binary for Register<T>
 {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result
     {
-        self.0.fmt(f)
+        self.get().fmt(f)
     }
 }
 
@@ -95,7 +97,7 @@ impl<T> LowerHex for Register<T>
 {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result
     {
-        self.0.fmt(f)
+        self.get().fmt(f)
     }
 }
 
@@ -104,7 +106,7 @@ impl<T> UpperHex for Register<T>
 {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result
     {
-        self.0.fmt(f)
+        self.get().fmt(f)
     }
 }
 
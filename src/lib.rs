@@ -25,7 +25,6 @@ extern crate num_traits;
 use crate::consts::HEAP_SIZE;
 #[cfg(target_arch = "x86_64")]
 use arch::processor::*;
-use arch::x86_64::kernel::busy_sleep;
 use core::panic::PanicInfo;
 pub use logging::*;
 use simple_chunk_allocator::{heap, heap_bitmap, GlobalChunkAllocator, PageAligned};
@@ -57,7 +56,8 @@ static mut HEAP: PageAligned<[u8; HEAP_SIZE]> =
 	heap!(chunks = CHUNK_AMOUNT, chunksize = CHUNK_SIZE);
 static mut HEAP_BITMAP: PageAligned<[u8; CHUNK_AMOUNT / 8]> = heap_bitmap!(chunks = CHUNK_AMOUNT);
 
-#[global_allocator]
+// Host tests (cargo test) run with the allocator of std
+#[cfg_attr(not(test), global_allocator)]
 static ALLOCATOR: GlobalChunkAllocator =
 	unsafe { GlobalChunkAllocator::new(HEAP.deref_mut_const(), HEAP_BITMAP.deref_mut_const()) };
 
@@ -93,120 +93,33 @@ pub fn heap_investigation()
 	}
 }
 
-pub extern "C" fn test_c()
+/// Runs the AHCI self-test given at boot (see `drivers::ahci::SelfTest::from_boot_parameter`)
+/// and prints the result of every disk.
+pub extern "C" fn disk_selftest_c()
 {
-	test()
+	disk_selftest()
 }
 
-pub fn test()
+pub fn disk_selftest()
 {
-//	use alloc::vec::Vec;
-//	use drivers::pci::devices::{Device, CommonHeader};
-
-//	let mut pci_count = 0;
-//	let mut generic_count = 0;
-//	let mut card_count = 0;
-//	let mut bridge_count = 0;
-//	let mut ahci_count = 0;
-
-//	drivers::pci::on_each_device_mut(|_, _| pci_count += 1);
-//	drivers::pci::on_each_generic_device_mut(|_, _| generic_count += 1);
-//	drivers::pci::on_each_pci_bridge_device_mut(|_, _| bridge_count += 1);
-//	drivers::pci::on_each_card_bridge_device_mut(|_, _| card_count += 1);
-
-	/*drivers::pci::on_each_generic_device(|it|
-	{
-		if drivers::ahci::is_ahci_device(&it)
-		{
-			println!("Pin:  {}\nLine: {}", it.get_interrupt_pin(), it.get_interrupt_line());
+	let config = match drivers::ahci::SelfTest::from_boot_parameter() {
+		Some(it) => it,
+		None => {
+			println!("Disk self-test: {} not set or not understood", drivers::ahci::BOOT_PARAMETER);
+			return;
 		}
-	});*/
-
-//	drivers::ahci::on_each_device_mut(|_, _| ahci_count += 1);
-//	drivers::ahci::on_each_device(|i, it| {
-//
-//		println!("HBA {}", i);
-//		println!(
-//			"- BOH {}, NCS {}, NP  {}",
-//			it.abar_ptr.ghc.cap2.get_boh(),
-//			it.abar_ptr.ghc.cap.get_ncs_adjusted(),
-//			it.abar_ptr.ghc.cap.get_np_adjusted());
-//		println!("- IS {:032b}, PI  {:032b}", it.abar_ptr.ghc.is, it.abar_ptr.ghc.pi);
-//		for (j, port) in it.ports.iter().enumerate()
-//		{
-//			if let Some(port) = port
-//			{
-//				println!("- Port {}", j);
-//				println!(
-//					"  - {}, {}, CR {}, FR {}",
-//					port.hba_idx == i,
-//					port.hba_port_idx == j,
-//					it.abar_ptr.ports[port.hba_port_idx].cmd.get_cr(),
-//					it.abar_ptr.ports[port.hba_port_idx].cmd.get_fr());
-//				println!("  - CI  {}, CCS {}", it.abar_ptr.ports[port.hba_port_idx].ci.get(), it.abar_ptr.ports[port.hba_port_idx].cmd.get_ccs());
-//				println!(
-//					"  - STS {:x}, SSTS {:03x}, SIG {:08x}, SERR {:08x}",
-//					it.abar_ptr.ports[port.hba_port_idx].tfd.get() & 0xf,
-//					it.abar_ptr.ports[port.hba_port_idx].ssts.get(),
-//					it.abar_ptr.ports[port.hba_port_idx].sig.get(),
-//					it.abar_ptr.ports[port.hba_port_idx].serr.get());
-//				
-//			}
-//		}
-//	});
-
-//	println!("TEST ({}, {}, {}, {}, {})", pci_count, generic_count, card_count, bridge_count, ahci_count);
-
-	/*let devices = pci::scan_bus();
-	println!("Found {} PCI Device(s)", devices.len());
-	let ahci_devices = devices.into_iter()
-		// .filter(|it| ahci::is_ahci_device(it))
-		.filter_map(|it| ahci::AhciDevice::try_new(it))
-		.collect::<Vec<_>>();
-	println!("Found {} AHCI Device(s)", ahci_devices.len());
-	for dev in &ahci_devices
-	{
-		dev.debug_print();
-	}*/
-
-	static mut BUFFER: [u16; 8192] = [0u16; 8192];
-	static mut BACKUP: [u16; 8192] = [0u16; 8192];
-	let buffer = unsafe { &mut BUFFER };
-	let backup = unsafe { &mut BACKUP };
-	drivers::ahci::on_each_device_mut(|i, hba| {
+	};
 
-		for (j, port) in hba.ports.iter_mut().enumerate()
-		{
-			if let Some(ref mut port) = port
-			{
-				*buffer = [0u16; 8192];
-				*backup = [0u16; 8192];
-				if let Some(bytes_transferred) = port.read_u16(hba.abar_ptr, 0, backup)
-				{
-					println!("0: HBA: {}, Port: {}, Bytes Read: {}, Last Value: {}, All zero? {}", i, j, bytes_transferred, /*backup[bytes_transferred / 2 - 1]*/"N/A", backup.iter().all(|it| *it == 0));
-				}
-				if let Some(bytes_transferred) = port.write_u16(hba.abar_ptr, 0, buffer)
-				{
-					println!("1: HBA: {}, Port: {}, Bytes Written: {}", i, j, bytes_transferred);
-				}
-				if let Some(bytes_transferred) = port.read_u16(hba.abar_ptr, 0, buffer)
-				{
-					println!("2: HBA: {}, Port: {}, Bytes Read: {}, Last Value: {}, All zero? {}", i, j, bytes_transferred, /*backup[bytes_transferred / 2 - 1]*/"N/A", buffer.iter().all(|it| *it == 0));
-				}
-				if let Some(bytes_transferred) = port.write_u16(hba.abar_ptr, 0, backup)
-				{
-					println!("3: HBA: {}, Port: {}, Bytes Written: {}", i, j, bytes_transferred);
-				}
-				if let Some(bytes_transferred) = port.read_u16(hba.abar_ptr, 0, buffer)
-				{
-					println!("4: HBA: {}, Port: {}, Bytes Read: {}, Last Value: {}, All zero? {}", i, j, bytes_transferred, /*backup[bytes_transferred / 2 - 1]*/"N/A", buffer.iter().all(|it| *it == 0));
-				}
-				println!("All Fine: {}", backup.iter().enumerate().all(|(i, v)| buffer[i] == *v));
-			}
-		}
-		// TODO: REMOVE
-		busy_sleep(2000);
-	});
+	println!("Disk self-test: {:?}", config);
+	let reports = config.run();
+	for it in reports.iter() {
+		println!("- {}", it);
+	}
+	println!(
+		"Disk self-test: {} of {} disks passed",
+		reports.iter().filter(|it| it.passed()).count(),
+		reports.len()
+	);
 }
 
 /// This function is called on panic.
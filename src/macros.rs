@@ -52,6 +52,7 @@ macro_rules! println
 	($fmt:expr, $($arg:tt)*) => ($crate::print!(concat!($fmt, "\n"), $($arg)*));
 }
 
+#[cfg(not(all(test, not(target_os = "none"))))]
 #[doc(hidden)]
 pub fn _vga_print(args: core::fmt::Arguments<'_>)
 {
@@ -59,6 +60,7 @@ pub fn _vga_print(args: core::fmt::Arguments<'_>)
 	crate::vga::get_buffer().as_mut().unwrap().write_fmt(args).unwrap()
 }
 
+#[cfg(not(all(test, not(target_os = "none"))))]
 #[doc(hidden)]
 pub fn _serial_print(args: core::fmt::Arguments<'_>)
 {
@@ -66,6 +68,19 @@ pub fn _serial_print(args: core::fmt::Arguments<'_>)
 	crate::console::CONSOLE.lock().write_fmt(args).unwrap();
 }
 
+// Tests on the host have neither the VGA buffer nor the serial port, the output goes to stderr (once)
+#[cfg(all(test, not(target_os = "none")))]
+#[doc(hidden)]
+pub fn _vga_print(_args: core::fmt::Arguments<'_>) {}
+
+#[cfg(all(test, not(target_os = "none")))]
+#[doc(hidden)]
+pub fn _serial_print(args: core::fmt::Arguments<'_>)
+{
+	extern crate std;
+	std::eprint!("{}", args);
+}
+
 macro_rules! align_down {
 	($value:expr, $alignment:expr) => {
 		$value & !($alignment - 1)
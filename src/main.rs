@@ -16,7 +16,7 @@ use eduos_rs::arch::load_application;
 use eduos_rs::fs;
 use eduos_rs::mm;
 use eduos_rs::scheduler;
-use eduos_rs::scheduler::task::{ LOW_PRIORITY, NORMAL_PRIORITY };
+use eduos_rs::scheduler::task::{ HIGH_PRIORITY, LOW_PRIORITY, NORMAL_PRIORITY };
 use eduos_rs::{LogLevel, LOGGER};
 use eduos_rs::drivers;
 
@@ -37,6 +37,12 @@ extern "C" fn foo() {
 	println!("hello from task {}", tid);
 }
 
+/// Writes back the caches, spins the disks down and powers off. A task of its own, as the idle task
+/// must never block, but this may have to wait for a disk (and its lock).
+extern "C" fn shutdown_task() {
+	arch::processor::shutdown();
+}
+
 /// This function is the entry point, since the linker looks for a function
 /// named `_start` by default.
 #[cfg(not(test))]
@@ -52,6 +58,8 @@ pub extern "C" fn main() -> ! {
 	arch::irq::irq_enable();
 	drivers::init();
 	arch::irq::irq_disable();
+	// the driver tasks (hot-plug, write-back) run until the system powers off
+	let driver_tasks = scheduler::number_of_tasks();
 
 	println!("Hello from eduOS-rs!");
 
@@ -62,20 +70,28 @@ pub extern "C" fn main() -> ! {
 		scheduler::spawn(foo, NORMAL_PRIORITY).unwrap();
 	}
 	scheduler::spawn(create_user_foo, NORMAL_PRIORITY).unwrap();
-	scheduler::spawn(eduos_rs::test_c, LOW_PRIORITY).unwrap();
+	// Only if asked for at boot, as it writes to the disks (see drivers::ahci::SelfTest)
+	if drivers::ahci::SelfTest::from_boot_parameter().is_some() {
+		scheduler::spawn(eduos_rs::disk_selftest_c, LOW_PRIORITY).unwrap();
+	}
 
 	// enable interrupts => enable preemptive multitasking
 	arch::irq::irq_enable();
 
+	// the idle task gets back as soon as nobody is ready, which includes every task waiting for a disk:
+	// shut down only after all tasks (the disk self-test as well) are done
 	scheduler::reschedule();
+	while scheduler::number_of_tasks() > driver_tasks {
+		arch::processor::halt();
+		scheduler::reschedule();
+	}
 
 	println!("Shutdown system!");
 
-	// Keep the QEMU window alive
-	// TODO: Remove
+	// shutdown system
+	scheduler::spawn(shutdown_task, HIGH_PRIORITY).unwrap();
 	loop {
-		unsafe { x86::halt() };
+		scheduler::reschedule();
+		arch::processor::halt();
 	}
-	// shutdown system
-	arch::processor::shutdown();
 }
@@ -88,6 +88,38 @@ impl FreeList {
 		Err(())
 	}
 
+	/// Like allocate without alignment, but the whole block has to end at or below `limit`
+	/// (e.g. for devices only able to address the first 4 GiB).
+	pub fn allocate_below(&mut self, size: usize, limit: usize) -> Result<usize, ()> {
+		debug!(
+			"Allocating {} bytes below {:#X} from Free List {:#X}",
+			size, limit, self as *const Self as usize
+		);
+
+		let mut cursor = self.list.cursor_front_mut();
+		while let Some(node) = cursor.current() {
+			let (region_start, region_size) = (node.start, node.end - node.start);
+
+			if region_start + size <= limit {
+				match region_size.cmp(&size) {
+					Ordering::Greater => {
+						node.start += size;
+						return Ok(region_start);
+					}
+					Ordering::Equal => {
+						cursor.remove_current();
+						return Ok(region_start);
+					}
+					Ordering::Less => {}
+				}
+			}
+
+			cursor.move_next();
+		}
+
+		Err(())
+	}
+
 	pub fn reserve(&mut self, address: usize, size: usize) -> Result<(), ()> {
 		debug!(
 			"Reserving {} bytes at address {:#X} in Free List {:#X}",
@@ -268,3 +300,28 @@ fn deallocate() {
 		cursor.move_next();
 	}
 }
+
+#[cfg(not(target_os = "none"))]
+#[test]
+fn allocate_below() {
+	let mut freelist = FreeList::new();
+	freelist.list.push_back(FreeListEntry::new(0x10000, 0x12000));
+	freelist.list.push_back(FreeListEntry::new(0x20000, 0x100000));
+
+	// Too large for the first region, the second one ends above the limit
+	assert!(freelist.allocate_below(0x4000, 0x22000).is_err());
+
+	let addr = freelist.allocate_below(0x2000, 0x22000);
+	assert_eq!(addr.unwrap(), 0x10000);
+
+	let addr = freelist.allocate_below(0x2000, 0x22000);
+	assert_eq!(addr.unwrap(), 0x20000);
+
+	let mut cursor = freelist.list.cursor_front_mut();
+	if let Some(node) = cursor.current() {
+		assert_eq!(node.start, 0x22000);
+		assert_eq!(node.end, 0x100000);
+	}
+	cursor.move_next();
+	assert!(cursor.current().is_none());
+}
@@ -79,6 +79,16 @@ pub fn wakeup_task(task: Rc<RefCell<Task>>) {
 	unsafe { SCHEDULER.as_mut().unwrap().wakeup_task(task) }
 }
 
+/// Is the current task the idle task? The idle task is never allowed to block.
+pub fn is_idle_task() -> bool {
+	unsafe { SCHEDULER.as_ref().unwrap().is_idle_task() }
+}
+
+/// Number of tasks alive, without the idle task
+pub fn number_of_tasks() -> u32 {
+	unsafe { SCHEDULER.as_ref().unwrap().number_of_tasks() }
+}
+
 /// Get the TaskID of the current running task
 pub fn get_current_taskid() -> task::TaskId {
 	unsafe { SCHEDULER.as_ref().unwrap().get_current_taskid() }
@@ -162,6 +162,15 @@ impl Scheduler {
 		irqsave(closure);
 	}
 
+	pub fn is_idle_task(&self) -> bool {
+		irqsave(|| self.current_task.borrow().status == TaskStatus::TaskIdle)
+	}
+
+	/// Tasks spawned, which did not finish yet. The idle task is not one of them.
+	pub fn number_of_tasks(&self) -> u32 {
+		NO_TASKS.load(Ordering::SeqCst)
+	}
+
 	pub fn get_current_taskid(&self) -> TaskId {
 		irqsave(|| self.current_task.borrow().id)
 	}
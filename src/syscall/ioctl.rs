@@ -0,0 +1,143 @@
+// NEW
+
+use crate::arch::is_user_memory;
+use crate::drivers::ahci::{self, DataDirection, DiskError, RegH2D, TaskfileResult};
+use core::mem::{align_of, size_of};
+use core::ptr::addr_of;
+
+/// ioctl request: ATA pass-through, the argument is an `AtaTaskfile`.
+/// Same number as HDIO_DRIVE_TASKFILE of Linux, which it mimics.
+pub const IOCTL_ATA_TASKFILE: u32 = 0x031d;
+
+/// `AtaTaskfile::flags`: Allows commands, which may write the media or change the device (secure erase, microcode, SET FEATURES...)
+pub const ATA_TASKFILE_PRIVILEGED: u32 = 1;
+
+/// No port multiplier port, the disk on the HBA port itself
+pub const ATA_TASKFILE_NO_PMP: u32 = u32::MAX;
+
+// The Linux numbers, what user space tools expect
+const EPERM: isize = 1;
+const EIO: isize = 5;
+const EFAULT: isize = 14;
+const ENODEV: isize = 19;
+const EBUSY: isize = 16;
+const EINVAL: isize = 22;
+const ENOTTY: isize = 25;
+const EOPNOTSUPP: isize = 95;
+const ETIMEDOUT: isize = 110;
+
+/// The argument of IOCTL_ATA_TASKFILE. The fields up to `buffer_len` are read, the rest is written.
+#[repr(C)]
+pub struct AtaTaskfile {
+	pub hba_idx: u32,
+	pub port_idx: u32,
+	/// ATA_TASKFILE_NO_PMP or the port multiplier port of the disk
+	pub pmp: u32,
+	/// ATA_TASKFILE_PRIVILEGED
+	pub flags: u32,
+	/// DataDirection: 0 None, 1 from the device, 2 to the device
+	pub direction: u32,
+	/// The Register H2D FIS to send, its FIS type is ignored
+	pub taskfile: RegH2D,
+	/// Multiple of 512 bytes, 2 byte aligned
+	pub buffer: *mut u8,
+	pub buffer_len: usize,
+
+	/// Status, as reported by the device. Set as well, if the device failed the command.
+	pub status: u8,
+	/// Error, as reported by the device. Set as well, if the device failed the command.
+	pub error: u8,
+	pub device: u8,
+	pub count: u16,
+	/// The LBA from the D2H Register FIS, or the failing sector after a media error
+	pub lba: u64,
+}
+
+/// ioctl, currently only for IOCTL_ATA_TASKFILE. `fd` is ignored, there are no device files (yet), the disk is picked by the argument.
+///
+/// Returns 0 or a negative errno. Anything but mapped user memory, for the argument or the buffer, is -EFAULT.
+#[no_mangle]
+pub unsafe extern "C" fn sys_ioctl(_fd: i32, cmd: u32, arg: *mut AtaTaskfile) -> isize {
+	if cmd != IOCTL_ATA_TASKFILE {
+		return -ENOTTY;
+	}
+	if arg.is_null() || arg as usize % align_of::<AtaTaskfile>() != 0 {
+		return -EINVAL;
+	}
+	if !is_user_memory(arg as usize, size_of::<AtaTaskfile>(), true) {
+		return -EFAULT;
+	}
+
+	let direction = match DataDirection::from_raw((*arg).direction) {
+		Some(it) => it,
+		None => return -EINVAL,
+	};
+	let pmp = match (*arg).pmp {
+		ATA_TASKFILE_NO_PMP => None,
+		it if it < 16 => Some(it as u8),
+		_ => return -EINVAL,
+	};
+
+	// Read once, user space may change them while the command runs
+	let buffer = (*arg).buffer;
+	let buffer_len = (*arg).buffer_len;
+	if buffer_len != 0
+		&& !is_user_memory(
+			buffer as usize,
+			buffer_len,
+			direction == DataDirection::FromDevice,
+		) {
+		return -EFAULT;
+	}
+
+	// Copied byte wise, without the FIS type: it is an enum, whatever user space put in there may not be a valid one
+	let mut fis = RegH2D::default();
+	core::ptr::copy_nonoverlapping(
+		(addr_of!((*arg).taskfile) as *const u8).add(1),
+		(&mut fis as *mut RegH2D as *mut u8).add(1),
+		size_of::<RegH2D>() - 1,
+	);
+
+	let privileged = (*arg).flags & ATA_TASKFILE_PRIVILEGED != 0;
+	let result = ahci::with_port(
+		(*arg).hba_idx as usize,
+		(*arg).port_idx as usize,
+		pmp,
+		|port| port.ata_passthrough(&fis, direction, buffer, buffer_len, privileged),
+	);
+
+	let (result, errno) = match result {
+		None => return -ENODEV,
+		Some(Ok(it)) => (it, 0),
+		Some(Err(DiskError::DeviceFault { status, error })) => (
+			TaskfileResult {
+				status,
+				error,
+				..Default::default()
+			},
+			-EIO,
+		),
+		Some(Err(DiskError::MediaError { lba })) => (
+			TaskfileResult {
+				lba,
+				..Default::default()
+			},
+			-EIO,
+		),
+		Some(Err(DiskError::Timeout)) => return -ETIMEDOUT,
+		Some(Err(DiskError::NoFreeSlot)) => return -EBUSY,
+		Some(Err(DiskError::PortNotReady)) => return -ENODEV,
+		Some(Err(DiskError::Unsupported)) => return -EOPNOTSUPP,
+		Some(Err(DiskError::PermissionDenied)) => return -EPERM,
+		Some(Err(DiskError::MisalignedBuffer))
+		| Some(Err(DiskError::OutOfRange { .. }))
+		| Some(Err(DiskError::InvalidArgument)) => return -EINVAL,
+	};
+
+	(*arg).status = result.status;
+	(*arg).error = result.error;
+	(*arg).device = result.device;
+	(*arg).count = result.count;
+	(*arg).lba = result.lba;
+	errno
+}
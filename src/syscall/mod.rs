@@ -7,11 +7,13 @@
 
 mod exit;
 mod invalid;
+mod ioctl;
 mod nothing;
 mod write;
 
 use crate::syscall::exit::sys_exit;
 use crate::syscall::invalid::sys_invalid;
+use crate::syscall::ioctl::sys_ioctl;
 use crate::syscall::nothing::sys_nothing;
 use crate::syscall::write::{sys_write, sys_writev};
 
@@ -21,6 +23,7 @@ pub const SYSNO_WRITE: usize = 1;
 /// number of the system call `close`
 pub const SYSNO_CLOSE: usize = 3;
 
+/// number of the system call `ioctl`, only ATA pass-through for now
 pub const SYSNO_IOCTL: usize = 16;
 
 pub const SYSNO_WRITEV: usize = 20;
@@ -53,7 +56,7 @@ impl SyscallTable {
 
 		table.handle[SYSNO_WRITE] = sys_write as *const _;
 		table.handle[SYSNO_CLOSE] = sys_nothing as *const _;
-		table.handle[SYSNO_IOCTL] = sys_nothing as *const _;
+		table.handle[SYSNO_IOCTL] = sys_ioctl as *const _;
 		table.handle[SYSNO_WRITEV] = sys_writev as *const _;
 		table.handle[SYSNO_EXIT] = sys_exit as *const _;
 		table.handle[SYSNO_ARCH_PRCTL] = sys_nothing as *const _;